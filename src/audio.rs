@@ -0,0 +1,205 @@
+use std::f32::consts::PI;
+use std::io::{self, Write};
+
+use crate::cpu::AUDIO_PATTERN_SIZE;
+use crate::system::{System, FRAMES_PER_SECOND, XO_CHIP_MEMSIZE};
+
+// Sample rate the beeper is rendered at, both for the SDL device and for WAV output
+pub const SAMPLE_RATE: u32 = 44100;
+
+pub const DEFAULT_FREQUENCY: f32 = 440.0;
+pub const DEFAULT_VOLUME: f32 = 0.25;
+
+// Time the tone takes to fade in when ST becomes non zero and to fade out when it reaches 0.
+// Switching a wave on or off mid-period makes an audible click.
+const FADE_SECONDS: f32 = 0.005;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Waveform {
+    Square,
+    Sine,
+}
+
+pub const WAVEFORM_NAMES: [&str; 2] = ["square", "sine"];
+
+impl Waveform {
+    pub fn from_name(name: &str) -> Option<Waveform> {
+        match name {
+            "square" => Some(Waveform::Square),
+            "sine" => Some(Waveform::Sine),
+            _ => None,
+        }
+    }
+}
+
+// XO-CHIP sound: the 128 bits F002 loaded, played from the most significant bit of the first
+// byte on at a rate set by FX3A, over and over while ST is non zero
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AudioPattern {
+    pub bits: [u8; AUDIO_PATTERN_SIZE],
+    pub pitch: u8,
+}
+
+impl AudioPattern {
+    // The pattern system plays instead of the tone: only with the 64 KiB of XO-CHIP memory, and
+    // only once F002 has loaded something other than the all zero buffer it starts with
+    pub fn from_system(system: &System) -> Option<AudioPattern> {
+        let cpu = &system.cpu;
+        if cpu.memory.borrow().len() != XO_CHIP_MEMSIZE || cpu.audio_pattern.iter().all(|byte| *byte == 0) {
+            return None;
+        }
+        Some(AudioPattern { bits: cpu.audio_pattern, pitch: cpu.pitch })
+    }
+
+    // Bits played per second, 4000 at the default pitch of 64 and doubling every 48 steps
+    pub fn rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.pitch as f32 - 64.0) / 48.0)
+    }
+
+    // The bit at position, 0.0 to 1.0, through the pattern
+    fn bit(&self, position: f32) -> bool {
+        let n = ((position * (AUDIO_PATTERN_SIZE * 8) as f32) as usize).min(AUDIO_PATTERN_SIZE * 8 - 1);
+        self.bits[n / 8] & (0x80 >> (n % 8)) != 0
+    }
+}
+
+// Tone generator for the sound timer. The frontend asks it for samples and says whether ST is
+// non zero, it takes care of the waveform and of fading in and out.
+#[derive(Debug, Clone)]
+pub struct Beeper {
+    pub frequency: f32,
+    pub waveform: Waveform,
+    pub volume: f32,            // 0.0 to 1.0
+    pub muted: bool,
+    pub pattern: Option<AudioPattern>, // Played instead of the waveform when set
+    sample_rate: u32,
+    phase: f32,                 // Position in the current period, 0.0 to 1.0
+    envelope: f32,              // Fade in/out level, 0.0 to 1.0
+}
+
+impl Beeper {
+    pub fn new(sample_rate: u32) -> Beeper {
+        Beeper {
+            frequency: DEFAULT_FREQUENCY,
+            waveform: Waveform::Square,
+            volume: DEFAULT_VOLUME,
+            muted: false,
+            pattern: None,
+            sample_rate,
+            phase: 0.0,
+            envelope: 0.0,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Number of samples that make up one 60 Hz frame
+    pub fn samples_per_frame(&self) -> usize {
+        (self.sample_rate / FRAMES_PER_SECOND) as usize
+    }
+
+    // Appends one frame of samples to out, the tone sounding if on is set
+    pub fn render_frame(&mut self, out: &mut Vec<f32>, on: bool) {
+        let start = out.len();
+        out.resize(start + self.samples_per_frame(), 0.0);
+        self.fill(&mut out[start..], on);
+    }
+
+    // Fills out with the next samples, the tone sounding while on is set
+    pub fn fill(&mut self, out: &mut [f32], on: bool) {
+        let target = if on && !self.muted { 1.0 } else { 0.0 };
+        let fade_step = 1.0 / (FADE_SECONDS * self.sample_rate as f32);
+        for sample in out.iter_mut() {
+            if self.envelope < target {
+                self.envelope = (self.envelope + fade_step).min(target);
+            } else if self.envelope > target {
+                self.envelope = (self.envelope - fade_step).max(target);
+            }
+            if self.envelope == 0.0 {
+                // Silent, start the next tone at the beginning of a period
+                self.phase = 0.0;
+                *sample = 0.0;
+                continue;
+            }
+            // With a pattern, phase is the position through its bits instead of through a period
+            let (wave, step) = match (self.pattern, self.waveform) {
+                (Some(pattern), _) => {
+                    let wave = if pattern.bit(self.phase) { 1.0 } else { -1.0 };
+                    (wave, pattern.rate() / (AUDIO_PATTERN_SIZE * 8) as f32)
+                },
+                (None, Waveform::Square) => (if self.phase < 0.5 { 1.0 } else { -1.0 }, self.frequency),
+                (None, Waveform::Sine) => ((self.phase * 2.0 * PI).sin(), self.frequency),
+            };
+            *sample = wave * self.envelope * self.volume;
+            self.phase += step / self.sample_rate as f32;
+            if self.phase >= 1.0 {
+                self.phase -= 1.0;
+            }
+        }
+    }
+}
+
+// Writes samples between -1.0 and 1.0 as a mono 16 bit PCM WAV file
+pub fn write_wav<W: Write>(mut out: W, sample_rate: u32, samples: &[f32]) -> io::Result<()> {
+    let data_len = (samples.len() * 2) as u32;
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_len).to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+    out.write_all(b"fmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;          // Size of the format chunk
+    out.write_all(&1u16.to_le_bytes())?;           // PCM
+    out.write_all(&1u16.to_le_bytes())?;           // Mono
+    out.write_all(&sample_rate.to_le_bytes())?;
+    out.write_all(&(sample_rate * 2).to_le_bytes())?; // Bytes per second
+    out.write_all(&2u16.to_le_bytes())?;           // Bytes per sample
+    out.write_all(&16u16.to_le_bytes())?;          // Bits per sample
+    out.write_all(b"data")?;
+    out.write_all(&data_len.to_le_bytes())?;
+    for sample in samples {
+        let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        out.write_all(&value.to_le_bytes())?;
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audio::{AudioPattern, Beeper};
+    use crate::quirks::Quirks;
+    use crate::rom::ROM;
+    use crate::system::{System, XO_CHIP_MEMSIZE};
+
+    #[test]
+    fn pattern_playback() {
+        // LD V0, 112; LD I, 0x20A; AUDIO; PITCH V0; JP 0x208; then a pattern starting with 8 set
+        // bits and 8 clear ones
+        let program: [u16; 6] = [0x6070, 0xA20A, 0xF002, 0xF03A, 0x1208, 0xFF00];
+        let rom = ROM::new(program.iter().flat_map(|word| word.to_be_bytes().to_vec()).collect());
+        let mut system = System::with_memory_size(Quirks::xo_chip(), XO_CHIP_MEMSIZE);
+        system.load_rom(&rom).unwrap();
+        assert_eq!(AudioPattern::from_system(&system), None);
+        for _ in 0..4 {
+            system.step().unwrap();
+        }
+        let pattern = AudioPattern::from_system(&system).unwrap();
+        assert_eq!(pattern.rate(), 8000.0);
+
+        // At 8000 bits/s and 16000 samples/s every bit lasts two samples
+        let mut beeper = Beeper::new(16000);
+        beeper.pattern = Some(pattern);
+        let mut out = vec![0.0; 32];
+        beeper.fill(&mut out, true);
+        let signs: Vec<bool> = out.iter().map(|sample| *sample > 0.0).collect();
+        assert_eq!(signs, [vec![true; 16], vec![false; 16]].concat());
+
+        // Not with less memory
+        let mut system = System::with_quirks(Quirks::xo_chip());
+        system.load_rom(&rom).unwrap();
+        for _ in 0..4 {
+            system.step().unwrap();
+        }
+        assert_eq!(AudioPattern::from_system(&system), None);
+    }
+}
@@ -0,0 +1,474 @@
+/* Runs a CHIP8 ROM without a window and dumps the final screen.
+
+   Exit status:
+     0 - ran for the requested number of frames/cycles
+     1 - bad arguments, the ROM or output file could not be read/written, or the ROM does not
+         fit in memory
+     2 - the CPU halted (jumped to itself) before the budget ran out
+     3 - an instruction failed (invalid opcode, stack overflow/underflow, memory access out of
+         range or invalid key) and --on-error is halt or trap
+     4 - with --backend lockstep, the recompiler and the interpreter disagreed
+*/
+
+use clap::{App, Arg};
+use std::fs::File;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::process;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+
+use chip8_emu::system::{self, ErrorPolicy, INSTR_PER_FRAME};
+use chip8_emu::debugger::{self, Reply};
+use chip8_emu::{audio, dap, gdbstub, quirks, read_rom, recompiler, timing, AudioPattern, Backend, Beeper, Debugger, ExecutionError, Movie, Quirks, System, Timing};
+
+const EXIT_OK: i32 = 0;
+const EXIT_USAGE: i32 = 1;
+const EXIT_HALTED: i32 = 2;
+const EXIT_EXECUTION_ERROR: i32 = 3;
+const EXIT_MISMATCH: i32 = 4;
+
+const DEFAULT_FRAMES: &str = "600";
+
+enum RunResult {
+    Completed,
+    Halted,
+    ExecutionError(String),
+    Mismatch(String),
+}
+
+// Runs a chunk of the budget: up to a frame of instructions, or a whole frame with VIP timing
+fn advance(system: &mut System, chunk: u64) -> Result<(), ExecutionError> {
+    match system.timing {
+        Timing::Fixed => system.run_ticks(chunk),
+        Timing::Vip => system.run_frame(),
+    }
+}
+
+// Runs the budget a frame at a time, checking for a halted CPU in between. With a reference
+// system the same instructions are run on it too and both machines are compared after every
+// frame. With VIP timing or a movie the budget is counted in frames rather than instructions,
+// and the movie's keys and speed are fed to the system before each frame.
+// With audio, one frame of beeper output is rendered after each chunk, sounding if ST is non zero.
+fn run(system: &mut System, mut reference: Option<&mut System>, mut audio: Option<&mut AudioRecording>, movie: Option<&Movie>, frames: u64, cycles: Option<u64>) -> RunResult {
+    let counts_frames = system.timing == Timing::Vip || movie.is_some();
+    let mut remaining = if counts_frames {
+        frames
+    } else {
+        cycles.unwrap_or(frames * system.cycles_per_frame as u64)
+    };
+    let mut frame = 0;
+    let mut executed = 0;
+    while remaining > 0 {
+        if let Some(movie) = movie {
+            movie.play_frame(frame, system);
+            if let Some(reference) = reference.as_mut() {
+                movie.play_frame(frame, reference);
+            }
+        }
+        let cycles_per_frame = system.cycles_per_frame as u64;
+        let chunk = if counts_frames { cycles_per_frame } else { remaining.min(cycles_per_frame) };
+        let result = advance(system, chunk);
+        if let Some(reference) = reference.as_mut() {
+            let reference_result = advance(reference, chunk);
+            if result != reference_result {
+                return RunResult::Mismatch(format!("results {:?} != {:?}", result, reference_result));
+            }
+            if let Some(difference) = compare(system, reference) {
+                return RunResult::Mismatch(format!("{} after chunk {}..{}", difference, executed, executed + chunk));
+            }
+        }
+        if let Some(audio) = audio.as_mut() {
+            audio.beeper.pattern = AudioPattern::from_system(system);
+            audio.beeper.render_frame(&mut audio.samples, system.cpu.st > 0);
+        }
+        if let Err(err) = result {
+            return RunResult::ExecutionError(err.to_string());
+        }
+        if system.cpu.is_halted() {
+            return RunResult::Halted;
+        }
+        remaining -= if counts_frames { 1 } else { chunk };
+        executed += chunk;
+        frame += 1;
+    }
+    RunResult::Completed
+}
+
+// Describes the first difference in machine state between two systems
+fn compare(system: &System, reference: &System) -> Option<String> {
+    let (cpu, other) = (&system.cpu, &reference.cpu);
+    if cpu.pc != other.pc {
+        return Some(format!("PC {:04X} != {:04X}", cpu.pc, other.pc));
+    }
+    if cpu.vregs != other.vregs {
+        return Some(format!("V registers {:02X?} != {:02X?}", cpu.vregs, other.vregs));
+    }
+    if cpu.i != other.i {
+        return Some(format!("I {:04X} != {:04X}", cpu.i, other.i));
+    }
+    if cpu.stack != other.stack {
+        return Some(format!("stack {:04X?} != {:04X?}", cpu.stack, other.stack));
+    }
+    if (cpu.dt, cpu.st) != (other.dt, other.st) {
+        return Some(format!("DT/ST {}/{} != {}/{}", cpu.dt, cpu.st, other.dt, other.st));
+    }
+    let memory = cpu.memory.borrow();
+    let other_memory = other.memory.borrow();
+    if let Some(addr) = (0..memory.len()).find(|addr| memory[*addr] != other_memory[*addr]) {
+        return Some(format!("memory at {:04X} {:02X} != {:02X}", addr, memory[addr], other_memory[addr]));
+    }
+    if system.display.mem[..] != reference.display.mem[..] || system.display.hires != reference.display.hires {
+        return Some("display differs".to_string());
+    }
+    None
+}
+
+// Beeper output of the whole run, written as a WAV file at the end
+struct AudioRecording {
+    beeper: Beeper,
+    samples: Vec<f32>,
+}
+
+// Reads debugger commands from stdin until quit or end of input. Lines are read on their own
+// thread so that an empty line can interrupt a continue; other lines typed while the program
+// runs are kept for after it stops.
+fn debug(system: &mut System) {
+    let mut debugger = Debugger::new();
+    let (sender, lines) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            if line.map(|line| sender.send(line)).is_err() {
+                break;
+            }
+        }
+    });
+    let mut pending = VecDeque::new();
+    let (text, _) = debugger::disassemble(&system.cpu, system.cpu.pc);
+    println!("{:04X}  {}", system.cpu.pc, text);
+    loop {
+        print!("(chip8) ");
+        io::stdout().flush().ok();
+        let line = match pending.pop_front() {
+            Some(line) => line,
+            None => match lines.recv() {
+                Ok(line) => line,
+                Err(_) => break,
+            },
+        };
+        match debugger.command(system, &line) {
+            Reply::Output(output) => if !output.is_empty() { println!("{}", output) },
+            Reply::Continue => {
+                let reason = debugger.cont_interruptible(system, || {
+                    while let Ok(line) = lines.try_recv() {
+                        if line.trim().is_empty() {
+                            return true;
+                        }
+                        pending.push_back(line);
+                    }
+                    false
+                });
+                println!("{}", debugger::stopped(system, reason));
+            },
+            Reply::Quit => break,
+        }
+    }
+}
+
+fn write_screen(system: &System, output: Option<&str>) -> io::Result<()> {
+    let screen = system.display.to_ascii();
+    match output {
+        Some(path) => File::create(path)?.write_all(screen.as_bytes()),
+        None => io::stdout().write_all(screen.as_bytes()),
+    }
+}
+
+fn main() {
+    let argmatches = App::new("CHIP8 Headless Runner")
+        .version("0.1.0")
+        .author("fhools")
+        .about("Runs a ROM without a window and dumps the final screen as ASCII art")
+        .arg(
+            Arg::with_name("file")
+                .short("f")
+                .long("file")
+                .takes_value(true)
+                .required_unless_one(&["dap", "dap-port"])
+                .help("filepath to ROM"),
+        )
+        .arg(
+            Arg::with_name("frames")
+                .long("frames")
+                .takes_value(true)
+                .default_value(DEFAULT_FRAMES)
+                .help("number of 60 Hz frames to run"),
+        )
+        .arg(
+            Arg::with_name("cycles")
+                .long("cycles")
+                .takes_value(true)
+                .conflicts_with_all(&["frames", "timing"])
+                .help("number of instructions to run, instead of --frames"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .help("write the final screen to this file instead of stdout"),
+        )
+        .arg(
+            Arg::with_name("wav")
+                .long("wav")
+                .takes_value(true)
+                .help("write the sound timer tone to this WAV file, one 60 Hz frame at a time"),
+        )
+        .arg(
+            Arg::with_name("time")
+                .long("time")
+                .help("print how long the run took and the instructions per second to stderr"),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .help("print every executed instruction"),
+        )
+        .arg(
+            Arg::with_name("backend")
+                .long("backend")
+                .takes_value(true)
+                .possible_values(&[recompiler::BACKEND_NAMES[0], recompiler::BACKEND_NAMES[1], "lockstep"])
+                .default_value("interpreter")
+                .help("execution engine, lockstep runs the recompiler and the interpreter side by side and compares them"),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .takes_value(true)
+                .help("seed for RND, so a run can be reproduced"),
+        )
+        .arg(
+            Arg::with_name("cycles-per-frame")
+                .long("cycles-per-frame")
+                .takes_value(true)
+                .help("instructions run per 60 Hz frame with fixed timing, 10 by default"),
+        )
+        .arg(
+            Arg::with_name("timing")
+                .long("timing")
+                .takes_value(true)
+                .possible_values(&timing::TIMING_NAMES)
+                .default_value("fixed")
+                .help("fixed runs 10 instructions per frame, vip charges each instruction what it cost on the COSMAC VIP"),
+        )
+        .arg(
+            Arg::with_name("on-error")
+                .long("on-error")
+                .takes_value(true)
+                .possible_values(&system::ERROR_POLICY_NAMES)
+                .default_value("halt")
+                .help("what to do when an instruction fails, ignore skips it and carries on"),
+        )
+        .arg(
+            Arg::with_name("quirks")
+                .long("quirks")
+                .takes_value(true)
+                .possible_values(&quirks::PRESET_NAMES)
+                .default_value("vip")
+                .help("interpreter whose quirks to emulate"),
+        )
+        .arg(
+            Arg::with_name("key-press-completes")
+                .long("key-press-completes")
+                .help("let FX0A complete when the key is pressed instead of when it is released"),
+        )
+        .arg(
+            Arg::with_name("debug")
+                .long("debug")
+                .help("start an interactive debugger on stdin instead of running, type help for its commands"),
+        )
+        .arg(
+            Arg::with_name("gdb")
+                .long("gdb")
+                .takes_value(true)
+                .conflicts_with("debug")
+                .help("wait for gdb to connect on this port of 127.0.0.1 and let it control the run"),
+        )
+        .arg(
+            Arg::with_name("dap")
+                .long("dap")
+                .conflicts_with_all(&["debug", "gdb"])
+                .help("serve the debug adapter protocol on stdin and stdout, the client's launch request names the ROM"),
+        )
+        .arg(
+            Arg::with_name("dap-port")
+                .long("dap-port")
+                .takes_value(true)
+                .conflicts_with_all(&["debug", "gdb", "dap"])
+                .help("serve the debug adapter protocol to one client on this port of 127.0.0.1 instead of stdio"),
+        )
+        .arg(
+            Arg::with_name("play-movie")
+                .long("play-movie")
+                .takes_value(true)
+                .conflicts_with("cycles")
+                .help("feed the keys recorded in this movie, with its seed and settings, for as many frames as it lasts unless --frames is given"),
+        )
+        .get_matches();
+
+    let parse_number = |name: &str| -> Option<u64> {
+        argmatches.value_of(name).map(|value| {
+            value.parse().unwrap_or_else(|_| {
+                eprintln!("--{} expects a number, got {}", name, value);
+                process::exit(EXIT_USAGE);
+            })
+        })
+    };
+    // The ROM and settings come with the client's launch request
+    if argmatches.is_present("dap") || argmatches.is_present("dap-port") {
+        let served = match parse_number("dap-port") {
+            Some(port) if port > u16::MAX as u64 => {
+                eprintln!("--dap-port expects a port number, got {}", port);
+                process::exit(EXIT_USAGE);
+            },
+            Some(port) => {
+                eprintln!("waiting for a debug adapter client on 127.0.0.1:{}", port);
+                dap::serve(("127.0.0.1", port as u16))
+            },
+            None => dap::serve_stdio(),
+        };
+        if let Err(err) = served {
+            eprintln!("debug adapter connection failed: {}", err);
+            process::exit(EXIT_USAGE);
+        }
+        process::exit(EXIT_OK);
+    }
+    let movie = argmatches.value_of("play-movie").map(|path| {
+        Movie::load(path).unwrap_or_else(|err| {
+            eprintln!("could not load movie {}: {}", path, err);
+            process::exit(EXIT_USAGE);
+        })
+    });
+    let frames = match movie {
+        Some(ref movie) if argmatches.occurrences_of("frames") == 0 => movie.frames.len() as u64,
+        _ => parse_number("frames").unwrap_or(0),
+    };
+    let cycles = parse_number("cycles");
+    let cycles_per_frame = parse_number("cycles-per-frame").unwrap_or(INSTR_PER_FRAME as u64).max(1) as u32;
+    // Both systems of a lockstep run need the same random numbers
+    let seed = parse_number("seed").unwrap_or_else(rand::random);
+
+    let filepath = argmatches.value_of("file").unwrap();
+    let rom = match read_rom(Path::new(filepath)) {
+        Ok(rom) => rom,
+        Err(err) => {
+            eprintln!("err: {}", err);
+            process::exit(EXIT_USAGE);
+        }
+    };
+
+    let preset = argmatches.value_of("quirks").unwrap();
+    let mut quirks = Quirks::from_preset_name(preset).unwrap();
+    quirks.key_wait_completes_on_press = argmatches.is_present("key-press-completes");
+    let memsize = if preset == "xochip" { system::XO_CHIP_MEMSIZE } else { system::MEMSIZE };
+    let error_policy = ErrorPolicy::from_name(argmatches.value_of("on-error").unwrap()).unwrap();
+    if let Some(ref movie) = movie {
+        if !movie.matches_rom(&rom) {
+            eprintln!("warning: the movie was recorded with a different ROM");
+        }
+    }
+    // A movie brings its own seed and settings
+    let new_system = |backend: Backend| {
+        let mut system = match movie {
+            Some(ref movie) => movie.new_system(),
+            None => {
+                let mut system = System::with_memory_size(quirks, memsize);
+                system.error_policy = error_policy;
+                system.set_seed(seed);
+                system.cycles_per_frame = cycles_per_frame;
+                system.timing = Timing::from_name(argmatches.value_of("timing").unwrap()).unwrap();
+                system
+            }
+        };
+        system.trace = argmatches.is_present("trace");
+        system.backend = backend;
+        if let Err(err) = system.load_rom(&rom) {
+            eprintln!("err: {}", err);
+            process::exit(EXIT_USAGE);
+        }
+        system
+    };
+    let backend = argmatches.value_of("backend").unwrap();
+    let lockstep = backend == "lockstep";
+    let mut system = new_system(Backend::from_name(backend).unwrap_or(Backend::Recompiler));
+    let mut reference = if lockstep { Some(new_system(Backend::Interpreter)) } else { None };
+
+    if argmatches.is_present("debug") {
+        debug(&mut system);
+        process::exit(EXIT_OK);
+    }
+    if let Some(port) = parse_number("gdb") {
+        if port > u16::MAX as u64 {
+            eprintln!("--gdb expects a port number, got {}", port);
+            process::exit(EXIT_USAGE);
+        }
+        eprintln!("waiting for gdb on 127.0.0.1:{}", port);
+        if let Err(err) = gdbstub::serve(&mut system, ("127.0.0.1", port as u16)) {
+            eprintln!("gdb connection failed: {}", err);
+            process::exit(EXIT_USAGE);
+        }
+        process::exit(EXIT_OK);
+    }
+
+    let start = Instant::now();
+    let mut audio = if argmatches.is_present("wav") {
+        Some(AudioRecording { beeper: Beeper::new(audio::SAMPLE_RATE), samples: vec![] })
+    } else {
+        None
+    };
+    let result = run(&mut system, reference.as_mut(), audio.as_mut(), movie.as_ref(), frames, cycles);
+    if argmatches.is_present("time") {
+        let elapsed = start.elapsed();
+        if system.timing == Timing::Vip || movie.is_some() {
+            eprintln!("ran in {:?}, {:.0} frames/s", elapsed, frames as f64 / elapsed.as_secs_f64());
+        } else {
+            let executed = cycles.unwrap_or(frames * system.cycles_per_frame as u64);
+            eprintln!("ran in {:?}, {:.0} instructions/s", elapsed, executed as f64 / elapsed.as_secs_f64());
+        }
+    }
+
+    if let (Some(audio), Some(path)) = (audio, argmatches.value_of("wav")) {
+        let written = File::create(path).and_then(|file| {
+            audio::write_wav(io::BufWriter::new(file), audio.beeper.sample_rate(), &audio.samples)
+        });
+        if let Err(err) = written {
+            eprintln!("could not write {}: {}", path, err);
+            process::exit(EXIT_USAGE);
+        }
+    }
+
+    if let Err(err) = write_screen(&system, argmatches.value_of("output")) {
+        eprintln!("could not write screen: {}", err);
+        process::exit(EXIT_USAGE);
+    }
+
+    let status = match result {
+        RunResult::Completed => EXIT_OK,
+        RunResult::Halted => {
+            eprintln!("CPU halted at PC {:04X}", system.cpu.pc);
+            EXIT_HALTED
+        }
+        RunResult::ExecutionError(err) => {
+            eprintln!("{}", err);
+            EXIT_EXECUTION_ERROR
+        }
+        RunResult::Mismatch(err) => {
+            eprintln!("recompiler and interpreter differ: {}", err);
+            EXIT_MISMATCH
+        }
+    };
+    process::exit(status);
+}
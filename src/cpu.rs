@@ -1,1167 +1,760 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
 use std::fmt::{self};
-use std::any::Any;
 
-use rand::Rng;
-use crate::System;
+use crate::machine::Machine;
+use crate::quirks::Quirks;
+use crate::system::BIG_FONT_OFFSET;
 
 pub const VF : usize = 0xF;
-#[derive(Debug)]
-pub struct CPU {
-    pub pc: u16,
-    pub vregs: [u8; 16],
-    pub i: u16,
-    pub stack: Vec<u16>,
-    pub memory: Rc<RefCell<Vec<u8>>>,
-    pub curr_keys: [Option<bool>; 16],
-    pub dt: u8,                        // Delay Timer, decrements a tick every 60HZ
-    pub st: u8,                        // Sound Timer, decrements a tick every 60HZ
-    pub is_halted: bool                // Is the program done executing? Self Jp
-}
-
-impl CPU {
-    pub fn stack_push(&mut self, addr: u16) {
-        assert_eq!(self.stack.len() < 16, true);
-        self.stack.push(addr);
-    }
-
-    pub fn is_halted(&self) -> bool {
-        self.is_halted
-    }
-}
-#[derive(Debug)]
-pub enum DecodeError {
-    GenericError,
-    GenericErrorEx(String)
-}
-impl fmt::Display for DecodeError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            DecodeError::GenericError =>
-                write!(f, "Something went wrong decoding error"),
-            DecodeError::GenericErrorEx(ref errmsg) =>
-                write!(f, "GenericError err: {}", errmsg)
-        }
-    }
-}
-use core::fmt::Debug;
-impl Debug for dyn Instruction {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "Series{{{}}}", self.print())
-    }
-}
-
-pub trait Instruction : fmt::Display  {
-    fn print(&self) -> String;
-
-    fn do_instr(&self, _cpu: &mut CPU) {
-        println!("\t\t{}", self);
-    }
-    // Must be called after execute() to finish executing instruction including setting up for pc for next step
-    fn incr_pc(&self, cpu: &mut CPU) {
-        cpu.pc += 2;
-    }
-
-    fn execute(&self, cpu: &mut CPU) {
-        //println!("Executing instr pc = 0x{:X}, instr: {}", cpu.pc, self.print());
-        self.do_instr(cpu);
-    }
-
-    fn check_completed(&self, _system: &mut System) -> bool {
-        return true
-    }
-
-    fn is_waited_instr(&self) -> bool {
-        return false
-    }
-
-    fn as_any(&self) -> &dyn Any;
-}
-
-pub struct SysInstr {
-    pub addr: u16
-}
-
-impl Instruction for SysInstr {
-    fn print(&self) -> String {
-        "SYS".to_string()
-    }
-
-    fn do_instr(&self, _cpu: &mut CPU) {
-        //println!("executed SYS");
-    }
-
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-}
-
-impl fmt::Display for SysInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
-}
-
-pub struct ClrInstr {
-
-}
-
-impl Instruction for ClrInstr {
-    fn print(&self) -> String {
-        "CLR".to_string()
-    }
-
-    fn do_instr(&self, _cpu: &mut CPU) {
-        println!("excuted CLR");
-    }
-
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-}
-
-pub struct RetInstr {}
-
-impl Instruction for RetInstr {
-    fn print(&self) -> String {
-        "RET".to_string()
-    }
-
-    fn do_instr(&self, _cpu: &mut CPU) {
-        //println!("executed RET");
-    }
-
-    fn incr_pc(&self, cpu: &mut CPU) {
-        let return_addr = cpu.stack.pop();
-        match return_addr {
-            Some(addr) => {
-                cpu.pc = addr;
-            },
-            None => {
-                println!("Error excuting return. Empty stack");
-            }
-        }
-    }
-
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-}
-
-impl fmt::Display for RetInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
-}
-
-
-pub struct CallInstr {
-    addr: u16
-}
-impl Instruction for CallInstr {
-    fn print(&self) -> String {
-        let result = format!("CALL {:x} ", self.addr);
-        result
-    }
-
-    fn do_instr(&self, cpu: &mut CPU) {
-        //println!("executed {}", self);
-        cpu.stack_push(cpu.pc + 2);
-    }
-
-    fn incr_pc(&self, cpu: &mut CPU) {
-        cpu.pc = self.addr;
-    }
-
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-}
-
-impl fmt::Display for CallInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
-}
-
-impl fmt::Display for ClrInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
-}
-
-pub struct JpInstr {
-    pub addr : u16,
-}
-
-impl Instruction for JpInstr {
-    fn print(&self) -> String {
-        let jp = format!("JP {:x}", self.addr);
-        jp
-    }
-
-
-    fn do_instr(&self, cpu: &mut CPU) {
-        //println!("Executing {}", self);
-        if cpu.pc == self.addr {
-            println!("JP to same address. Must be the end of the program");
-            cpu.is_halted = true
-        }
-    }
-
-    fn incr_pc(&self, cpu: &mut CPU) {
-        cpu.pc = self.addr;
-    }
-
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-}
-
-impl fmt::Display for JpInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
-}
-
-pub struct SeInstr {
-    pub vx: u8,
-    pub val: u8,
-}
-
-impl Instruction for SeInstr {
-    fn print(&self) -> String {
-        let skipeq = format!("SE V{:x}, {:x}", self.vx, self.val);
-        skipeq
-    }
-
-    fn do_instr(&self, _cpu: &mut CPU) {
-        //println!("executed SE");
-    }
-
-    fn incr_pc(&self, cpu: &mut CPU) {
-        if cpu.vregs[self.vx as usize] == self.val {
-            cpu.pc += 4;
-        } else {
-            cpu.pc +=2;
-        }
-    }
-
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-}
-
-impl fmt::Display for SeInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
-}
-
-pub struct SneInstr {
-    pub vx: u8,
-    pub val: u8,
-}
-
-impl Instruction for SneInstr {
-    fn print(&self) -> String {
-        let sne = format!("SNE V{:x}, {:x}", self.vx, self.val);
-        sne 
-    }
-
-    fn do_instr(&self, _cpu: &mut CPU) {
-        //println!("executed {}", self);
-    }
-
-    fn incr_pc(&self, cpu: &mut CPU) {
-        if cpu.vregs[self.vx as usize] != self.val {
-            cpu.pc += 4;
-        } else {
-            cpu.pc +=2;
-        }
-    }
-
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-}
-
-impl fmt::Display for SneInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
-}
-
-struct SeRegsInstr {
-    pub vx: u8,
-    pub vy: u8
-}
-
-impl Instruction for SeRegsInstr {
-    fn print(&self) -> String {
-        let se = format!("SE V{:x}, V{:x}", self.vx, self.vy);
-        se
-    }
-
-    fn do_instr(&self, _cpu: &mut CPU) {
-        //println!("executed {}", self);
-    }
-
-    fn incr_pc(&self, cpu: &mut CPU) {
-        if cpu.vregs[self.vx as usize] == cpu.vregs[self.vy as usize] {
-            cpu.pc += 4;
-        } else {
-            cpu.pc +=2;
-        }
-    }
-
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-}
-
-impl fmt::Display for SeRegsInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
-}
-
-struct LdValInstr {
-    vx: u8,
-    value: u8
-}
-
-impl Instruction for LdValInstr {
-    fn print(&self) -> String {
-        let ld = format!("LD V{:X}, {:x}", self.vx, self.value); 
-        ld
-    }
-
-    fn do_instr(&self, cpu: &mut CPU) {
-        cpu.vregs[self.vx as usize] = self.value;
-    }
-
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-}
-
-impl fmt::Display for LdValInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
-}
-
-struct AddImmediateInstr {
-    vx: u8,
-    value: u8
-}
-
-impl Instruction for AddImmediateInstr {
-    fn print(&self) -> String {
-        let add = format!("ADD V{:X}, {:x}", self.vx, self.value); 
-        add
-    }
-
-    fn do_instr(&self, cpu: &mut CPU) {
-        println!("vregs {:X} = {:X} value = {:X}", self.vx,cpu.vregs[self.vx as usize], self.value );
-        cpu.vregs[self.vx as usize] = cpu.vregs[self.vx as usize].wrapping_add(self.value);
-    }
-
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-}
-
-impl fmt::Display for AddImmediateInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
-}
-
-struct LdRegInstr {
-    vx: u8,
-    vy: u8
-}
-
-impl Instruction for LdRegInstr {
-    fn print(&self) -> String {
-        let ld = format!("LD V{:X}, V{:X}", self.vx, self.vy);
-        ld
-    }
-
-    fn do_instr(&self, cpu: &mut CPU) {
-        cpu.vregs[self.vx as usize] = cpu.vregs[self.vy as usize];
-    }
-
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-}
-
-impl fmt::Display for LdRegInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
-}
-
-struct OrInstr {
-    vx: u8,
-    vy: u8
-}
-
-impl Instruction for OrInstr {
-    fn print(&self) -> String {
-        let ld = format!("OR V{:X}, V{:X}", self.vx, self.vy);
-        ld
-    }
-
-    fn do_instr(&self, cpu: &mut CPU) {
-        cpu.vregs[self.vx as usize] = cpu.vregs[self.vx as usize] | cpu.vregs[self.vy as usize];
-    }
-
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-}
-
-impl fmt::Display for OrInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
-}
-
-struct AndInstr {
-    vx: u8,
-    vy: u8
-}
-
-impl Instruction for AndInstr {
-    fn print(&self) -> String {
-        let ld = format!("AND V{:X}, V{:X}", self.vx, self.vy);
-        ld
-    }
-    
-    fn do_instr(&self, cpu: &mut CPU) {
-        cpu.vregs[self.vx as usize] = cpu.vregs[self.vx as usize] & cpu.vregs[self.vy as usize];
-    }
-
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-}
-
-impl fmt::Display for AndInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
-}
-
-struct XorInstr {
-    vx: u8,
-    vy: u8
-}
-
-impl Instruction for XorInstr {
-    fn print(&self) -> String {
-        let xor = format!("XOR V{:X}, V{:X}", self.vx, self.vy);
-        xor 
-    }
-
-    fn do_instr(&self, cpu: &mut CPU) {
-        cpu.vregs[self.vx as usize] = cpu.vregs[self.vx as usize] ^ cpu.vregs[self.vy as usize];
-    }
-
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-}
-
-impl fmt::Display for XorInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
-}
-
-struct AddInstr {
-    vx: u8,
-    vy: u8
-}
-
-impl Instruction for AddInstr {
-    fn print(&self) -> String {
-        let add = format!("ADD V{:X}, V{:X}", self.vx, self.vy);
-        add
-    }
-
-    fn do_instr(&self, cpu: &mut CPU) {
-        let (add_result, carry) = cpu.vregs[self.vx as usize].overflowing_add(cpu.vregs[self.vy as usize]); 
-        cpu.vregs[self.vx as usize] = add_result;
-        cpu.vregs[0xF] = carry as u8;
-    }
-
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-}
-
-impl fmt::Display for AddInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
-}
-
-struct SubInstr {
-    vx: u8,
-    vy: u8
-}
-
-impl Instruction for SubInstr {
-    fn print(&self) -> String {
-        let sub = format!("SUB V{:X}, V{:X}", self.vx, self.vy);
-        sub 
-    }
-
-    fn do_instr(&self, cpu: &mut CPU) {
-        let (sub_result, not_underflow) = cpu.vregs[self.vx as usize].overflowing_sub(cpu.vregs[self.vy as usize]); 
-        cpu.vregs[self.vx as usize] = sub_result;
-        cpu.vregs[0xF] = (!not_underflow) as u8;
-    }
-
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-}
-
-impl fmt::Display for SubInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
-}
-
-struct ShrInstr {
-    vx: u8,
-    vy: u8
-}
-
-impl Instruction for ShrInstr {
-    fn print(&self) -> String {
-        let shr = format!("SHR V{:X}, V{:X}", self.vx, self.vy);
-        shr 
-    }
-
-    fn do_instr(&self, cpu: &mut CPU) {
-       if cpu.vregs[self.vx as usize] & 1 == 1 {
-           cpu.vregs[VF] = 1;
-       }
-       cpu.vregs[self.vx as usize] = cpu.vregs[self.vx as usize].wrapping_shr(1); 
-    }
-
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-}
-
-impl fmt::Display for ShrInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
-}
-
-struct SubnInstr {
-    vx: u8,
-    vy: u8
-}
-
-impl Instruction for SubnInstr {
-    fn print(&self) -> String {
-        let subn = format!("SUBN V{:X}, V{:X}", self.vx, self.vy);
-        subn
-    }
-
-    fn do_instr(&self, cpu: &mut CPU) {
-        let (sub_result, not_underflow) = cpu.vregs[self.vx as usize].overflowing_sub(cpu.vregs[self.vy as usize]); 
-        cpu.vregs[self.vx as usize] = sub_result;
-        cpu.vregs[VF] = not_underflow as u8;
-    }
-
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-}
-
-impl fmt::Display for SubnInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
-}
-
-struct ShlInstr {
-    vx: u8,
-    vy: u8
-}
-
-impl Instruction for ShlInstr {
-    fn print(&self) -> String {
-        let shl = format!("SHL V{:X}, V{:X}", self.vx, self.vy);
-        shl 
-    }
-
-    fn do_instr(&self, cpu: &mut CPU) {
-       if cpu.vregs[self.vx as usize] & 0x80 == 0x80 {
-           cpu.vregs[VF] = 1;
-       }
-       cpu.vregs[self.vx as usize] = cpu.vregs[self.vx as usize].wrapping_shl(1); 
-    }
-
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-}
-
-impl fmt::Display for ShlInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
-}
-
-struct SneRegInstr {
-    vx: u8,
-    vy: u8
-}
-
-impl Instruction for SneRegInstr {
-    fn print(&self) -> String {
-        let sne = format!("SNE V{:X}, V{:X}", self.vx, self.vy);
-        sne 
-    }
-
-    fn do_instr(&self, _cpu: &mut CPU) {
-        //println!("executed {}", self);
-    }
-
-    fn incr_pc(&self, cpu: &mut CPU) {
-        if cpu.vregs[self.vx as usize] != cpu.vregs[self.vy as usize] {
-            cpu.pc += 4;
-        } else {
-            cpu.pc +=2;
-        }
-    }
-
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-}
-
-impl fmt::Display for SneRegInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
-}
-
-struct LdIInstr {
-    addr: u16
-}
-
-impl Instruction for LdIInstr {
-    fn print(&self) -> String {
-        let ld_i = format!("LD I, {:X}", self.addr);
-        ld_i 
-    }
-
-    fn do_instr(&self, cpu: &mut CPU) {
-        cpu.i = self.addr; 
-    }
-
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-}
-
-impl fmt::Display for LdIInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
-}
-
-struct RndInstr {
-    vx: u8,
-    value: u8
-}
-
-impl Instruction for RndInstr {
-    fn print(&self) -> String {
-        let rnd = format!("RND V{:X}, 0x{:X}", self.vx, self.value);
-        rnd 
-    }
-
-    fn do_instr(&self, cpu: &mut CPU) {
-        //println!("executed {}", self);
-        let mut rng = rand::thread_rng();
-        let random_byte : u8 = rng.gen();
-        cpu.vregs[self.vx as usize] = random_byte & self.value; 
-    }
-
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-}
-
-impl fmt::Display for RndInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
-}
-
-struct JpV0Instr {
-    addr: u16
-}
-
-impl Instruction for JpV0Instr {
-    fn print(&self) -> String {
-        let jpv0 = format!("JP V0, 0x{:X}", self.addr);
-        jpv0 
-    }
-
-    fn do_instr(&self, _cpu: &mut CPU) {
-        //println!("executed {}", self);
-    }
-
-    fn incr_pc(&self, cpu: &mut CPU) {
-        cpu.pc = cpu.vregs[0] as u16 + self.addr;
-    }
-
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-}
-
-impl fmt::Display for JpV0Instr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
-}
-
-pub struct DrwInstr {
-    pub vx: u8,
-    pub vy: u8,
-    pub n: u8
-}
-
-impl Instruction for DrwInstr {
-    fn print(&self) -> String {
-        let drw = format!("DRW V{:X}, V{:X}, {:x} ", self.vx, self.vy, self.n);
-        drw
-    }
-
-    fn do_instr(&self, _cpu: &mut CPU)  {
-        //println!("Drawing pixel at V{:X}, {:X}, {} bytes", self.vx, self.vy, self.n)
-    }
-
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-
-    fn is_waited_instr(&self) -> bool {
-        false
-    }
-}
-
-impl fmt::Display for DrwInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
-}
-
-struct SkpInstr {
-    vx: u8,
-}
-
-impl Instruction for SkpInstr {
-    fn print(&self) -> String {
-        format!("SKP V{:X}", self.vx)
-    }
-
-    fn do_instr(&self, _cpu: &mut CPU) {
-        //println!("executed {}", self)
-    }
-
-    fn incr_pc(&self, cpu: &mut CPU) {
-        let keyid = cpu.vregs[self.vx as usize] as usize;
-        match cpu.curr_keys[keyid] {
-            Some(_) => {
-                    cpu.pc += 4;
-                    return;
-            },
-            None => {}
-        }
-        cpu.pc += 2;
-    }
-
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-}
-
-impl fmt::Display for SkpInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
-}
-
-struct SknpInstr {
-    vx: u8,
-}
-
-impl Instruction for SknpInstr {
-    fn print(&self) -> String {
-        format!("SKNP V{:X}", self.vx)
-    }
 
-    fn do_instr(&self, _cpu: &mut CPU) {
-        println!("executed {}", self);
-    }
+// XO-CHIP F000 NNNN, the only instruction followed by a second word
+pub const LD_I_LONG_OPCODE : u16 = 0xF000;
 
-    fn incr_pc(&self, cpu: &mut CPU) {
-        let keyid = cpu.vregs[self.vx as usize] as usize;
-        match cpu.curr_keys[keyid] {
-            Some(_) => {
-                println!("SKNP: key {:X} is pressed", keyid);
-            },  
-            None => {
-                println!("SKNP: key {:X} is not pressed", keyid);
-                cpu.pc += 4;
-                return;
-            }
-        }
-        cpu.pc += 2;
-    }
+// Size of the XO-CHIP audio pattern buffer loaded by F002
+pub const AUDIO_PATTERN_SIZE : usize = 16;
 
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-}
+// Number of return addresses CALL can nest
+pub const STACK_SIZE : usize = 16;
 
-impl fmt::Display for SknpInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
+// A range of memory whose loads or stores are reported, for the debugger. Only data accesses
+// count: FX33/FX55/FX65 and the other loads and stores, and sprite reads. Instruction fetches
+// do not.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Watchpoint {
+    pub start: usize,
+    pub end: usize,                    // Inclusive
+    pub on_read: bool,
+    pub on_write: bool,
 }
 
-struct LdDtInstr {
-    vx: u8,
+// A load or store that hit a watchpoint
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatchHit {
+    pub pc: u16,                       // Instruction that made the access
+    pub addr: usize,
+    pub value: u8,                     // Byte read, or byte written
+    pub write: bool,
 }
 
-impl Instruction for LdDtInstr {
-    fn print(&self) -> String {
-        format!("LD V{:X}, DT", self.vx)
-    }
-
-    fn do_instr(&self, cpu: &mut CPU) {
-        cpu.vregs[self.vx as usize] = cpu.dt;
-    }
-
-    fn as_any(&self) ->  &dyn Any {
-        self
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let access = if self.write { "write" } else { "read" };
+        write!(f, "{} of {:02X} at {:04X} by PC {:04X}", access, self.value, self.addr, self.pc)
     }
 }
+#[derive(Debug)]
+pub struct CPU {
+    pub pc: u16,
+    pub vregs: [u8; 16],
+    pub i: u16,
+    pub stack: Vec<u16>,
+    pub memory: Rc<RefCell<Vec<u8>>>,
+    pub curr_keys: [Option<bool>; 16],
+    pub dt: u8,                        // Delay Timer, decrements a tick every 60HZ
+    pub st: u8,                        // Sound Timer, decrements a tick every 60HZ
+    pub is_halted: bool,               // Is the program done executing? Self Jp
+    pub quirks: Quirks,                // Interpreter specific behaviour the instructions follow
+    pub rpl: [u8; 16],                 // SUPER-CHIP RPL user flags, saved/loaded by FX75/FX85
+    pub audio_pattern: [u8; AUDIO_PATTERN_SIZE], // XO-CHIP 1-bit audio samples, loaded by F002
+    pub pitch: u8,                     // XO-CHIP audio playback rate, set by FX3A
+    pub waiting_key: Option<u8>,       // Key pressed during FX0A, waiting to be released
+    pub watchpoints: Vec<Watchpoint>,  // Memory ranges the debugger is watching
 
-impl fmt::Display for LdDtInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
-}
+    // First access that hit a watchpoint since the last take_watch_hit()
+    watch_hit: Cell<Option<WatchHit>>,
 
-struct LdKeyInstr {
-    vx: u8,
+    // Decoded instruction for each address, filled in by fetch_instr_from_pc. store_byte_mem
+    // drops the entries covering the written byte, so self-modifying code is decoded again.
+    // Writes that go to memory directly must call clear_decode_cache().
+    decode_cache: RefCell<Vec<Option<Opcode>>>,
+    // Address range of decoded instructions overwritten since the last take_code_writes()
+    code_writes: Cell<Option<(usize, usize)>>,
 }
 
-impl Instruction for LdKeyInstr {
-    fn print(&self) -> String {
-        format!("LD V{:X}, K", self.vx)
-    }
-
-    fn do_instr(&self, _cpu: &mut CPU) {
-
-    }
-
-    fn is_waited_instr(&self) -> bool {
-        return true;
+impl CPU {
+    pub fn stack_push(&mut self, addr: u16) -> Result<(), ExecutionError> {
+        if self.stack.len() >= STACK_SIZE {
+            return Err(ExecutionError::StackOverflow { pc: self.pc });
+        }
+        self.stack.push(addr);
+        Ok(())
     }
 
-    fn check_completed(&self, _system: &mut System) -> bool {
-        return false
+    pub fn is_halted(&self) -> bool {
+        self.is_halted
     }
 
-    fn as_any(&self) ->  &dyn Any {
-        self
+    // Moves pc to the next instruction, or past it when condition holds. Like Octo, pc wraps
+    // around from the top of a 64 KiB memory to 0.
+    pub fn skip_if(&mut self, condition: bool) {
+        if condition {
+            self.skip_next_instr();
+        } else {
+            self.pc = self.pc.wrapping_add(2);
+        }
     }
-}
 
-impl fmt::Display for LdKeyInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
+    // Moves pc past the current instruction and the one after it. The XO-CHIP F000 NNNN
+    // instruction is 4 bytes long, so skipping over it moves pc by 6.
+    pub fn skip_next_instr(&mut self) {
+        self.pc = self.pc.wrapping_add(2);
+        if self.fetch_instr_from_addr(self.pc as usize) == Ok(LD_I_LONG_OPCODE) {
+            self.pc = self.pc.wrapping_add(4);
+        } else {
+            self.pc = self.pc.wrapping_add(2);
+        }
     }
 }
-
-struct StoreDtInstr {
-    vx: u8,
-}
-
-impl Instruction for StoreDtInstr {
-    fn print(&self) -> String {
-        format!("LD DT, V{:X}", self.vx)
-    }
-
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
+#[derive(Debug)]
+pub enum DecodeError {
+    GenericError,
+    GenericErrorEx(String)
 }
-
-impl fmt::Display for StoreDtInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::GenericError =>
+                write!(f, "Something went wrong decoding error"),
+            DecodeError::GenericErrorEx(ref errmsg) =>
+                write!(f, "GenericError err: {}", errmsg)
+        }
     }
 }
 
-struct StoreStInstr {
-    vx: u8,
-}
-
-impl Instruction for StoreStInstr {
-    fn print(&self) -> String {
-        format!("LD ST, V{:X}", self.vx)
-    }
-
-    fn do_instr(&self, cpu: &mut CPU) {
-        cpu.st = cpu.vregs[self.vx as usize];
-    }
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
+// Errors that stop an instruction from executing. pc is the address of the instruction, and
+// the machine is left as it was before the instruction ran.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExecutionError {
+    // CALL with STACK_SIZE return addresses already on the stack
+    StackOverflow { pc: u16 },
+    // RET with an empty stack
+    StackUnderflow { pc: u16 },
+    // The word at pc is not an instruction
+    InvalidOpcode { pc: u16, opcode: u16 },
+    // An instruction fetch, load, store or sprite read past the end of memory
+    MemoryOutOfRange { pc: u16, addr: usize },
+    // SKP/SKNP with a VX that is not one of the 16 keys
+    InvalidKey { pc: u16, key: u8 },
 }
 
-impl fmt::Display for StoreStInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
+impl fmt::Display for ExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExecutionError::StackOverflow { pc } =>
+                write!(f, "stack overflow at PC {:04X}", pc),
+            ExecutionError::StackUnderflow { pc } =>
+                write!(f, "return with an empty stack at PC {:04X}", pc),
+            ExecutionError::InvalidOpcode { pc, opcode } =>
+                write!(f, "invalid opcode {:04X} at PC {:04X}", opcode, pc),
+            ExecutionError::MemoryOutOfRange { pc, addr } =>
+                write!(f, "memory address {:04X} out of range at PC {:04X}", addr, pc),
+            ExecutionError::InvalidKey { pc, key } =>
+                write!(f, "invalid key {:X} at PC {:04X}", key, pc),
+        }
     }
 }
-
-struct AddIInstr {
-    vx: u8,
-}
-
-impl Instruction for AddIInstr {
-    fn print(&self) -> String {
-        format!("ADD I,  V{:X}", self.vx)
+// A decoded instruction. Decoding happens once per fetch and execution is a match on the
+// variant, so stepping the CPU does not allocate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Opcode {
+    Sys { addr: u16 },
+    Clr,
+    Ret,
+    Call { addr: u16 },
+    Jp { addr: u16 },
+    Se { vx: u8, val: u8 },
+    Sne { vx: u8, val: u8 },
+    SeRegs { vx: u8, vy: u8 },
+    LdVal { vx: u8, value: u8 },
+    AddImmediate { vx: u8, value: u8 },
+    LdReg { vx: u8, vy: u8 },
+    Or { vx: u8, vy: u8 },
+    And { vx: u8, vy: u8 },
+    Xor { vx: u8, vy: u8 },
+    Add { vx: u8, vy: u8 },
+    Sub { vx: u8, vy: u8 },
+    Shr { vx: u8, vy: u8 },
+    Subn { vx: u8, vy: u8 },
+    Shl { vx: u8, vy: u8 },
+    SneReg { vx: u8, vy: u8 },
+    LdI { addr: u16 },
+    JpV0 { addr: u16 },
+    Rnd { vx: u8, value: u8 },
+    Drw { vx: u8, vy: u8, n: u8 },
+    Skp { vx: u8 },
+    Sknp { vx: u8 },
+    LdDt { vx: u8 },
+    LdKey { vx: u8 },
+    StoreDt { vx: u8 },
+    StoreSt { vx: u8 },
+    AddI { vx: u8 },
+    StoreSprite { vx: u8 },
+    StoreBcd { vx: u8 },
+    StoreVxsIntoI { vx: u8 },
+    LdVxsFromI { vx: u8 },
+
+    // SUPER-CHIP 1.1 instructions
+    Scd { n: u8 },
+    Scr,
+    Scl,
+    Exit,
+    Low,
+    High,
+    LdHf { vx: u8 },
+    StoreRpl { vx: u8 },
+    LdRpl { vx: u8 },
+
+    // XO-CHIP instructions
+    LdILong { addr: u16 },
+    StoreRange { vx: u8, vy: u8 },
+    LdRange { vx: u8, vy: u8 },
+    Scu { n: u8 },
+    Plane { planes: u8 },
+    Audio,
+    Pitch { vx: u8 },
+}
+
+// Registers VX through VY inclusive, in the order they are stored. Y may be lower than X.
+fn register_range(vx: u8, vy: u8) -> Vec<u8> {
+    if vx <= vy {
+        (vx..=vy).collect()
+    } else {
+        (vy..=vx).rev().collect()
+    }
+}
+
+impl Opcode {
+    // Mnemonic used for disassembly and tracing
+    pub fn print(&self) -> String {
+        match *self {
+            Opcode::Sys { .. } => "SYS".to_string(),
+            Opcode::Clr => "CLR".to_string(),
+            Opcode::Ret => "RET".to_string(),
+            Opcode::Call { addr } => format!("CALL {:x} ", addr),
+            Opcode::Jp { addr } => format!("JP {:x}", addr),
+            Opcode::Se { vx, val } => format!("SE V{:x}, {:x}", vx, val),
+            Opcode::Sne { vx, val } => format!("SNE V{:x}, {:x}", vx, val),
+            Opcode::SeRegs { vx, vy } => format!("SE V{:x}, V{:x}", vx, vy),
+            Opcode::LdVal { vx, value } => format!("LD V{:X}, {:x}", vx, value),
+            Opcode::AddImmediate { vx, value } => format!("ADD V{:X}, {:x}", vx, value),
+            Opcode::LdReg { vx, vy } => format!("LD V{:X}, V{:X}", vx, vy),
+            Opcode::Or { vx, vy } => format!("OR V{:X}, V{:X}", vx, vy),
+            Opcode::And { vx, vy } => format!("AND V{:X}, V{:X}", vx, vy),
+            Opcode::Xor { vx, vy } => format!("XOR V{:X}, V{:X}", vx, vy),
+            Opcode::Add { vx, vy } => format!("ADD V{:X}, V{:X}", vx, vy),
+            Opcode::Sub { vx, vy } => format!("SUB V{:X}, V{:X}", vx, vy),
+            Opcode::Shr { vx, vy } => format!("SHR V{:X}, V{:X}", vx, vy),
+            Opcode::Subn { vx, vy } => format!("SUBN V{:X}, V{:X}", vx, vy),
+            Opcode::Shl { vx, vy } => format!("SHL V{:X}, V{:X}", vx, vy),
+            Opcode::SneReg { vx, vy } => format!("SNE V{:X}, V{:X}", vx, vy),
+            Opcode::LdI { addr } => format!("LD I, {:X}", addr),
+            Opcode::JpV0 { addr } => format!("JP V0, 0x{:X}", addr),
+            Opcode::Rnd { vx, value } => format!("RND V{:X}, 0x{:X}", vx, value),
+            Opcode::Drw { vx, vy, n } => format!("DRW V{:X}, V{:X}, {:x} ", vx, vy, n),
+            Opcode::Skp { vx } => format!("SKP V{:X}", vx),
+            Opcode::Sknp { vx } => format!("SKNP V{:X}", vx),
+            Opcode::LdDt { vx } => format!("LD V{:X}, DT", vx),
+            Opcode::LdKey { vx } => format!("LD V{:X}, K", vx),
+            Opcode::StoreDt { vx } => format!("LD DT, V{:X}", vx),
+            Opcode::StoreSt { vx } => format!("LD ST, V{:X}", vx),
+            Opcode::AddI { vx } => format!("ADD I,  V{:X}", vx),
+            Opcode::StoreSprite { vx } => format!("LD F, V{:X}", vx),
+            Opcode::StoreBcd { vx } => format!("LD B, V{:X}", vx),
+            Opcode::StoreVxsIntoI { vx } => format!("LD [I], V{:X}", vx),
+            Opcode::LdVxsFromI { vx } => format!("LD V{:X}, [I]", vx),
+            Opcode::Scd { n } => format!("SCD {:x}", n),
+            Opcode::Scr => "SCR".to_string(),
+            Opcode::Scl => "SCL".to_string(),
+            Opcode::Exit => "EXIT".to_string(),
+            Opcode::Low => "LOW".to_string(),
+            Opcode::High => "HIGH".to_string(),
+            Opcode::LdHf { vx } => format!("LD HF, V{:X}", vx),
+            Opcode::StoreRpl { vx } => format!("LD R, V{:X}", vx),
+            Opcode::LdRpl { vx } => format!("LD V{:X}, R", vx),
+            Opcode::LdILong { addr } => format!("LD I, LONG {:X}", addr),
+            Opcode::StoreRange { vx, vy } => format!("SAVE V{:X} - V{:X}", vx, vy),
+            Opcode::LdRange { vx, vy } => format!("LOAD V{:X} - V{:X}", vx, vy),
+            Opcode::Scu { n } => format!("SCU {:x}", n),
+            Opcode::Plane { planes } => format!("PLANE {:x}", planes),
+            Opcode::Audio => "AUDIO".to_string(),
+            Opcode::Pitch { vx } => format!("PITCH V{:X}", vx),
+        }
     }
 
-    fn do_instr(&self, cpu: &mut CPU) {
-        cpu.i = cpu.i + (cpu.vregs[self.vx as usize] as u16);
-    }
-    fn as_any(&self) ->  &dyn Any {
-        self
+    // Executes the instruction's effect on the machine. Must be followed by incr_pc().
+    pub fn execute(&self, machine: &mut Machine) -> Result<(), ExecutionError> {
+        let cpu = &mut *machine.cpu;
+        match *self {
+            Opcode::Clr => machine.update_display(|display| display.clear()),
+            Opcode::Call { .. } => {
+                cpu.stack_push(cpu.pc.wrapping_add(2))?;
+            },
+            Opcode::Jp { addr } if cpu.pc == addr => {
+                //println!("JP to same address. Must be the end of the program");
+                cpu.is_halted = true
+            },
+            Opcode::LdVal { vx, value } => {
+                cpu.vregs[vx as usize] = value;
+            },
+            Opcode::AddImmediate { vx, value } => {
+                cpu.vregs[vx as usize] = cpu.vregs[vx as usize].wrapping_add(value);
+            },
+            Opcode::LdReg { vx, vy } => {
+                cpu.vregs[vx as usize] = cpu.vregs[vy as usize];
+            },
+            Opcode::Or { vx, vy } => {
+                cpu.vregs[vx as usize] |= cpu.vregs[vy as usize];
+                if cpu.quirks.logic_resets_vf {
+                    cpu.vregs[VF] = 0;
+                }
+            },
+            Opcode::And { vx, vy } => {
+                cpu.vregs[vx as usize] &= cpu.vregs[vy as usize];
+                if cpu.quirks.logic_resets_vf {
+                    cpu.vregs[VF] = 0;
+                }
+            },
+            Opcode::Xor { vx, vy } => {
+                cpu.vregs[vx as usize] ^= cpu.vregs[vy as usize];
+                if cpu.quirks.logic_resets_vf {
+                    cpu.vregs[VF] = 0;
+                }
+            },
+            Opcode::Add { vx, vy } => {
+                let (add_result, carry) = cpu.vregs[vx as usize].overflowing_add(cpu.vregs[vy as usize]);
+                cpu.vregs[vx as usize] = add_result;
+                cpu.vregs[VF] = carry as u8;
+            },
+            Opcode::Sub { vx, vy } => {
+                let (sub_result, not_underflow) = cpu.vregs[vx as usize].overflowing_sub(cpu.vregs[vy as usize]);
+                cpu.vregs[vx as usize] = sub_result;
+                cpu.vregs[VF] = (!not_underflow) as u8;
+            },
+            Opcode::Shr { vx, vy } => {
+                // The VIP shifts VY into VX, later interpreters shift VX in place
+                let source = if cpu.quirks.shift_uses_vy { vy } else { vx };
+                let value = cpu.vregs[source as usize];
+                cpu.vregs[vx as usize] = value.wrapping_shr(1);
+                cpu.vregs[VF] = value & 1;
+            },
+            Opcode::Subn { vx, vy } => {
+                let (sub_result, not_underflow) = cpu.vregs[vx as usize].overflowing_sub(cpu.vregs[vy as usize]);
+                cpu.vregs[vx as usize] = sub_result;
+                cpu.vregs[VF] = not_underflow as u8;
+            },
+            Opcode::Shl { vx, vy } => {
+                // The VIP shifts VY into VX, later interpreters shift VX in place
+                let source = if cpu.quirks.shift_uses_vy { vy } else { vx };
+                let value = cpu.vregs[source as usize];
+                cpu.vregs[vx as usize] = value.wrapping_shl(1);
+                cpu.vregs[VF] = value >> 7;
+            },
+            Opcode::LdI { addr } => {
+                cpu.i = addr;
+            },
+            Opcode::Rnd { vx, value } => {
+                let random_byte = machine.rng.next_byte();
+                cpu.vregs[vx as usize] = random_byte & value;
+            },
+            Opcode::LdDt { vx } => {
+                cpu.vregs[vx as usize] = cpu.dt;
+            },
+            Opcode::StoreDt { vx } => {
+                cpu.dt = cpu.vregs[vx as usize];
+            },
+            Opcode::StoreSt { vx } => {
+                cpu.st = cpu.vregs[vx as usize];
+            },
+            Opcode::AddI { vx } => {
+                cpu.i = cpu.i.wrapping_add(cpu.vregs[vx as usize] as u16);
+            },
+            Opcode::StoreSprite { vx } => {
+                let vx_val = cpu.vregs[vx as usize] & 0xF;
+                // Each sprite for a digit is 5 bytes , starting address 0x for 0
+                cpu.i = (vx_val as u16) * 5;
+            },
+            Opcode::StoreBcd { vx } => {
+                // Stores the hundreds digit into [I], tens digit into [I+1] and ones digit 
+                // into [I+3]
+                let mut value = cpu.vregs[vx as usize];
+                let ones = value % 10;
+                value /= 10;
+                let tens =  value % 10;
+                value /= 10;
+                let hundreds = value % 10;
+                cpu.check_mem_range(cpu.i as usize, 3)?;
+                cpu.store_byte_mem(cpu.i as usize, hundreds)?;
+                cpu.store_byte_mem(cpu.i as usize + 1, tens)?;
+                cpu.store_byte_mem(cpu.i as usize + 2, ones)?;
+            },
+            Opcode::StoreVxsIntoI { vx } => {
+                //println!("Loading regs V0 to V{:X} into I ({:X})", vx, cpu.i);
+                cpu.check_mem_range(cpu.i as usize, vx as usize + 1)?;
+                for i in 0..(vx + 1) {
+                    cpu.store_byte_mem(cpu.i as usize + i as usize, cpu.vregs[i as usize])?;
+                }
+                if cpu.quirks.load_store_increments_i {
+                    cpu.i = cpu.i.wrapping_add(vx as u16 + 1);
+                }
+            },
+            Opcode::LdVxsFromI { vx } => {
+                cpu.check_mem_range(cpu.i as usize, vx as usize + 1)?;
+                for i in 0..(vx + 1) {
+                    cpu.vregs[i as usize] = cpu.get_byte_mem(cpu.i as usize + i as usize)?;
+                }
+                if cpu.quirks.load_store_increments_i {
+                    cpu.i = cpu.i.wrapping_add(vx as u16 + 1);
+                }
+            },
+            Opcode::Exit => {
+                cpu.is_halted = true
+            },
+            Opcode::LdHf { vx } => {
+                let vx_val = cpu.vregs[vx as usize] & 0xF;
+                // Each large digit is 10 bytes, stored after the small font
+                cpu.i = BIG_FONT_OFFSET as u16 + (vx_val as u16) * 10;
+            },
+            Opcode::StoreRpl { vx } => {
+                for i in 0..=(vx as usize) {
+                    cpu.rpl[i] = cpu.vregs[i];
+                }
+            },
+            Opcode::LdRpl { vx } => {
+                for i in 0..=(vx as usize) {
+                    cpu.vregs[i] = cpu.rpl[i];
+                }
+            },
+            Opcode::LdILong { addr } => {
+                cpu.i = addr;
+            },
+            Opcode::StoreRange { vx, vy } => {
+                // Unlike FX55 this never changes I
+                let registers = register_range(vx, vy);
+                cpu.check_mem_range(cpu.i as usize, registers.len())?;
+                for (offset, reg) in registers.iter().enumerate() {
+                    cpu.store_byte_mem(cpu.i as usize + offset, cpu.vregs[*reg as usize])?;
+                }
+            },
+            Opcode::LdRange { vx, vy } => {
+                // Unlike FX65 this never changes I
+                let registers = register_range(vx, vy);
+                cpu.check_mem_range(cpu.i as usize, registers.len())?;
+                for (offset, reg) in registers.iter().enumerate() {
+                    cpu.vregs[*reg as usize] = cpu.get_byte_mem(cpu.i as usize + offset)?;
+                }
+            },
+            Opcode::Audio => {
+                cpu.check_mem_range(cpu.i as usize, AUDIO_PATTERN_SIZE)?;
+                for i in 0..AUDIO_PATTERN_SIZE {
+                    cpu.audio_pattern[i] = cpu.get_byte_mem(cpu.i as usize + i)?;
+                }
+            },
+            Opcode::Pitch { vx } => {
+                cpu.pitch = cpu.vregs[vx as usize];
+            },
+            Opcode::Drw { vx, vy, n } => machine.draw(vx, vy, n)?,
+            Opcode::Scd { n } => machine.update_display(|display| display.scroll_down(n)),
+            Opcode::Scu { n } => machine.update_display(|display| display.scroll_up(n)),
+            Opcode::Plane { planes } => machine.update_display(|display| display.select_planes(planes)),
+            Opcode::Scr => machine.update_display(|display| display.scroll_right()),
+            Opcode::Scl => machine.update_display(|display| display.scroll_left()),
+            Opcode::Low => machine.update_display(|display| display.set_hires(false)),
+            Opcode::High => machine.update_display(|display| display.set_hires(true)),
+            _ => {}
+        }
+        Ok(())
     }
-}
 
-impl fmt::Display for AddIInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
+    // Must be called after execute() to finish executing instruction including setting up for pc for next step
+    pub fn incr_pc(&self, cpu: &mut CPU) -> Result<(), ExecutionError> {
+        match *self {
+            Opcode::Ret => {
+                let return_addr = cpu.stack.pop();
+                match return_addr {
+                    Some(addr) => {
+                        cpu.pc = addr;
+                    },
+                    None => {
+                        return Err(ExecutionError::StackUnderflow { pc: cpu.pc });
+                    }
+                }
+            },
+            Opcode::Call { addr } | Opcode::Jp { addr } => {
+                cpu.pc = addr;
+            },
+            Opcode::Se { vx, val } => cpu.skip_if(cpu.vregs[vx as usize] == val),
+            Opcode::Sne { vx, val } => cpu.skip_if(cpu.vregs[vx as usize] != val),
+            Opcode::SeRegs { vx, vy } => cpu.skip_if(cpu.vregs[vx as usize] == cpu.vregs[vy as usize]),
+            Opcode::SneReg { vx, vy } => cpu.skip_if(cpu.vregs[vx as usize] != cpu.vregs[vy as usize]),
+            Opcode::JpV0 { addr } => {
+                // CHIP-48 and SCHIP read this as BXNN and add VX instead of V0
+                let vreg = if cpu.quirks.jump_uses_vx { (addr >> 8) & 0xF } else { 0 };
+                cpu.pc = cpu.vregs[vreg as usize] as u16 + addr;
+            },
+            Opcode::Skp { vx } => {
+                let pressed = cpu.is_key_pressed(cpu.vregs[vx as usize])?;
+                cpu.skip_if(pressed);
+            },
+            Opcode::Sknp { vx } => {
+                let pressed = cpu.is_key_pressed(cpu.vregs[vx as usize])?;
+                cpu.skip_if(!pressed);
+            },
+            Opcode::Exit => {
+                // EXIT stops the interpreter, leave pc on the instruction
+            },
+            Opcode::LdILong { .. } => {
+                cpu.pc = cpu.pc.wrapping_add(4);
+            },
+            _ => {
+                cpu.pc = cpu.pc.wrapping_add(2);
+            }
+        }
+        Ok(())
     }
-}
-
-struct StoreSpriteInstr {
-    vx: u8,
-}
 
-impl Instruction for StoreSpriteInstr {
-    fn print(&self) -> String {
-        format!("LD F, V{:X}", self.vx)
+    // Number of bytes the instruction takes up in memory
+    pub fn size(&self) -> u16 {
+        match *self {
+            Opcode::LdILong { .. } => 4,
+            _ => 2,
+        }
     }
 
-    fn do_instr(&self, cpu: &mut CPU) {
-        let vx_val = cpu.vregs[self.vx as usize];
-        // Each sprite for a digit is 5 bytes , starting address 0x for 0
-        cpu.i = (vx_val * 5) as u16;
+    // Instructions that _halt_ the cpu until a condition is met are kept by System and polled
+    // with check_completed() before pc moves on.
+    pub fn is_waited_instr(&self) -> bool {
+        matches!(*self, Opcode::LdKey { .. })
     }
 
-    fn as_any(&self) ->  &dyn Any {
-        self
+    pub fn check_completed(&self, machine: &mut Machine) -> bool {
+        match *self {
+            Opcode::LdKey { vx } => machine.cpu.poll_key_wait(vx),
+            _ => true,
+        }
     }
 }
 
-impl fmt::Display for StoreSpriteInstr {
+impl fmt::Display for Opcode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.print())
     }
 }
 
-struct StoreBcdInstr {
-    vx: u8,
-}
-
-impl Instruction for StoreBcdInstr {
-    fn print(&self) -> String {
-        format!("LD B, V{:X}", self.vx)
-    }
-
-    fn do_instr(&self,  cpu: &mut CPU) {
-        // Stores the hundreds digit into [I], tens digit into [I+1] and ones digit 
-        // into [I+3]
-        let mut value = cpu.vregs[self.vx as usize];
-        let ones = value % 10; 
-        value /= 10; 
-        let tens =  value % 10;
-        value /= 10;
-        let hundreds = value % 10;
-        cpu.store_byte_mem(cpu.i as usize, hundreds);
-        cpu.store_byte_mem((cpu.i + 1) as usize, tens);
-        cpu.store_byte_mem((cpu.i + 2) as usize, ones);
-    }
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-}
-
-impl fmt::Display for StoreBcdInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
+impl CPU {
+    pub fn new(mem: Rc<RefCell<Vec<u8>>>, quirks: Quirks) -> Self {
+        let memsize = mem.borrow().len();
+        CPU {
+            pc: 0x200,
+            vregs: [0; 16],
+            stack: vec![],
+            i: 0,
+            memory: mem,
+            curr_keys: [None; 16],
+            is_halted: false,
+            quirks,
+            rpl: [0; 16],
+            audio_pattern: [0; AUDIO_PATTERN_SIZE],
+            pitch: 64,
+            waiting_key: None,
+            watchpoints: vec![],
+            watch_hit: Cell::new(None),
+            dt: 0,
+            st: 0,
+            decode_cache: RefCell::new(vec![None; memsize]),
+            code_writes: Cell::new(None),
+        }
     }
-}
-
-struct StoreVxsIntoIInstr {
-    vx: u8,
-}
 
-impl Instruction for StoreVxsIntoIInstr{
-    fn print(&self) -> String {
-        format!("LD [I], V{:X}", self.vx)
+    // Fails unless len bytes starting at addr are all in memory. Instructions that touch several
+    // bytes check up front so they either run completely or not at all.
+    pub fn check_mem_range(&self, addr: usize, len: usize) -> Result<(), ExecutionError> {
+        let memsize = self.memory.borrow().len();
+        match addr.checked_add(len) {
+            _ if len == 0 => Ok(()),
+            Some(end) if end <= memsize => Ok(()),
+            _ => Err(ExecutionError::MemoryOutOfRange { pc: self.pc, addr: addr.max(memsize) }),
+        }
     }
 
-    fn do_instr(&self, cpu: &mut CPU) {
-        println!("Loading regs V0 to V{:X} into I ({:X})", self.vx, cpu.i);
-        for i in 0..(self.vx + 1) {
-            cpu.store_byte_mem((cpu.i + (i as u16)) as usize, cpu.vregs[i as usize]);
+    pub fn get_byte_mem(&self, addr: usize) -> Result<u8, ExecutionError> {
+        let value = self.read_byte(addr)?;
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(addr, value, false);
         }
+        Ok(value)
     }
 
-    fn as_any(&self) ->  &dyn Any {
-        self
+    // Reads a byte without going through the watchpoints, for instruction fetches
+    fn read_byte(&self, addr: usize) -> Result<u8, ExecutionError> {
+        match self.memory.borrow().get(addr) {
+            Some(value) => Ok(*value),
+            None => Err(ExecutionError::MemoryOutOfRange { pc: self.pc, addr }),
+        }
     }
-}
 
-impl fmt::Display for StoreVxsIntoIInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
+    pub fn store_byte_mem(&self, addr: usize, value: u8) -> Result<(), ExecutionError> {
+        match self.memory.borrow_mut().get_mut(addr) {
+            Some(byte) => *byte = value,
+            None => return Err(ExecutionError::MemoryOutOfRange { pc: self.pc, addr }),
+        }
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(addr, value, true);
+        }
+        // The byte can be part of the instruction starting at addr or at any of the 3 bytes
+        // before it, F000 NNNN being 4 bytes long.
+        let mut decode_cache = self.decode_cache.borrow_mut();
+        let mut overwrote_code = false;
+        for entry in decode_cache.iter_mut().take(addr + 1).skip(addr.saturating_sub(3)) {
+            overwrote_code |= entry.take().is_some();
+        }
+        if overwrote_code {
+            self.record_code_write(addr.saturating_sub(3), addr);
+        }
+        Ok(())
+    }
+
+    // Polls FX0A. The first key pressed is remembered and stored in VX once it is released, like
+    // the COSMAC VIP, or straight away with the key_wait_completes_on_press quirk.
+    pub fn poll_key_wait(&mut self, vx: u8) -> bool {
+        let key = match self.waiting_key {
+            Some(key) => key,
+            None => match self.curr_keys.iter().position(|state| state.is_some()) {
+                Some(key) => {
+                    self.waiting_key = Some(key as u8);
+                    key as u8
+                },
+                None => return false,
+            },
+        };
+        if self.quirks.key_wait_completes_on_press || self.curr_keys[key as usize].is_none() {
+            self.vregs[vx as usize] = key;
+            self.waiting_key = None;
+            return true;
+        }
+        false
     }
-}
 
-struct LdVxsFromIInstr {
-    vx: u8,
-}
-
-impl Instruction for LdVxsFromIInstr{
-    fn print(&self) -> String {
-        format!("LD V{:X}, [I]", self.vx)
+    // Whether key is held down. Only VX values 0 to F name a key.
+    pub fn is_key_pressed(&self, key: u8) -> Result<bool, ExecutionError> {
+        match self.curr_keys.get(key as usize) {
+            Some(state) => Ok(state.is_some()),
+            None => Err(ExecutionError::InvalidKey { pc: self.pc, key }),
+        }
     }
 
-    fn do_instr(&self, cpu: &mut CPU) {
-        for i in 0..(self.vx + 1) {
-            cpu.vregs[i as usize] = cpu.get_byte_mem((cpu.i + (i as u16)) as usize);
+    pub fn clear_decode_cache(&self) {
+        let mut decode_cache = self.decode_cache.borrow_mut();
+        for entry in decode_cache.iter_mut() {
+            *entry = None;
         }
+        self.record_code_write(0, decode_cache.len());
     }
-    fn as_any(&self) ->  &dyn Any {
-        self
-    }
-}
 
-impl fmt::Display for LdVxsFromIInstr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
+    fn record_code_write(&self, start: usize, end: usize) {
+        let range = match self.code_writes.get() {
+            Some((old_start, old_end)) => (old_start.min(start), old_end.max(end)),
+            None => (start, end),
+        };
+        self.code_writes.set(Some(range));
     }
-}
 
-impl CPU {
-    pub fn new(mem: Rc<RefCell<Vec<u8>>>) -> Self {
-        CPU {
-            pc: 0x200,
-            vregs: [0; 16],
-            stack: vec![],
-            i: 0,
-            memory: mem,
-            curr_keys: [None; 16],
-            is_halted: false,
-            dt: 255,
-            st: 255
-        }
+    // Returns and resets the inclusive address range of previously decoded instructions that
+    // have been written to, so translated code built from them can be thrown away.
+    pub fn take_code_writes(&self) -> Option<(usize, usize)> {
+        self.code_writes.take()
     }
 
-    pub fn get_byte_mem(&self, addr: usize) -> u8 {
-        self.memory.borrow()[addr]
+    // Remembers the access if it is the first to hit a watchpoint
+    fn check_watchpoints(&self, addr: usize, value: u8, write: bool) {
+        let hit = self.watchpoints.iter().any(|watch| {
+            addr >= watch.start && addr <= watch.end && if write { watch.on_write } else { watch.on_read }
+        });
+        if hit && self.watch_hit.get().is_none() {
+            self.watch_hit.set(Some(WatchHit { pc: self.pc, addr, value, write }));
+        }
     }
 
-    pub fn store_byte_mem(&self, addr: usize, value: u8)  {
-        self.memory.borrow_mut()[addr] = value;
+    // Returns and resets the first access that hit a watchpoint
+    pub fn take_watch_hit(&self) -> Option<WatchHit> {
+        self.watch_hit.take()
     }
 
-    pub fn fetch_instr_from_addr(&self, addr: usize) -> u16 {
-        let instr = u16::from(self.memory.borrow()[addr]) << 8 | 
-                    u16::from(self.memory.borrow()[addr+1]);
-        instr.into()
+    pub fn fetch_instr_from_addr(&self, addr: usize) -> Result<u16, ExecutionError> {
+        let instr = u16::from(self.read_byte(addr)?) << 8 | 
+                    u16::from(self.read_byte(addr + 1)?);
+        Ok(instr)
     }
 
-    pub fn decode_instr(instr: u16) -> Result<Box<dyn Instruction>, DecodeError> {
+    pub fn decode_instr(instr: u16) -> Result<Opcode, DecodeError> {
         let bits15_12 : u8 = (instr >> 12) as u8;
         let bits11_8 : u8 = ((instr >> 8) & 0xF) as u8;
         let bits7_4 : u8 = ((instr >> 4) & 0xF) as u8;
         let bits3_0 : u8 = ((instr) & 0xF) as u8;
-        let nnn : u16 = ((u16::from(bits11_8) << 8) | (u16::from(bits7_4) << 4) | u16::from(bits3_0)) as u16; 
-        let result : Result<Box<dyn Instruction>, DecodeError>;
+        let nnn : u16 = (u16::from(bits11_8) << 8) | (u16::from(bits7_4) << 4) | u16::from(bits3_0);
+        let result : Result<Opcode, DecodeError>;
         match bits15_12 {
             // CLR
             // RET
+            // SUPER-CHIP scrolling, EXIT, LOW and HIGH
             0x0 => {
                 if bits11_8  == 0 {
                     if bits7_4 == 0xE {
                         if bits3_0 == 0 {
-                            result = Ok(Box::new(ClrInstr{}))   
+                            result = Ok(Opcode::Clr)   
                         } else if bits3_0 == 0xE {
-                            result = Ok(Box::new(RetInstr{})) 
+                            result = Ok(Opcode::Ret) 
                         } else {
                             result = Err(DecodeError::GenericError)
                         }
+                    } else if bits7_4 == 0xC {
+                        result = Ok(Opcode::Scd { n: bits3_0 })
+                    } else if bits7_4 == 0xD {
+                        result = Ok(Opcode::Scu { n: bits3_0 })
+                    } else if bits7_4 == 0xF {
+                        match bits3_0 {
+                            0xB => result = Ok(Opcode::Scr),
+                            0xC => result = Ok(Opcode::Scl),
+                            0xD => result = Ok(Opcode::Exit),
+                            0xE => result = Ok(Opcode::Low),
+                            0xF => result = Ok(Opcode::High),
+                            _ => result = Err(DecodeError::GenericError)
+                        }
                     } else {
                         result = Err(DecodeError::GenericError) 
                     }                   
                 } else {
-                    result = Ok(Box::new(SysInstr{ addr: nnn })) 
+                    result = Ok(Opcode::Sys { addr: nnn }) 
                 }
             },
             // JP NNN
             0x1 => {
-                result =  Ok(Box::new(JpInstr { addr: nnn})) 
+                result =  Ok(Opcode::Jp { addr: nnn }) 
             },
             // CALL NNN
             0x2 => {
-                result = Ok(Box::new(CallInstr { addr: nnn }))
+                result = Ok(Opcode::Call { addr: nnn })
             },
 
             // SE Vx, kk --- Skip next instruction if Vx == kk
             0x3 => {
                 let byte = bits7_4 << 4 | bits3_0;
-                result = Ok(Box::new(SeInstr { vx: bits11_8, val: byte }))
+                result = Ok(Opcode::Se { vx: bits11_8, val: byte })
             },
 
             // SNE Vx, kk --- Skip next instruction if Vx != kk
             0x4 => {
                 let byte = bits7_4 << 4 | bits3_0;
-                result = Ok(Box::new(SneInstr { vx: bits11_8, val: byte }))
+                result = Ok(Opcode::Sne { vx: bits11_8, val: byte })
             },
 
             0x5 => {
                 if bits3_0 == 0 {
-                    result = Ok(Box::new(SeRegsInstr { vx: bits11_8, vy: bits7_4}))
+                    result = Ok(Opcode::SeRegs { vx: bits11_8, vy: bits7_4 })
+                } else if bits3_0 == 2 {
+                    result = Ok(Opcode::StoreRange { vx: bits11_8, vy: bits7_4 })
+                } else if bits3_0 == 3 {
+                    result = Ok(Opcode::LdRange { vx: bits11_8, vy: bits7_4 })
                 } else {
                     let unsupported_5xxx = format!("unsupported 5 instr:{:x}", instr);
                     result = Err(DecodeError::GenericErrorEx(unsupported_5xxx,))
@@ -1170,41 +763,41 @@ impl CPU {
             // LD Vx, KK
             0x6 => {
                 let byte = bits7_4 << 4 | bits3_0;
-                result = Ok(Box::new(LdValInstr{ vx: bits11_8, value: byte}))
+                result = Ok(Opcode::LdVal { vx: bits11_8, value: byte })
             },
             0x7 => {
                 let byte = bits7_4 << 4 | bits3_0;
-                result = Ok(Box::new(AddImmediateInstr{ vx: bits11_8, value: byte}))
+                result = Ok(Opcode::AddImmediate { vx: bits11_8, value: byte })
             },
             // LD Vx,Vy and ALU instructions
             0x8 => {
                 match bits3_0 {
                     0x0 => {
-                        result = Ok(Box::new(LdRegInstr{ vx: bits11_8, vy: bits7_4}))
+                        result = Ok(Opcode::LdReg { vx: bits11_8, vy: bits7_4 })
                     },
                     0x1 => {
-                        result = Ok(Box::new(OrInstr{ vx: bits11_8, vy: bits7_4}))
+                        result = Ok(Opcode::Or { vx: bits11_8, vy: bits7_4 })
                     },
                     0x2 => {
-                        result = Ok(Box::new(AndInstr{ vx: bits11_8, vy: bits7_4}))
+                        result = Ok(Opcode::And { vx: bits11_8, vy: bits7_4 })
                     },
                     0x3 => {
-                        result = Ok(Box::new(XorInstr{ vx: bits11_8, vy: bits7_4}))
+                        result = Ok(Opcode::Xor { vx: bits11_8, vy: bits7_4 })
                     },
                     0x4 => {
-                        result = Ok(Box::new(AddInstr{ vx: bits11_8, vy: bits7_4}))
+                        result = Ok(Opcode::Add { vx: bits11_8, vy: bits7_4 })
                     },
                     0x5 => {
-                        result = Ok(Box::new(SubInstr{ vx: bits11_8, vy: bits7_4}))
+                        result = Ok(Opcode::Sub { vx: bits11_8, vy: bits7_4 })
                     },
                     0x6 => {
-                        result = Ok(Box::new(ShrInstr{ vx: bits11_8, vy: bits7_4}))
+                        result = Ok(Opcode::Shr { vx: bits11_8, vy: bits7_4 })
                     },
                     0x7 => {
-                        result = Ok(Box::new(SubnInstr{ vx: bits11_8, vy: bits7_4}))
+                        result = Ok(Opcode::Subn { vx: bits11_8, vy: bits7_4 })
                     },
                     0xE => {
-                        result = Ok(Box::new(ShlInstr{ vx: bits11_8, vy: bits7_4}))
+                        result = Ok(Opcode::Shl { vx: bits11_8, vy: bits7_4 })
                     },
                     _ => {
                         let unsupported = format!("unsupported {:x}", instr);
@@ -1213,29 +806,29 @@ impl CPU {
                 }
             },
             0x9 => {
-                result = Ok(Box::new(SneRegInstr{ vx: bits11_8, vy: bits7_4}))
+                result = Ok(Opcode::SneReg { vx: bits11_8, vy: bits7_4 })
             },
             0xA => {
-                result = Ok(Box::new(LdIInstr{ addr: nnn }))
+                result = Ok(Opcode::LdI { addr: nnn })
             },
             0xB => {
-                result = Ok(Box::new(JpV0Instr{ addr: nnn }))
+                result = Ok(Opcode::JpV0 { addr: nnn })
             }
             0xC => {
-                let byte : u8 = (bits7_4 as u8) << 4 | bits3_0; 
-                result = Ok(Box::new(RndInstr{ vx: bits11_8, value: byte }))
+                let byte : u8 = bits7_4 << 4 | bits3_0; 
+                result = Ok(Opcode::Rnd { vx: bits11_8, value: byte })
             }
             0xD => {
-                result = Ok(Box::new(DrwInstr{ vx: bits11_8, vy: bits7_4, n: bits3_0}))
+                result = Ok(Opcode::Drw { vx: bits11_8, vy: bits7_4, n: bits3_0 })
             },
             0xE => {
                 let lsbyte = bits7_4 << 4 | bits3_0;
                 match lsbyte {
                     0x9E => {
-                        result = Ok(Box::new(SkpInstr{ vx: bits11_8 }))
+                        result = Ok(Opcode::Skp { vx: bits11_8 })
                     },
                     0xA1 => {
-                        result = Ok(Box::new(SknpInstr{ vx: bits11_8 }))
+                        result = Ok(Opcode::Sknp { vx: bits11_8 })
                     },
                     _ => {
                         let unsupported_e = format!("unsupported E instr {}", instr);
@@ -1248,32 +841,54 @@ impl CPU {
             0xF => {
                 let f_opcode = bits7_4 << 4 | bits3_0;  
                 match f_opcode {
+                    0x00 if bits11_8 == 0 => {
+                        let unsupported_f = "F000 NNNN must be decoded together with its address, see fetch_instr_from_pc".to_string();
+                        result = Err(DecodeError::GenericErrorEx(unsupported_f))
+                    },
+                    0x01 => {
+                        result = Ok(Opcode::Plane { planes: bits11_8 })
+                    },
+                    0x02 if bits11_8 == 0 => {
+                        result = Ok(Opcode::Audio)
+                    },
                     0x07 => {
-                        result = Ok(Box::new(LdDtInstr{ vx: bits11_8 }))
+                        result = Ok(Opcode::LdDt { vx: bits11_8 })
                     },
                     0x0A => {
-                        result = Ok(Box::new(LdKeyInstr{ vx: bits11_8 }))
+                        result = Ok(Opcode::LdKey { vx: bits11_8 })
                     },
                     0x15 => {
-                        result = Ok(Box::new(StoreDtInstr{ vx: bits11_8 }))
+                        result = Ok(Opcode::StoreDt { vx: bits11_8 })
                     },
                     0x18 => {
-                        result = Ok(Box::new(StoreStInstr{ vx: bits11_8 }))
+                        result = Ok(Opcode::StoreSt { vx: bits11_8 })
                     },
                     0x1E => {
-                        result = Ok(Box::new(AddIInstr{ vx: bits11_8 }))
+                        result = Ok(Opcode::AddI { vx: bits11_8 })
                     },
                     0x29 => {
-                        result = Ok(Box::new(StoreSpriteInstr{ vx: bits11_8 }))
+                        result = Ok(Opcode::StoreSprite { vx: bits11_8 })
+                    },
+                    0x30 => {
+                        result = Ok(Opcode::LdHf { vx: bits11_8 })
+                    },
+                    0x3A => {
+                        result = Ok(Opcode::Pitch { vx: bits11_8 })
                     },
                     0x33 => {
-                        result = Ok(Box::new(StoreBcdInstr{ vx: bits11_8}))
+                        result = Ok(Opcode::StoreBcd { vx: bits11_8 })
                     },
                     0x55 => {
-                        result = Ok(Box::new(StoreVxsIntoIInstr{ vx: bits11_8}))
+                        result = Ok(Opcode::StoreVxsIntoI { vx: bits11_8 })
                     },
                     0x65 => {
-                        result = Ok(Box::new(LdVxsFromIInstr{ vx: bits11_8}))
+                        result = Ok(Opcode::LdVxsFromI { vx: bits11_8 })
+                    },
+                    0x75 => {
+                        result = Ok(Opcode::StoreRpl { vx: bits11_8 })
+                    },
+                    0x85 => {
+                        result = Ok(Opcode::LdRpl { vx: bits11_8 })
                     },
                     _ => {
                         let unsupported_f = format!("unsupported F instr: {}", instr);
@@ -1292,9 +907,161 @@ impl CPU {
     }
 
 
-    pub fn fetch_instr_from_pc(&self) -> Result<Box<dyn Instruction>, DecodeError> {
-        let instr = self.fetch_instr_from_addr(self.pc as usize);
-        let decoded_instr = CPU::decode_instr(instr);
-        decoded_instr
+    pub fn fetch_instr_from_pc(&self) -> Result<Opcode, ExecutionError> {
+        self.decode_at(self.pc as usize)
+    }
+
+    // Decodes the instruction starting at addr, going through the decode cache
+    pub fn decode_at(&self, addr: usize) -> Result<Opcode, ExecutionError> {
+        if let Some(Some(instr)) = self.decode_cache.borrow().get(addr) {
+            return Ok(*instr);
+        }
+        let instr = self.fetch_instr_from_addr(addr)?;
+        let decoded = if instr == LD_I_LONG_OPCODE {
+            let long_addr = self.fetch_instr_from_addr(addr + 2)?;
+            Opcode::LdILong { addr: long_addr }
+        } else {
+            CPU::decode_instr(instr)
+                .map_err(|_| ExecutionError::InvalidOpcode { pc: addr as u16, opcode: instr })?
+        };
+        if let Some(entry) = self.decode_cache.borrow_mut().get_mut(addr) {
+            *entry = Some(decoded);
+        }
+        Ok(decoded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::quirks::Quirks;
+    use crate::rom::ROM;
+    use crate::cpu::{CPU, Opcode};
+    use crate::system::{System, BIG_FONT_OFFSET, ROM_OFFSET, XO_CHIP_MEMSIZE};
+
+    // Loads program into a system with the given quirks and steps through it once
+    fn run(quirks: Quirks, program: &[u16]) -> System {
+        let mut system = System::with_quirks(quirks);
+        system.load_rom(&ROM::new(program.iter().flat_map(|word| word.to_be_bytes().to_vec()).collect())).unwrap();
+        for _ in 0..program.len() {
+            system.step().unwrap();
+        }
+        system
+    }
+
+    // Like run, with the 64 KiB of XO-CHIP memory
+    fn run_xo_chip(program: &[u16], steps: usize) -> System {
+        let mut system = System::with_memory_size(Quirks::xo_chip(), XO_CHIP_MEMSIZE);
+        system.load_rom(&ROM::new(program.iter().flat_map(|word| word.to_be_bytes().to_vec()).collect())).unwrap();
+        for _ in 0..steps {
+            system.step().unwrap();
+        }
+        system
+    }
+
+    #[test]
+    fn xo_chip_long_load() {
+        let system = run_xo_chip(&[0xF000, 0xFEDC, 0x6105], 2);
+        assert_eq!(system.cpu.decode_at(ROM_OFFSET), Ok(Opcode::LdILong { addr: 0xFEDC }));
+        assert_eq!(Opcode::LdILong { addr: 0xFEDC }.size(), 4);
+        assert_eq!((system.cpu.i, system.cpu.pc, system.cpu.vregs[1]), (0xFEDC, 0x206, 5));
+
+        // SE V0, 0 skips all 4 bytes of it, so do SNE and SKNP
+        for skip in [0x3000, 0x4001, 0xE0A1].iter() {
+            let system = run_xo_chip(&[*skip, 0xF000, 0xFEDC, 0x6105], 2);
+            assert_eq!((system.cpu.i, system.cpu.pc, system.cpu.vregs[1]), (0, 0x208, 5));
+        }
+    }
+
+    #[test]
+    fn xo_chip_register_ranges() {
+        // LD V1, 1; LD V2, 2; LD V3, 3; LD I, 0x300; SAVE V3 - V1
+        let system = run_xo_chip(&[0x6101, 0x6202, 0x6303, 0xA300, 0x5312], 5);
+        assert_eq!(&system.cpu.memory.borrow()[0x300..0x303], &[3, 2, 1]);
+        assert_eq!(system.cpu.i, 0x300);
+
+        // LD I, 0x206; LOAD V3 - V1; JP 0x204; then the bytes 7, 8, 9
+        let system = run_xo_chip(&[0xA206, 0x5313, 0x1204, 0x0708, 0x0900], 2);
+        assert_eq!(&system.cpu.vregs[1..4], &[9, 8, 7]);
+        assert_eq!(system.cpu.i, 0x206);
+    }
+
+    #[test]
+    fn xo_chip_planes() {
+        // PLANE 3; LD I, 0x208; DRW V0, V0, 1; JP 0x206; then one row for each plane
+        let mut system = run_xo_chip(&[0xF301, 0xA208, 0xD001, 0x1206, 0xC080], 3);
+        assert_eq!(system.display.planes, 3);
+        assert_eq!(&system.display.mem[0][..3], &[3, 1, 0]);
+        assert_eq!(system.cpu.vregs[0xF], 0);
+
+        // PLANE 2; CLS only clears plane 2
+        system.cpu.memory.borrow_mut()[0x206..0x20A].copy_from_slice(&[0xF2, 0x01, 0x00, 0xE0]);
+        system.cpu.clear_decode_cache();
+        system.step().unwrap();
+        system.step().unwrap();
+        assert_eq!(&system.display.mem[0][..3], &[1, 1, 0]);
+    }
+
+    #[test]
+    fn schip_resolution_and_scrolling() {
+        // HIGH; LD I, 0 (the font's 0); LD V0, 4; DRW V0, V0, 5; SCD 3; SCR. The top row of the
+        // 0 is 4 pixels wide and ends up 3 rows down and 4 columns right.
+        let system = run(Quirks::schip(), &[0x00FF, 0xA000, 0x6004, 0xD005, 0x00C3, 0x00FB]);
+        assert!(system.display.hires);
+        assert_eq!(&system.display.mem[7][7..13], &[0, 1, 1, 1, 1, 0]);
+        assert_eq!(&system.display.mem[4][4..8], &[0, 0, 0, 0]);
+        // LOW clears the screen
+        let system = run(Quirks::schip(), &[0x00FF, 0xA000, 0xD005, 0x00FE]);
+        assert!(!system.display.hires);
+        assert!(system.display.mem.iter().all(|row| row.iter().all(|pixel| *pixel == 0)));
+    }
+
+    #[test]
+    fn schip_large_sprite() {
+        // HIGH; LD V0, 9; LD HF, V0; DRW V1, V1, 0; DRW V1, V1, 0
+        let mut system = run(Quirks::schip(), &[0x00FF, 0x6009, 0xF030, 0xD110]);
+        assert_eq!(system.cpu.i as usize, BIG_FONT_OFFSET + 9 * 10);
+        assert_eq!(system.cpu.vregs[0xF], 0);
+        let drawn: usize = system.display.mem.iter().map(|row| row.iter().filter(|pixel| **pixel != 0).count()).sum();
+        assert!(drawn > 0);
+        // Drawing it again erases it and reports the collision
+        system.cpu.pc -= 2;
+        system.step().unwrap();
+        assert_eq!(system.cpu.vregs[0xF], 1);
+        assert!(system.display.mem.iter().all(|row| row.iter().all(|pixel| *pixel == 0)));
+    }
+
+    #[test]
+    fn schip_rpl_flags() {
+        // LD V0, 1; LD V1, 2; LD V2, 3; LD R, V1; LD V0, 0; LD V1, 0; LD V2, 0; LD V2, R
+        let system = run(Quirks::schip(), &[0x6001, 0x6102, 0x6203, 0xF175, 0x6000, 0x6100, 0x6200, 0xF285]);
+        assert_eq!(&system.cpu.rpl[..3], &[1, 2, 0]);
+        assert_eq!(&system.cpu.vregs[..3], &[1, 2, 0]);
+    }
+
+    #[test]
+    fn print_mnemonics() {
+        // The same text the per-instruction trait objects printed before Opcode replaced them
+        let expected = [
+            (0x00E0, "CLR"), (0x00EE, "RET"), (0x0123, "SYS"), (0x1ABC, "JP abc"),
+            (0x2ABC, "CALL abc "), (0x3A1F, "SE Va, 1f"), (0x4B20, "SNE Vb, 20"),
+            (0x5120, "SE V1, V2"), (0x6A0F, "LD VA, f"), (0x7C01, "ADD VC, 1"),
+            (0x8AB0, "LD VA, VB"), (0x8121, "OR V1, V2"), (0x8122, "AND V1, V2"),
+            (0x8123, "XOR V1, V2"), (0x8124, "ADD V1, V2"), (0x8125, "SUB V1, V2"),
+            (0x8126, "SHR V1, V2"), (0x8127, "SUBN V1, V2"), (0x812E, "SHL V1, V2"),
+            (0x9120, "SNE V1, V2"), (0xA2F0, "LD I, 2F0"), (0xB2F0, "JP V0, 0x2F0"),
+            (0xC3FF, "RND V3, 0xFF"), (0xD12A, "DRW V1, V2, a "), (0xE59E, "SKP V5"),
+            (0xE5A1, "SKNP V5"), (0xF507, "LD V5, DT"), (0xF50A, "LD V5, K"),
+            (0xF515, "LD DT, V5"), (0xF518, "LD ST, V5"), (0xF51E, "ADD I,  V5"),
+            (0xF529, "LD F, V5"), (0xF533, "LD B, V5"), (0xF555, "LD [I], V5"),
+            (0xF565, "LD V5, [I]"), (0x00CA, "SCD a"), (0x00FB, "SCR"), (0x00FC, "SCL"),
+            (0x00FD, "EXIT"), (0x00FE, "LOW"), (0x00FF, "HIGH"), (0xF530, "LD HF, V5"),
+            (0xF575, "LD R, V5"), (0xF585, "LD V5, R"), (0x5312, "SAVE V3 - V1"),
+            (0x5313, "LOAD V3 - V1"), (0x00DA, "SCU a"), (0xF301, "PLANE 3"),
+            (0xF002, "AUDIO"), (0xF53A, "PITCH V5"),
+        ];
+        for (word, mnemonic) in expected.iter() {
+            assert_eq!(CPU::decode_instr(*word).unwrap().print(), *mnemonic, "{:04X}", word);
+        }
+        assert_eq!(Opcode::LdILong { addr: 0xFEDC }.print(), "LD I, LONG FEDC");
     }
 }
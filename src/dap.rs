@@ -0,0 +1,807 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use crate::debugger::{self, ConditionalBreakpoint, Condition, Debugger, Operand, Reply, StopReason};
+use crate::json::Json;
+use crate::quirks::Quirks;
+use crate::rom::read_rom;
+use crate::system::{self, ErrorPolicy, System, ROM_OFFSET};
+use crate::timing::Timing;
+
+// Instructions run between checks for requests while the program runs
+const CONTINUE_CHUNK: u64 = 10_000;
+// The program has a single thread
+const THREAD_ID: i64 = 1;
+// sourceReference of the disassembly listing of the ROM, one line per 2 byte word from ROM_OFFSET
+const LISTING_REFERENCE: i64 = 1;
+// variablesReference of the scopes
+const REGISTERS_REFERENCE: i64 = 1;
+const STACK_REFERENCE: i64 = 2;
+// Messages with a larger body are skipped unread
+const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+const BASE64_DIGITS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Serves one debug adapter protocol session on stdin and stdout
+pub fn serve_stdio() -> io::Result<()> {
+    DapServer::new(io::stdin(), io::stdout()).run()
+}
+
+// Serves one session on addr, which should be a local address such as 127.0.0.1:4711
+pub fn serve<A: ToSocketAddrs>(addr: A) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    DapServer::new(stream.try_clone()?, stream).run()
+}
+
+// Debug adapter protocol server for one client. The launch request loads the ROM into a new
+// System, breakpoints and stepping go through Debugger as in the --debug prompt and the gdb
+// stub. Requests are read on their own thread, so a running program can be paused.
+//
+// Without a source map, breakpoints are set on the disassembly listing the source request
+// returns, where line N is the word at ROM_OFFSET + 2 * (N - 1), or as instruction breakpoints.
+pub struct DapServer<W: Write> {
+    requests: Receiver<Json>,
+    output: W,
+    seq: i64,
+    debugger: Debugger,
+    system: Option<System>,
+    program: String,
+    rom_size: usize,
+    stop_on_entry: bool,
+    configured: bool,                           // configurationDone was received
+    running: bool,
+    resumed: bool,                              // The next chunk starts on the instruction a stop was at
+    source_breakpoints: Vec<ConditionalBreakpoint>,
+    instruction_breakpoints: Vec<ConditionalBreakpoint>,
+    applied: Vec<ConditionalBreakpoint>,        // Breakpoints of both kinds given to debugger
+    events: Vec<Json>,                          // Sent after the response to the current request
+}
+
+impl<W: Write> DapServer<W> {
+    pub fn new<R: Read + Send + 'static>(input: R, output: W) -> DapServer<W> {
+        let (sender, requests) = mpsc::channel();
+        thread::spawn(move || {
+            let mut input = BufReader::new(input);
+            while let Ok(Some(message)) = read_message(&mut input) {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+        DapServer {
+            requests,
+            output,
+            seq: 1,
+            debugger: Debugger::new(),
+            system: None,
+            program: String::new(),
+            rom_size: 0,
+            stop_on_entry: false,
+            configured: false,
+            running: false,
+            resumed: false,
+            source_breakpoints: vec![],
+            instruction_breakpoints: vec![],
+            applied: vec![],
+            events: vec![],
+        }
+    }
+
+    // Answers requests and runs the program until the client disconnects
+    pub fn run(&mut self) -> io::Result<()> {
+        loop {
+            let message = if self.running {
+                match self.requests.try_recv() {
+                    Ok(message) => Some(message),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            } else {
+                match self.requests.recv() {
+                    Ok(message) => Some(message),
+                    Err(_) => return Ok(()),
+                }
+            };
+            let more = match message {
+                Some(ref request) if request.get("type").as_str() == Some("request") => self.request(request)?,
+                Some(_) => true,
+                None => {
+                    self.run_chunk();
+                    true
+                },
+            };
+            for event in std::mem::take(&mut self.events) {
+                self.send(event)?;
+            }
+            if !more {
+                return Ok(());
+            }
+        }
+    }
+
+    // Handles a request and sends the response. Returns false once the session is over.
+    fn request(&mut self, request: &Json) -> io::Result<bool> {
+        let command = request.get("command").as_str().unwrap_or("");
+        let args = request.get("arguments");
+        let result = match command {
+            "initialize" => {
+                self.event("initialized", Json::Null);
+                Ok(capabilities())
+            },
+            "launch" => self.launch(args),
+            "configurationDone" => {
+                self.configured = true;
+                self.start();
+                Ok(Json::Null)
+            },
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(args),
+            "setExceptionBreakpoints" => Ok(Json::object(vec![("breakpoints", Json::Array(vec![]))])),
+            "threads" => Ok(Json::object(vec![
+                ("threads", vec![Json::object(vec![("id", THREAD_ID.into()), ("name", "CHIP-8".into())])].into()),
+            ])),
+            "stackTrace" => self.stack_trace(args),
+            "scopes" => Ok(Json::object(vec![("scopes", vec![
+                Json::object(vec![
+                    ("name", "Registers".into()),
+                    ("presentationHint", "registers".into()),
+                    ("variablesReference", REGISTERS_REFERENCE.into()),
+                    ("expensive", false.into()),
+                ]),
+                Json::object(vec![
+                    ("name", "Stack".into()),
+                    ("variablesReference", STACK_REFERENCE.into()),
+                    ("expensive", false.into()),
+                ]),
+            ].into())])),
+            "variables" => self.variables(args),
+            "source" => self.source(args),
+            "readMemory" => self.read_memory(args),
+            "disassemble" => self.disassemble(args),
+            "evaluate" => self.evaluate(args),
+            "continue" => match self.system {
+                Some(_) => {
+                    self.debugger.cancel_step();
+                    self.running = true;
+                    self.resumed = true;
+                    Ok(Json::object(vec![("allThreadsContinued", true.into())]))
+                },
+                None => Err("no program is running".to_string()),
+            },
+            "pause" => {
+                if self.running {
+                    self.running = false;
+                    self.debugger.cancel_step();
+                    self.stopped("pause", "paused".to_string());
+                }
+                Ok(Json::Null)
+            },
+            "next" | "stepIn" | "stepOut" => self.step(command),
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(Json::Null))?;
+                return Ok(false);
+            },
+            _ => Err(format!("unsupported request {}", command)),
+        };
+        self.respond(request, result)?;
+        Ok(true)
+    }
+
+    // Creates the system and loads the ROM given as program. The quirks preset, seed, timing and
+    // error policy can be given as in the headless runner's options.
+    fn launch(&mut self, args: &Json) -> Result<Json, String> {
+        let program = args.get("program").as_str().ok_or("launch needs the path of the ROM in program")?;
+        let rom = read_rom(Path::new(program)).map_err(|err| format!("could not load {}: {}", program, err))?;
+        let preset = args.get("quirks").as_str().unwrap_or("vip");
+        let quirks = Quirks::from_preset_name(preset).ok_or(format!("unknown quirks preset {}", preset))?;
+        let memsize = if preset == "xochip" { system::XO_CHIP_MEMSIZE } else { system::MEMSIZE };
+        let mut system = System::with_memory_size(quirks, memsize);
+        if let Some(seed) = args.get("seed").as_i64() {
+            system.set_seed(seed as u64);
+        }
+        if let Some(name) = args.get("timing").as_str() {
+            system.timing = Timing::from_name(name).ok_or(format!("unknown timing {}", name))?;
+        }
+        if let Some(name) = args.get("onError").as_str() {
+            system.error_policy = ErrorPolicy::from_name(name).ok_or(format!("unknown error policy {}", name))?;
+        }
+        system.load_rom(&rom).map_err(|err| format!("could not load {}: {}", program, err))?;
+        self.system = Some(system);
+        self.program = program.to_string();
+        self.rom_size = rom.size();
+        self.stop_on_entry = args.get("stopOnEntry").as_bool().unwrap_or(false);
+        self.start();
+        Ok(Json::Null)
+    }
+
+    // Starts the program once it is launched and the client has set its breakpoints
+    fn start(&mut self) {
+        if self.system.is_none() || !self.configured {
+            return;
+        }
+        if self.stop_on_entry {
+            self.stopped("entry", "entry".to_string());
+        } else {
+            self.running = true;
+        }
+    }
+
+    // Runs part of a continue, or of a next or step out whose subroutine has not returned yet,
+    // stopping at breakpoints and when the CPU stops
+    fn run_chunk(&mut self) {
+        let system = match self.system.as_mut() {
+            Some(system) => system,
+            None => {
+                self.running = false;
+                return;
+            },
+        };
+        if self.debugger.is_stepping() {
+            self.resumed = false;
+            match self.debugger.finish_step(system, CONTINUE_CHUNK) {
+                StopReason::Running => return,
+                reason => {
+                    self.running = false;
+                    self.stop(reason);
+                    return;
+                },
+            }
+        }
+        let mut reason = None;
+        if !self.resumed {
+            reason = self.debugger.check_breakpoints(&system.cpu);
+        }
+        self.resumed = false;
+        let reason = match reason {
+            Some(reason) => reason,
+            None => match self.debugger.cont(system, CONTINUE_CHUNK) {
+                // Nothing to stop for yet, a waiting FX0A keeps the timers running
+                StopReason::Stepped | StopReason::Waiting => return,
+                reason => reason,
+            },
+        };
+        self.running = false;
+        self.stop(reason);
+    }
+
+    // A next or step out that does not return within a chunk goes on running like a continue,
+    // so pause is still answered
+    fn step(&mut self, command: &str) -> Result<Json, String> {
+        let system = self.system.as_mut().ok_or("no program is running")?;
+        self.running = false;
+        let reason = match command {
+            "next" => self.debugger.step_over(system, CONTINUE_CHUNK),
+            "stepOut" => self.debugger.step_out(system, CONTINUE_CHUNK),
+            _ => self.debugger.step(system),
+        };
+        match reason {
+            StopReason::Running => self.running = true,
+            reason => self.stop(reason),
+        }
+        Ok(Json::Null)
+    }
+
+    // Replaces the breakpoints of the listing. Lines of other sources have no address.
+    fn set_breakpoints(&mut self, args: &Json) -> Result<Json, String> {
+        let in_listing = args.get("source").get("sourceReference").as_i64() == Some(LISTING_REFERENCE);
+        let mut breakpoints = vec![];
+        let mut replies = vec![];
+        for requested in args.get("breakpoints").as_array() {
+            let line = requested.get("line").as_i64().unwrap_or(0);
+            let addr = ROM_OFFSET as i64 + 2 * (line - 1);
+            let result = if !in_listing {
+                Err("breakpoints can only be set in the disassembly listing".to_string())
+            } else if line < 1 || addr > u16::MAX as i64 {
+                Err(format!("line {} is outside of memory", line))
+            } else {
+                breakpoint(addr as u16, requested.get("condition").as_str())
+            };
+            replies.push(match result {
+                Ok(added) => {
+                    breakpoints.push(added);
+                    Json::object(vec![("verified", true.into()), ("line", line.into())])
+                },
+                Err(message) => Json::object(vec![("verified", false.into()), ("line", line.into()), ("message", message.into())]),
+            });
+        }
+        if in_listing {
+            self.source_breakpoints = breakpoints;
+            self.apply_breakpoints();
+        }
+        Ok(Json::object(vec![("breakpoints", replies.into())]))
+    }
+
+    // Replaces the breakpoints set in the client's disassembly view
+    fn set_instruction_breakpoints(&mut self, args: &Json) -> Result<Json, String> {
+        let mut breakpoints = vec![];
+        let mut replies = vec![];
+        for requested in args.get("breakpoints").as_array() {
+            let reference = requested.get("instructionReference").as_str().unwrap_or("");
+            let addr = parse_reference(reference, requested.get("offset").as_i64().unwrap_or(0));
+            let result = match addr {
+                Some(addr) if addr <= u16::MAX as i64 => breakpoint(addr as u16, requested.get("condition").as_str()),
+                _ => Err(format!("bad instruction reference {}", reference)),
+            };
+            replies.push(match result {
+                Ok(added) => {
+                    breakpoints.push(added);
+                    Json::object(vec![("verified", true.into()), ("instructionReference", reference.into())])
+                },
+                Err(message) => Json::object(vec![("verified", false.into()), ("message", message.into())]),
+            });
+        }
+        self.instruction_breakpoints = breakpoints;
+        self.apply_breakpoints();
+        Ok(Json::object(vec![("breakpoints", replies.into())]))
+    }
+
+    // Gives the debugger the current breakpoints, in place of the ones given before. Breakpoints
+    // added from the debug console are left alone.
+    fn apply_breakpoints(&mut self) {
+        for old in self.applied.drain(..) {
+            if old.conditions.is_empty() {
+                self.debugger.breakpoints.remove(&old.addr.unwrap_or(0));
+            } else if let Some(n) = self.debugger.conditional.iter().position(|other| *other == old) {
+                self.debugger.conditional.remove(n);
+            }
+        }
+        for new in self.source_breakpoints.iter().chain(self.instruction_breakpoints.iter()) {
+            if new.conditions.is_empty() {
+                self.debugger.breakpoints.insert(new.addr.unwrap_or(0));
+            } else {
+                self.debugger.conditional.push(new.clone());
+            }
+            self.applied.push(new.clone());
+        }
+    }
+
+    // PC, then the CALL each return address on the stack came from, innermost first
+    fn stack_trace(&mut self, args: &Json) -> Result<Json, String> {
+        let system = self.system.as_ref().ok_or("no program is running")?;
+        let cpu = &system.cpu;
+        let mut addrs = vec![cpu.pc];
+        addrs.extend(cpu.stack.iter().rev().map(|ret| ret.wrapping_sub(2)));
+        let start = args.get("startFrame").as_i64().unwrap_or(0).max(0) as usize;
+        let levels = match args.get("levels").as_i64() {
+            Some(levels) if levels > 0 => levels as usize,
+            _ => addrs.len(),
+        };
+        let frames: Vec<Json> = addrs.iter().enumerate().skip(start).take(levels).map(|(id, addr)| {
+            let (text, _) = debugger::disassemble(cpu, *addr);
+            let mut frame = vec![
+                ("id", id.into()),
+                ("name", format!("{:04X}  {}", addr, text).into()),
+                ("column", 1usize.into()),
+                ("instructionPointerReference", format!("0x{:04X}", addr).into()),
+            ];
+            match self.listing_line(*addr) {
+                Some(line) => {
+                    frame.push(("line", line.into()));
+                    frame.push(("source", self.listing_source()));
+                },
+                None => frame.push(("line", 0usize.into())),
+            }
+            Json::object(frame)
+        }).collect();
+        Ok(Json::object(vec![("stackFrames", frames.into()), ("totalFrames", addrs.len().into())]))
+    }
+
+    // V0-VF, I, PC, DT and ST, or the stack from the outermost return address
+    fn variables(&mut self, args: &Json) -> Result<Json, String> {
+        let cpu = &self.system.as_ref().ok_or("no program is running")?.cpu;
+        let variable = |name: String, value: String, memory: Option<u16>| {
+            let mut members = vec![("name", name.into()), ("value", value.into()), ("variablesReference", 0i64.into())];
+            if let Some(addr) = memory {
+                members.push(("memoryReference", format!("0x{:04X}", addr).into()));
+            }
+            Json::object(members)
+        };
+        let variables: Vec<Json> = match args.get("variablesReference").as_i64() {
+            Some(REGISTERS_REFERENCE) => {
+                let mut registers: Vec<Json> = (0..16).map(|vx| variable(format!("V{:X}", vx), format!("0x{:02X}", cpu.vregs[vx]), None)).collect();
+                registers.push(variable("I".to_string(), format!("0x{:04X}", cpu.i), Some(cpu.i)));
+                registers.push(variable("PC".to_string(), format!("0x{:04X}", cpu.pc), Some(cpu.pc)));
+                registers.push(variable("DT".to_string(), format!("0x{:02X}", cpu.dt), None));
+                registers.push(variable("ST".to_string(), format!("0x{:02X}", cpu.st), None));
+                registers
+            },
+            Some(STACK_REFERENCE) => cpu.stack.iter().enumerate()
+                .map(|(slot, addr)| variable(format!("[{}]", slot), format!("0x{:04X}", addr), Some(*addr)))
+                .collect(),
+            _ => vec![],
+        };
+        Ok(Json::object(vec![("variables", variables.into())]))
+    }
+
+    // The disassembly listing of the ROM, as it is in memory now
+    fn source(&mut self, args: &Json) -> Result<Json, String> {
+        let reference = args.get("sourceReference").as_i64().or_else(|| args.get("source").get("sourceReference").as_i64());
+        if reference != Some(LISTING_REFERENCE) {
+            return Err("unknown source".to_string());
+        }
+        let cpu = &self.system.as_ref().ok_or("no program is running")?.cpu;
+        let end = ROM_OFFSET + self.rom_size;
+        let lines: Vec<String> = (ROM_OFFSET..end).step_by(2).map(|addr| {
+            let (text, _) = debugger::disassemble(cpu, addr as u16);
+            format!("{:04X}  {}", addr, text)
+        }).collect();
+        Ok(Json::object(vec![("content", (lines.join("\n") + "\n").into()), ("mimeType", "text/plain".into())]))
+    }
+
+    // Memory as base64, bytes past the end of memory are unreadable
+    fn read_memory(&mut self, args: &Json) -> Result<Json, String> {
+        let cpu = &self.system.as_ref().ok_or("no program is running")?.cpu;
+        let reference = args.get("memoryReference").as_str().unwrap_or("");
+        let addr = parse_reference(reference, args.get("offset").as_i64().unwrap_or(0))
+            .ok_or(format!("bad memory reference {}", reference))?;
+        let count = args.get("count").as_i64().unwrap_or(0).max(0) as usize;
+        let memory = cpu.memory.borrow();
+        let start = (addr.max(0) as usize).min(memory.len());
+        let end = start.saturating_add(count).min(memory.len());
+        Ok(Json::object(vec![
+            ("address", format!("0x{:04X}", addr.max(0)).into()),
+            ("data", base64(&memory[start..end]).into()),
+            ("unreadableBytes", (count - (end - start)).into()),
+        ]))
+    }
+
+    // instructionCount instructions from instructionOffset instructions past the reference.
+    // Instructions before it are taken to be 2 bytes long.
+    fn disassemble(&mut self, args: &Json) -> Result<Json, String> {
+        let reference = args.get("memoryReference").as_str().unwrap_or("");
+        let mut addr = parse_reference(reference, args.get("offset").as_i64().unwrap_or(0))
+            .ok_or(format!("bad memory reference {}", reference))?;
+        let cpu = &self.system.as_ref().ok_or("no program is running")?.cpu;
+        let memsize = cpu.memory.borrow().len() as i64;
+        let instruction_offset = args.get("instructionOffset").as_i64().unwrap_or(0);
+        let count = args.get("instructionCount").as_i64().unwrap_or(0).max(0);
+        if count > memsize {
+            return Err(format!("instructionCount {} is more than memory holds", count));
+        }
+        if instruction_offset < 0 {
+            addr += 2 * instruction_offset;
+        } else {
+            for _ in 0..instruction_offset {
+                addr += if addr >= 0 && addr < memsize { debugger::disassemble(cpu, addr as u16).1 as i64 } else { 2 };
+            }
+        }
+        let mut instructions = vec![];
+        for _ in 0..count {
+            if addr < 0 || addr >= memsize {
+                instructions.push(Json::object(vec![
+                    ("address", format!("0x{:04X}", addr.max(0)).into()),
+                    ("instruction", "??".into()),
+                    ("presentationHint", "invalid".into()),
+                ]));
+                addr += 2;
+                continue;
+            }
+            let (text, size) = debugger::disassemble(cpu, addr as u16);
+            let memory = cpu.memory.borrow();
+            let bytes: Vec<String> = memory[addr as usize..(addr + size as i64).min(memsize) as usize]
+                .iter().map(|byte| format!("{:02X}", byte)).collect();
+            let mut instruction = vec![
+                ("address", format!("0x{:04X}", addr).into()),
+                ("instructionBytes", bytes.join(" ").into()),
+                ("instruction", text.into()),
+            ];
+            if let Some(line) = self.listing_line(addr as u16) {
+                instruction.push(("location", self.listing_source()));
+                instruction.push(("line", line.into()));
+            }
+            instructions.push(Json::object(instruction));
+            addr += size as i64;
+        }
+        Ok(Json::object(vec![("instructions", instructions.into())]))
+    }
+
+    // A register name such as V3 or I gives its value. Anything else is a command of the
+    // --debug prompt, such as "key 5 down" or "screen".
+    fn evaluate(&mut self, args: &Json) -> Result<Json, String> {
+        let expression = args.get("expression").as_str().unwrap_or("").trim();
+        // A count could keep the handler busy for as long as it likes, with no way to pause
+        if let ["s" | "step" | "c" | "continue", _, ..] = expression.split_whitespace().collect::<Vec<_>>().as_slice() {
+            return Err("run commands take no count here, use the step and continue buttons".to_string());
+        }
+        let system = self.system.as_mut().ok_or("no program is running")?;
+        let result = match Operand::parse(expression) {
+            Some(operand) => format!("0x{:X}", operand.value(&system.cpu)),
+            None => {
+                let pc = system.cpu.pc;
+                match self.debugger.command(system, expression) {
+                    Reply::Output(output) => {
+                        // Run commands move the program without the client asking
+                        if system.cpu.is_halted() {
+                            self.stop(StopReason::Halted);
+                        } else if system.cpu.pc != pc && !self.running {
+                            self.stopped("step", "stepped from the debug console".to_string());
+                        }
+                        output
+                    },
+                    Reply::Quit => {
+                        self.event("terminated", Json::Null);
+                        String::new()
+                    },
+                    // Runs in chunks like the continue request, so requests are still answered
+                    Reply::Continue => {
+                        self.running = true;
+                        self.resumed = true;
+                        self.event("continued", Json::object(vec![("threadId", THREAD_ID.into()), ("allThreadsContinued", true.into())]));
+                        String::new()
+                    },
+                }
+            },
+        };
+        Ok(Json::object(vec![("result", result.into()), ("variablesReference", 0i64.into())]))
+    }
+
+    // The line of addr in the disassembly listing, if it is in the ROM
+    fn listing_line(&self, addr: u16) -> Option<usize> {
+        let addr = addr as usize;
+        if addr >= ROM_OFFSET && addr < ROM_OFFSET + self.rom_size {
+            Some((addr - ROM_OFFSET) / 2 + 1)
+        } else {
+            None
+        }
+    }
+
+    fn listing_source(&self) -> Json {
+        let name = Path::new(&self.program).file_name().map_or(self.program.clone(), |name| name.to_string_lossy().into_owned());
+        Json::object(vec![
+            ("name", format!("{} (disassembly)", name).into()),
+            ("sourceReference", LISTING_REFERENCE.into()),
+        ])
+    }
+
+    // Reports why a step or continue ended. A halted CPU never runs again, so the program has
+    // exited rather than stopped.
+    fn stop(&mut self, reason: StopReason) {
+        let kind = match reason {
+            StopReason::Halted => {
+                self.event("exited", Json::object(vec![("exitCode", 0i64.into())]));
+                self.event("terminated", Json::Null);
+                return;
+            },
+            StopReason::Stepped | StopReason::Waiting => "step",
+            StopReason::Breakpoint(_) | StopReason::Condition(_) => "breakpoint",
+            StopReason::Watchpoint(_) => "data breakpoint",
+            StopReason::Interrupted | StopReason::Running => "pause",
+            StopReason::Error(_) => "exception",
+        };
+        self.stopped(kind, reason.to_string());
+    }
+
+    fn stopped(&mut self, reason: &str, description: String) {
+        self.event("stopped", Json::object(vec![
+            ("reason", reason.into()),
+            ("description", description.clone().into()),
+            ("text", description.into()),
+            ("threadId", THREAD_ID.into()),
+            ("allThreadsStopped", true.into()),
+        ]));
+    }
+
+    fn event(&mut self, name: &str, body: Json) {
+        let mut members = vec![("type", "event".into()), ("event", name.into())];
+        if body != Json::Null {
+            members.push(("body", body));
+        }
+        self.events.push(Json::object(members));
+    }
+
+    fn respond(&mut self, request: &Json, result: Result<Json, String>) -> io::Result<()> {
+        let mut members = vec![
+            ("type", "response".into()),
+            ("request_seq", request.get("seq").clone()),
+            ("command", request.get("command").clone()),
+        ];
+        match result {
+            Ok(body) => {
+                members.push(("success", true.into()));
+                if body != Json::Null {
+                    members.push(("body", body));
+                }
+            },
+            Err(message) => {
+                members.push(("success", false.into()));
+                members.push(("message", message.into()));
+            },
+        }
+        self.send(Json::object(members))
+    }
+
+    fn send(&mut self, message: Json) -> io::Result<()> {
+        let mut members = vec![("seq".to_string(), self.seq.into())];
+        self.seq += 1;
+        if let Json::Object(rest) = message {
+            members.extend(rest);
+        }
+        let body = Json::Object(members).to_string();
+        write!(self.output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        self.output.flush()
+    }
+}
+
+// What the server supports, the body of the initialize response
+fn capabilities() -> Json {
+    Json::object(vec![
+        ("supportsConfigurationDoneRequest", true.into()),
+        ("supportsConditionalBreakpoints", true.into()),
+        ("supportsInstructionBreakpoints", true.into()),
+        ("supportsReadMemoryRequest", true.into()),
+        ("supportsDisassembleRequest", true.into()),
+        ("supportsSteppingGranularity", false.into()),
+        ("supportsTerminateRequest", true.into()),
+    ])
+}
+
+// A breakpoint at addr, with the condition syntax of the --debug prompt
+fn breakpoint(addr: u16, condition: Option<&str>) -> Result<ConditionalBreakpoint, String> {
+    let text = condition.unwrap_or("").trim();
+    let conditions = if text.is_empty() { vec![] } else { Condition::parse_all(text)? };
+    Ok(ConditionalBreakpoint { addr: Some(addr), conditions, text: text.to_string() })
+}
+
+// Parses a memory or instruction reference, a hex address, and adds offset
+fn parse_reference(reference: &str, offset: i64) -> Option<i64> {
+    debugger::parse_addr(reference).map(|addr| addr as i64 + offset)
+}
+
+// Reads the next Content-Length framed message. Returns None at the end of the input, messages
+// that are not JSON or larger than MAX_MESSAGE_SIZE are skipped.
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Json>> {
+    loop {
+        let mut length = None;
+        loop {
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            let line = line.trim();
+            if line.is_empty() {
+                if length.is_some() {
+                    break;
+                }
+                continue;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("Content-Length") {
+                    length = value.trim().parse::<usize>().ok();
+                }
+            }
+        }
+        let length = length.unwrap_or(0);
+        if length > MAX_MESSAGE_SIZE {
+            io::copy(&mut input.by_ref().take(length as u64), &mut io::sink())?;
+            continue;
+        }
+        let mut body = vec![0u8; length];
+        input.read_exact(&mut body)?;
+        if let Ok(message) = Json::parse(&String::from_utf8_lossy(&body)) {
+            return Ok(Some(message));
+        }
+    }
+}
+
+fn base64(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len() / 3 * 4 + 4);
+    for chunk in data.chunks(3) {
+        let bits = (chunk[0] as u32) << 16 | (*chunk.get(1).unwrap_or(&0) as u32) << 8 | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_DIGITS[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{BufReader, Cursor};
+
+    use crate::dap::{read_message, DapServer};
+    use crate::json::Json;
+
+    // Runs a session with the given requests on a ROM written to a temporary file, returns every
+    // message the server sent
+    fn session(name: &str, rom: &[u8], requests: Vec<(&str, Json)>) -> Vec<Json> {
+        let path = std::env::temp_dir().join(format!("chip8-dap-{}-{}.ch8", name, std::process::id()));
+        fs::write(&path, rom).unwrap();
+        let mut input = vec![];
+        for (seq, (command, arguments)) in requests.into_iter().enumerate() {
+            let arguments = match arguments {
+                Json::String(ref program) if program == "ROM" => {
+                    Json::object(vec![("program", path.to_string_lossy().into_owned().into()), ("stopOnEntry", true.into())])
+                },
+                arguments => arguments,
+            };
+            let body = Json::object(vec![
+                ("seq", (seq + 1).into()),
+                ("type", "request".into()),
+                ("command", command.into()),
+                ("arguments", arguments),
+            ]).to_string();
+            input.extend_from_slice(format!("Content-Length: {}\r\n\r\n{}", body.len(), body).as_bytes());
+        }
+        let mut server = DapServer::new(Cursor::new(input), vec![]);
+        server.run().unwrap();
+        fs::remove_file(&path).ok();
+        let mut output = BufReader::new(Cursor::new(server.output));
+        let mut messages = vec![];
+        while let Some(message) = read_message(&mut output).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn response<'a>(messages: &'a [Json], command: &str) -> &'a Json {
+        messages.iter().find(|message| message.get("type").as_str() == Some("response") && message.get("command").as_str() == Some(command))
+            .unwrap_or_else(|| panic!("no {} response", command))
+    }
+
+    fn stops(messages: &[Json]) -> Vec<&str> {
+        messages.iter().filter(|message| message.get("event").as_str() == Some("stopped"))
+            .filter_map(|message| message.get("body").get("reason").as_str()).collect()
+    }
+
+    #[test]
+    fn pause_next_over_endless_call() {
+        // CALL 0x204; JP 0x200; then a subroutine that never returns
+        let messages = session("pause-next", &[0x22, 0x04, 0x12, 0x00, 0x70, 0x01, 0x12, 0x04], vec![
+            ("initialize", Json::Null),
+            ("launch", Json::from("ROM")),
+            ("setBreakpoints", Json::Null),
+            ("configurationDone", Json::Null),
+            ("next", Json::object(vec![("threadId", 1i64.into())])),
+            ("pause", Json::Null),
+            ("disconnect", Json::Null),
+        ]);
+        assert_eq!(response(&messages, "next").get("success").as_bool(), Some(true));
+        assert_eq!(response(&messages, "disconnect").get("success").as_bool(), Some(true));
+        assert_eq!(stops(&messages), vec!["entry", "pause"]);
+    }
+
+    #[test]
+    fn launch_break_and_next() {
+        // CALL 0x208; JP 0x202; then LD V0, 5; RET. Requests are all queued up front, so none
+        // of them may leave the program running for the next one to race with.
+        let rom = [0x22, 0x08, 0x12, 0x02, 0x00, 0x00, 0x00, 0x00, 0x60, 0x05, 0x00, 0xEE];
+        let listing = Json::object(vec![("sourceReference", 1i64.into())]);
+        let line = |line: i64| Json::object(vec![("line", line.into())]);
+        let thread = || Json::object(vec![("threadId", 1i64.into())]);
+        let messages = session("launch-break-next", &rom, vec![
+            ("initialize", Json::Null),
+            ("launch", Json::from("ROM")),
+            ("setBreakpoints", Json::object(vec![("source", listing), ("breakpoints", vec![line(6), line(0x8000)].into())])),
+            ("configurationDone", Json::Null),
+            ("next", thread()),
+            ("evaluate", Json::object(vec![("expression", "V0".into())])),
+            ("next", thread()),
+            ("evaluate", Json::object(vec![("expression", "PC".into())])),
+            ("disconnect", Json::Null),
+        ]);
+        // Every request is answered once, in order
+        let responses: Vec<_> = messages.iter().filter(|message| message.get("type").as_str() == Some("response")).collect();
+        let seqs: Vec<_> = responses.iter().map(|response| response.get("request_seq").as_i64().unwrap()).collect();
+        assert_eq!(seqs, (1..=9).collect::<Vec<i64>>());
+        assert!(responses.iter().all(|response| response.get("success").as_bool() == Some(true)));
+
+        let verified: Vec<_> = response(&messages, "setBreakpoints").get("body").get("breakpoints").as_array().iter()
+            .map(|breakpoint| breakpoint.get("verified").as_bool()).collect();
+        assert_eq!(verified, vec![Some(true), Some(false)]);
+        // The first next stops at the breakpoint on line 6 (the RET at 0x20A), the second returns
+        let results: Vec<_> = responses.iter().filter(|response| response.get("command").as_str() == Some("evaluate"))
+            .map(|response| response.get("body").get("result").as_str().unwrap()).collect();
+        assert_eq!(results, vec!["0x5", "0x202"]);
+        assert_eq!(stops(&messages), vec!["entry", "breakpoint", "step"]);
+    }
+}
@@ -0,0 +1,221 @@
+/* Debugger side panel of the SDL window: registers, the call stack and a disassembly around
+   PC, drawn with a built-in 5x7 font, plus pause and step buttons. */
+
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+
+use chip8_emu::debugger::{self, Debugger, StopReason};
+use chip8_emu::{display, System};
+
+pub const PANEL_WIDTH: u32 = 256;
+
+const MARGIN: i32 = 4;
+const CHAR_WIDTH: i32 = 6;
+const LINE_HEIGHT: i32 = 10;
+// Characters that fit on a line
+const COLUMNS: usize = ((PANEL_WIDTH as i32 - 2 * MARGIN) / CHAR_WIDTH) as usize;
+// Instructions shown before and after PC
+const DISASSEMBLY_CONTEXT: u16 = 4;
+
+// Lines the parts of the panel start at
+const STACK_LINE: i32 = 7;
+const DISASSEMBLY_LINE: i32 = 11;
+const BUTTON_LINE: i32 = 21;
+const STATUS_LINE: i32 = 24;
+
+const BUTTON_WIDTH: u32 = 60;
+const BUTTON_HEIGHT: u32 = 14;
+
+const BACKGROUND: Color = Color::RGB(0x20, 0x20, 0x28);
+const TEXT: Color = Color::RGB(0xD0, 0xD0, 0xD0);
+const LABEL: Color = Color::RGB(0x80, 0x80, 0x90);
+const HIGHLIGHT: Color = Color::RGB(0x30, 0x40, 0x80);
+
+// Glyphs for ' ' to '_', 7 rows of 5 pixels with bit 4 on the left. Lower case letters are
+// drawn as upper case, anything else as '?'.
+const FONT: [[u8; 7]; 64] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],  // ' '
+    [0x04, 0x04, 0x04, 0x04, 0x04, 0x00, 0x04],  // '!'
+    [0x0A, 0x0A, 0x0A, 0x00, 0x00, 0x00, 0x00],  // '"'
+    [0x0A, 0x0A, 0x1F, 0x0A, 0x1F, 0x0A, 0x0A],  // '#'
+    [0x04, 0x0F, 0x14, 0x0E, 0x05, 0x1E, 0x04],  // '$'
+    [0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03],  // '%'
+    [0x0C, 0x12, 0x14, 0x08, 0x15, 0x12, 0x0D],  // '&'
+    [0x04, 0x04, 0x08, 0x00, 0x00, 0x00, 0x00],  // '\''
+    [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],  // '('
+    [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],  // ')'
+    [0x00, 0x04, 0x15, 0x0E, 0x15, 0x04, 0x00],  // '*'
+    [0x00, 0x04, 0x04, 0x1F, 0x04, 0x04, 0x00],  // '+'
+    [0x00, 0x00, 0x00, 0x00, 0x0C, 0x04, 0x08],  // ','
+    [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],  // '-'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],  // '.'
+    [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],  // '/'
+    [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],  // '0'
+    [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],  // '1'
+    [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],  // '2'
+    [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],  // '3'
+    [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],  // '4'
+    [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],  // '5'
+    [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],  // '6'
+    [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],  // '7'
+    [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],  // '8'
+    [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],  // '9'
+    [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],  // ':'
+    [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x04, 0x08],  // ';'
+    [0x02, 0x04, 0x08, 0x10, 0x08, 0x04, 0x02],  // '<'
+    [0x00, 0x00, 0x1F, 0x00, 0x1F, 0x00, 0x00],  // '='
+    [0x08, 0x04, 0x02, 0x01, 0x02, 0x04, 0x08],  // '>'
+    [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04],  // '?'
+    [0x0E, 0x11, 0x01, 0x0D, 0x15, 0x15, 0x0E],  // '@'
+    [0x0E, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],  // 'A'
+    [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],  // 'B'
+    [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],  // 'C'
+    [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C],  // 'D'
+    [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],  // 'E'
+    [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],  // 'F'
+    [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],  // 'G'
+    [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],  // 'H'
+    [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],  // 'I'
+    [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],  // 'J'
+    [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],  // 'K'
+    [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],  // 'L'
+    [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],  // 'M'
+    [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],  // 'N'
+    [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],  // 'O'
+    [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],  // 'P'
+    [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],  // 'Q'
+    [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],  // 'R'
+    [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],  // 'S'
+    [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],  // 'T'
+    [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],  // 'U'
+    [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],  // 'V'
+    [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],  // 'W'
+    [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],  // 'X'
+    [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],  // 'Y'
+    [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],  // 'Z'
+    [0x0E, 0x08, 0x08, 0x08, 0x08, 0x08, 0x0E],  // '['
+    [0x00, 0x10, 0x08, 0x04, 0x02, 0x01, 0x00],  // '\\'
+    [0x0E, 0x02, 0x02, 0x02, 0x02, 0x02, 0x0E],  // ']'
+    [0x04, 0x0A, 0x11, 0x00, 0x00, 0x00, 0x00],  // '^'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1F],  // '_'
+];
+
+// Buttons of the panel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PanelButton {
+    Pause,  // Pauses or resumes the emulation
+    Step,   // Runs one instruction
+}
+
+#[derive(Debug, Default)]
+pub struct DebugPanel {
+    pub visible: bool,
+    debugger: Debugger,
+    status: String,     // What the last step or button did
+}
+
+impl DebugPanel {
+    // The button at x, y in the window
+    pub fn button_at(&self, x: i32, y: i32) -> Option<PanelButton> {
+        [PanelButton::Pause, PanelButton::Step].iter().copied()
+            .find(|button| button_rect(*button).contains_point((x, y)))
+    }
+
+    pub fn set_status(&mut self, status: &str) {
+        self.status = status.to_string();
+    }
+
+    // Runs one instruction, as the step command of the headless runner's --debug prompt does
+    pub fn step(&mut self, system: &mut System) {
+        self.status = match self.debugger.step(system) {
+            StopReason::Stepped => format!("stepped to {:04X}", system.cpu.pc),
+            reason => reason.to_string(),
+        };
+    }
+
+    // Draws the panel to the right of the game view
+    pub fn draw(&self, canvas: &mut Canvas<Window>, system: &System, paused: bool) -> Result<(), String> {
+        let left = display::REAL_SCREEN_WIDTH_PIXELS as i32;
+        let height = display::REAL_SCREEN_HEIGHT_PIXELS as u32;
+        canvas.set_draw_color(BACKGROUND);
+        canvas.fill_rect(Rect::new(left, 0, PANEL_WIDTH, height))?;
+
+        let cpu = &system.cpu;
+        draw_text(canvas, 0, &format!("PC {:04X}  I {:04X}  DT {:02X}  ST {:02X}", cpu.pc, cpu.i, cpu.dt, cpu.st), TEXT)?;
+        for row in 0..4 {
+            let regs: Vec<String> = (row * 4..row * 4 + 4).map(|vx| format!("V{:X} {:02X}", vx, cpu.vregs[vx])).collect();
+            draw_text(canvas, 2 + row as i32, &regs.join("  "), TEXT)?;
+        }
+
+        draw_text(canvas, STACK_LINE, &format!("STACK {}, INNERMOST FIRST", cpu.stack.len()), LABEL)?;
+        let stack: Vec<String> = cpu.stack.iter().rev().map(|addr| format!("{:04X}", addr)).collect();
+        for (row, addrs) in stack.chunks(8).enumerate() {
+            draw_text(canvas, STACK_LINE + 1 + row as i32, &addrs.join(" "), TEXT)?;
+        }
+
+        // Going backwards assumes 2 byte instructions
+        let mut addr = cpu.pc.saturating_sub(2 * DISASSEMBLY_CONTEXT);
+        for row in 0..(2 * DISASSEMBLY_CONTEXT as i32 + 1) {
+            if cpu.check_mem_range(addr as usize, 2).is_err() {
+                break;
+            }
+            let line = DISASSEMBLY_LINE + row;
+            let (text, size) = debugger::disassemble(cpu, addr);
+            let word = cpu.fetch_instr_from_addr(addr as usize).unwrap_or(0);
+            let marker = if addr == cpu.pc { ">" } else { " " };
+            if addr == cpu.pc {
+                canvas.set_draw_color(HIGHLIGHT);
+                canvas.fill_rect(Rect::new(left, line * LINE_HEIGHT + MARGIN - 2, PANEL_WIDTH, LINE_HEIGHT as u32))?;
+            }
+            draw_text(canvas, line, &format!("{}{:04X}  {:04X}  {}", marker, addr, word, text), TEXT)?;
+            addr = addr.wrapping_add(size);
+        }
+
+        draw_button(canvas, PanelButton::Pause, if paused { "RUN" } else { "PAUSE" })?;
+        draw_button(canvas, PanelButton::Step, "STEP")?;
+        draw_text(canvas, STATUS_LINE - 1, "F1 PANEL  F3 PAUSE  F4 STEP", LABEL)?;
+        draw_text(canvas, STATUS_LINE, &self.status, TEXT)
+    }
+}
+
+fn button_rect(button: PanelButton) -> Rect {
+    let column = match button {
+        PanelButton::Pause => 0,
+        PanelButton::Step => 1,
+    };
+    let x = display::REAL_SCREEN_WIDTH_PIXELS as i32 + MARGIN + column * (BUTTON_WIDTH as i32 + MARGIN);
+    Rect::new(x, BUTTON_LINE * LINE_HEIGHT, BUTTON_WIDTH, BUTTON_HEIGHT)
+}
+
+fn draw_button(canvas: &mut Canvas<Window>, button: PanelButton, label: &str) -> Result<(), String> {
+    let rect = button_rect(button);
+    canvas.set_draw_color(LABEL);
+    canvas.draw_rect(rect)?;
+    let x = rect.x() + (rect.width() as i32 - label.len() as i32 * CHAR_WIDTH) / 2;
+    draw_glyphs(canvas, x, rect.y() + (BUTTON_HEIGHT as i32 - 7) / 2, label, TEXT)
+}
+
+// Draws text on line of the panel, cut off at the panel's edge
+fn draw_text(canvas: &mut Canvas<Window>, line: i32, text: &str, colour: Color) -> Result<(), String> {
+    let text: String = text.chars().take(COLUMNS).collect();
+    draw_glyphs(canvas, display::REAL_SCREEN_WIDTH_PIXELS as i32 + MARGIN, line * LINE_HEIGHT + MARGIN, &text, colour)
+}
+
+fn draw_glyphs(canvas: &mut Canvas<Window>, x: i32, y: i32, text: &str, colour: Color) -> Result<(), String> {
+    let mut pixels = vec![];
+    for (n, c) in text.chars().enumerate() {
+        let c = c.to_ascii_uppercase();
+        let glyph = if (' '..='_').contains(&c) { FONT[c as usize - 0x20] } else { FONT['?' as usize - 0x20] };
+        for (row, bits) in glyph.iter().enumerate() {
+            for column in 0..5 {
+                if bits & (0x10 >> column) != 0 {
+                    pixels.push(Rect::new(x + n as i32 * CHAR_WIDTH + column, y + row as i32, 1, 1));
+                }
+            }
+        }
+    }
+    canvas.set_draw_color(colour);
+    canvas.fill_rects(&pixels)
+}
@@ -0,0 +1,815 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::cpu::{CPU, ExecutionError, Opcode, WatchHit, Watchpoint};
+use crate::system::{StepOutcome, System};
+
+// Instructions listed before and after PC by the list command
+const LIST_CONTEXT: u16 = 4;
+// Bytes shown by x when no length is given
+const DEFAULT_DUMP_LEN: usize = 64;
+
+const HELP: &str = "\
+break ADDR      (b)  stop when PC reaches ADDR
+break [ADDR] if COND stop before an instruction, at ADDR or anywhere, when COND holds
+watch ADDR [LEN]     stop after an instruction writes to LEN bytes from ADDR, 1 by default
+rwatch ADDR [LEN]    stop after an instruction reads them
+awatch ADDR [LEN]    stop after an instruction reads or writes them
+delete [ADDR|#N](d)  remove the breakpoint at ADDR, conditional breakpoint N, or everything
+unwatch [N]          remove watchpoint N, or all of them
+info break           list breakpoints and watchpoints
+step [N]        (s)  run N instructions, 1 by default
+next            (n)  like step, but runs a CALL until it returns; an empty line interrupts it
+continue [N]    (c)  run until a breakpoint, an error, a halt or a key wait, at most N instructions;
+                     an empty line interrupts it
+regs            (r)  show V0-VF, I, PC, DT, ST and the stack
+x ADDR [LEN]         hex dump LEN bytes of memory from ADDR, 64 by default
+list [ADDR]     (l)  disassemble around ADDR, PC by default
+key K down|up        press or release key K
+screen               print the display
+help            (h)  show this help
+quit            (q)  leave the debugger
+Addresses are hex, with or without 0x. An empty line repeats the last command.
+A condition is one or more of these joined with &&, where X is V0-VF, I, PC, DT, ST or SP (the
+stack depth) and numbers are decimal unless they start with 0x:
+  X == N, X != N, X < N, X <= N, X > N, X >= N
+  X inside N..M, X outside N..M   (both ends included)
+  any NAME                        the instruction at PC is NAME, such as DRW";
+
+// Why a debugger run stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopReason {
+    // The requested instructions ran
+    Stepped,
+    // PC reached a breakpoint
+    Breakpoint(u16),
+    // The condition of conditional breakpoint N held
+    Condition(usize),
+    // The instruction that just ran accessed watched memory
+    Watchpoint(WatchHit),
+    // LD VX, K is waiting for a key
+    Waiting,
+    // The CPU jumped to itself or an error halted it
+    Halted,
+    // An instruction failed and error_policy returned the error
+    Error(ExecutionError),
+    // The frontend asked to stop a continue
+    Interrupted,
+    // A next or step out used up its instructions before the subroutine returned, finish_step
+    // carries on with it
+    Running,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StopReason::Stepped => write!(f, "stepped"),
+            StopReason::Breakpoint(addr) => write!(f, "breakpoint at {:04X}", addr),
+            StopReason::Condition(n) => write!(f, "condition of breakpoint #{} holds", n),
+            StopReason::Watchpoint(hit) => write!(f, "watchpoint: {}", hit),
+            StopReason::Waiting => write!(f, "waiting for a key"),
+            StopReason::Halted => write!(f, "CPU halted"),
+            StopReason::Error(err) => write!(f, "{}", err),
+            StopReason::Interrupted => write!(f, "interrupted"),
+            StopReason::Running => write!(f, "still running"),
+        }
+    }
+}
+
+// A register or other value a condition looks at
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    V(u8),
+    I,
+    Pc,
+    Dt,
+    St,
+    Sp,     // Number of return addresses on the stack
+}
+
+impl Operand {
+    pub fn parse(text: &str) -> Option<Operand> {
+        let text = text.to_ascii_uppercase();
+        match text.as_str() {
+            "I" => Some(Operand::I),
+            "PC" => Some(Operand::Pc),
+            "DT" => Some(Operand::Dt),
+            "ST" => Some(Operand::St),
+            "SP" => Some(Operand::Sp),
+            _ => match text.strip_prefix('V').map(|vx| u8::from_str_radix(vx, 16)) {
+                Some(Ok(vx)) if vx < 16 && text.len() == 2 => Some(Operand::V(vx)),
+                _ => None,
+            },
+        }
+    }
+
+    pub fn value(&self, cpu: &CPU) -> u16 {
+        match *self {
+            Operand::V(vx) => cpu.vregs[vx as usize] as u16,
+            Operand::I => cpu.i,
+            Operand::Pc => cpu.pc,
+            Operand::Dt => cpu.dt as u16,
+            Operand::St => cpu.st as u16,
+            Operand::Sp => cpu.stack.len() as u16,
+        }
+    }
+}
+
+// How a conditional breakpoint compares a register with a value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    LessOrEqual,
+    GreaterOrEqual,
+    Less,
+    Greater,
+}
+
+// Longer operators first, so <= is not taken for <
+pub const COMPARISON_NAMES: [&str; 6] = ["==", "!=", "<=", ">=", "<", ">"];
+
+impl Comparison {
+    pub fn from_name(name: &str) -> Option<Comparison> {
+        match name {
+            "==" => Some(Comparison::Equal),
+            "!=" => Some(Comparison::NotEqual),
+            "<=" => Some(Comparison::LessOrEqual),
+            ">=" => Some(Comparison::GreaterOrEqual),
+            "<" => Some(Comparison::Less),
+            ">" => Some(Comparison::Greater),
+            _ => None,
+        }
+    }
+
+    pub fn holds(&self, current: u16, value: u16) -> bool {
+        match *self {
+            Comparison::Equal => current == value,
+            Comparison::NotEqual => current != value,
+            Comparison::LessOrEqual => current <= value,
+            Comparison::GreaterOrEqual => current >= value,
+            Comparison::Less => current < value,
+            Comparison::Greater => current > value,
+        }
+    }
+}
+
+// One test of a conditional breakpoint
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    // Operand compared with a value
+    Compare(Operand, Comparison, u16),
+    // Operand within start..end, both included, or not within it
+    Range { operand: Operand, start: u16, end: u16, inside: bool },
+    // The instruction at PC has this mnemonic, such as DRW
+    Instruction(String),
+}
+
+impl Condition {
+    // Parses one or more conditions joined with &&
+    pub fn parse_all(text: &str) -> Result<Vec<Condition>, String> {
+        text.split("&&").map(|clause| Condition::parse(clause.trim())).collect()
+    }
+
+    pub fn parse(text: &str) -> Result<Condition, String> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        match words.as_slice() {
+            ["any", name] => return Ok(Condition::Instruction(name.to_ascii_uppercase())),
+            [operand, keyword @ ("inside" | "outside"), range] => {
+                let operand = Operand::parse(operand).ok_or(format!("unknown register {}", operand))?;
+                let (start, end) = range.split_once("..").ok_or(format!("expected N..M, got {}", range))?;
+                let start = parse_number(start).ok_or(format!("bad number {}", start))?;
+                let end = parse_number(end).ok_or(format!("bad number {}", end))?;
+                return Ok(Condition::Range { operand, start, end, inside: *keyword == "inside" });
+            },
+            _ => {},
+        }
+        for name in COMPARISON_NAMES.iter() {
+            if let (Some((operand, value)), Some(comparison)) = (text.split_once(name), Comparison::from_name(name)) {
+                let (operand, value) = (operand.trim(), value.trim());
+                let operand = Operand::parse(operand).ok_or(format!("unknown register {}", operand))?;
+                let value = parse_number(value).ok_or(format!("bad number {}", value))?;
+                return Ok(Condition::Compare(operand, comparison, value));
+            }
+        }
+        Err(format!("bad condition {}", text))
+    }
+
+    pub fn holds(&self, cpu: &CPU) -> bool {
+        match *self {
+            Condition::Compare(operand, comparison, value) => comparison.holds(operand.value(cpu), value),
+            Condition::Range { operand, start, end, inside } => {
+                let current = operand.value(cpu);
+                (current >= start && current <= end) == inside
+            },
+            Condition::Instruction(ref name) => match cpu.decode_at(cpu.pc as usize) {
+                Ok(instr) => instr.print().split_whitespace().next() == Some(name.as_str()),
+                Err(_) => false,
+            },
+        }
+    }
+}
+
+// Stops before an instruction at addr, or at any address, when all its conditions hold
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConditionalBreakpoint {
+    pub addr: Option<u16>,
+    pub conditions: Vec<Condition>,
+    pub text: String,       // The conditions as they were typed
+}
+
+impl ConditionalBreakpoint {
+    pub fn holds(&self, cpu: &CPU) -> bool {
+        self.addr.map_or(true, |addr| addr == cpu.pc) && self.conditions.iter().all(|condition| condition.holds(cpu))
+    }
+}
+
+// What a debugger command asks of the frontend
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    // Print this and read the next command
+    Output(String),
+    // Leave the debugger
+    Quit,
+    // Run until something stops the program, or until an unfinished next returns, with
+    // Debugger::cont_interruptible so the user can break in
+    Continue,
+}
+
+// What an unfinished next or step out waits for: the stack back down to depth return addresses
+// and, for next, PC on the instruction after the CALL
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct StepTarget {
+    depth: usize,
+    pc: Option<u16>,
+}
+
+impl StepTarget {
+    fn reached(&self, cpu: &CPU) -> bool {
+        cpu.stack.len() <= self.depth && self.pc.unwrap_or(cpu.pc) == cpu.pc
+    }
+}
+
+// Breakpoints and stepping on top of System::run_tick, plus a gdb-like command language for the
+// headless runner's --debug mode. Timers tick as in a normal run with fixed timing.
+// Watchpoints are kept in CPU::watchpoints, where the loads and stores check them.
+#[derive(Debug, Default)]
+pub struct Debugger {
+    pub breakpoints: BTreeSet<u16>,
+    pub conditional: Vec<ConditionalBreakpoint>,
+    last_command: String,
+    step_target: Option<StepTarget>,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger { breakpoints: BTreeSet::new(), conditional: vec![], last_command: String::new(), step_target: None }
+    }
+
+    // Whether a breakpoint stops the instruction at PC from running
+    pub fn check_breakpoints(&self, cpu: &CPU) -> Option<StopReason> {
+        if self.breakpoints.contains(&cpu.pc) {
+            return Some(StopReason::Breakpoint(cpu.pc));
+        }
+        self.conditional.iter().position(|breakpoint| breakpoint.holds(cpu)).map(|n| StopReason::Condition(n + 1))
+    }
+
+    // Runs one instruction
+    pub fn step(&mut self, system: &mut System) -> StopReason {
+        step_once(system).unwrap_or(StopReason::Stepped)
+    }
+
+    // Runs one instruction, or a whole subroutine when PC is on a CALL, at most max_steps
+    // instructions. Stops early at a breakpoint inside the subroutine, and returns
+    // StopReason::Running if it has not returned after max_steps.
+    pub fn step_over(&mut self, system: &mut System, max_steps: u64) -> StopReason {
+        self.step_target = None;
+        let pc = system.cpu.pc;
+        let call_size = match system.cpu.decode_at(pc as usize) {
+            Ok(instr @ Opcode::Call { .. }) => instr.size(),
+            _ => return self.step(system),
+        };
+        let depth = system.cpu.stack.len();
+        if let Some(reason) = step_once(system) {
+            return reason;
+        }
+        self.step_target = Some(StepTarget { depth, pc: Some(pc.wrapping_add(call_size)) });
+        self.finish_step(system, max_steps.saturating_sub(1))
+    }
+
+    // Runs until the subroutine PC is in returns, or one instruction outside of a subroutine, at
+    // most max_steps instructions. Stops early at a breakpoint, and returns StopReason::Running
+    // if the subroutine has not returned after max_steps.
+    pub fn step_out(&mut self, system: &mut System, max_steps: u64) -> StopReason {
+        self.step_target = None;
+        let depth = system.cpu.stack.len();
+        if let Some(reason) = step_once(system) {
+            return reason;
+        }
+        if depth == 0 {
+            return StopReason::Stepped;
+        }
+        self.step_target = Some(StepTarget { depth: depth - 1, pc: None });
+        self.finish_step(system, max_steps.saturating_sub(1))
+    }
+
+    // Carries on with a next or step out that returned StopReason::Running, for at most
+    // max_steps more instructions
+    pub fn finish_step(&mut self, system: &mut System, max_steps: u64) -> StopReason {
+        let target = match self.step_target {
+            Some(target) => target,
+            None => return StopReason::Stepped,
+        };
+        let mut steps = 0;
+        loop {
+            if target.reached(&system.cpu) {
+                self.step_target = None;
+                return StopReason::Stepped;
+            }
+            if steps == max_steps {
+                return StopReason::Running;
+            }
+            if let Some(reason) = self.check_breakpoints(&system.cpu).or_else(|| step_once(system)) {
+                self.step_target = None;
+                return reason;
+            }
+            steps += 1;
+        }
+    }
+
+    // Whether a next or step out is waiting for finish_step
+    pub fn is_stepping(&self) -> bool {
+        self.step_target.is_some()
+    }
+
+    // Gives up on an unfinished next or step out
+    pub fn cancel_step(&mut self) {
+        self.step_target = None;
+    }
+
+    // Runs until PC reaches a breakpoint or the CPU stops, at most max_steps instructions. The
+    // instruction at PC always runs, so continuing from a breakpoint moves on.
+    pub fn cont(&mut self, system: &mut System, max_steps: u64) -> StopReason {
+        for n in 0..max_steps {
+            if n > 0 {
+                if let Some(reason) = self.check_breakpoints(&system.cpu) {
+                    return reason;
+                }
+            }
+            if let Some(reason) = step_once(system) {
+                return reason;
+            }
+        }
+        StopReason::Stepped
+    }
+
+    // Like cont without a limit, or finish_step when a next or step out is unfinished, but runs
+    // a frame of instructions at a time and stops when interrupted returns true between them
+    pub fn cont_interruptible<F: FnMut() -> bool>(&mut self, system: &mut System, mut interrupted: F) -> StopReason {
+        let mut first = true;
+        loop {
+            if !first && interrupted() {
+                self.step_target = None;
+                return StopReason::Interrupted;
+            }
+            let chunk = (system.cycles_per_frame as u64).max(1);
+            let stepping = self.is_stepping();
+            let reason = if stepping {
+                self.finish_step(system, chunk)
+            } else {
+                match self.check_breakpoints(&system.cpu) {
+                    Some(reason) if !first => return reason,
+                    _ => self.cont(system, chunk),
+                }
+            };
+            first = false;
+            match (stepping, reason) {
+                (false, StopReason::Stepped) | (true, StopReason::Running) => {},
+                (_, reason) => return reason,
+            }
+        }
+    }
+
+    // Runs one line of the command language
+    pub fn command(&mut self, system: &mut System, line: &str) -> Reply {
+        let line = if line.trim().is_empty() { self.last_command.clone() } else { line.trim().to_string() };
+        self.last_command = line.clone();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (name, args) = match words.split_first() {
+            Some((name, args)) => (*name, args),
+            None => return Reply::Output(String::new()),
+        };
+        let output = match (name, args) {
+            ("b", [addr]) | ("break", [addr]) => match parse_addr(addr) {
+                Some(addr) => {
+                    self.breakpoints.insert(addr);
+                    format!("breakpoint at {:04X}", addr)
+                },
+                None => format!("bad address {}", addr),
+            },
+            ("b", ["if", ..]) | ("break", ["if", ..]) => self.add_conditional(None, &args[1..]),
+            ("b", [addr, "if", ..]) | ("break", [addr, "if", ..]) => match parse_addr(addr) {
+                Some(addr) => self.add_conditional(Some(addr), &args[2..]),
+                None => format!("bad address {}", addr),
+            },
+            ("watch", [addr, ..]) => add_watchpoint(&mut system.cpu, addr, args.get(1), false, true),
+            ("rwatch", [addr, ..]) => add_watchpoint(&mut system.cpu, addr, args.get(1), true, false),
+            ("awatch", [addr, ..]) => add_watchpoint(&mut system.cpu, addr, args.get(1), true, true),
+            ("unwatch", []) => {
+                system.cpu.watchpoints.clear();
+                "deleted all watchpoints".to_string()
+            },
+            ("unwatch", [n]) => match n.parse::<usize>() {
+                Ok(n) if n >= 1 && n <= system.cpu.watchpoints.len() => {
+                    system.cpu.watchpoints.remove(n - 1);
+                    format!("deleted watchpoint {}", n)
+                },
+                _ => format!("no watchpoint {}", n),
+            },
+            ("d", []) | ("delete", []) => {
+                self.breakpoints.clear();
+                self.conditional.clear();
+                system.cpu.watchpoints.clear();
+                "deleted all breakpoints and watchpoints".to_string()
+            },
+            ("d", [number]) | ("delete", [number]) if number.starts_with('#') => match number[1..].parse::<usize>() {
+                Ok(n) if n >= 1 && n <= self.conditional.len() => {
+                    self.conditional.remove(n - 1);
+                    format!("deleted breakpoint #{}", n)
+                },
+                _ => format!("no breakpoint {}", number),
+            },
+            ("d", [addr]) | ("delete", [addr]) => match parse_addr(addr) {
+                Some(addr) if self.breakpoints.remove(&addr) => format!("deleted breakpoint at {:04X}", addr),
+                _ => format!("no breakpoint at {}", addr),
+            },
+            ("info", ["break"]) | ("info", ["breakpoints"]) => self.info_breakpoints(&system.cpu),
+            ("s", []) | ("step", []) => {
+                let reason = self.step(system);
+                stopped(system, reason)
+            },
+            ("s", [count]) | ("step", [count]) => match count.parse::<u64>() {
+                Ok(count) => {
+                    let mut reason = StopReason::Stepped;
+                    for _ in 0..count {
+                        reason = self.step(system);
+                        if reason != StopReason::Stepped {
+                            break;
+                        }
+                    }
+                    stopped(system, reason)
+                },
+                Err(_) => format!("bad count {}", count),
+            },
+            ("n", []) | ("next", []) => match self.step_over(system, (system.cycles_per_frame as u64).max(1)) {
+                // The frontend finishes it a frame at a time, like a continue
+                StopReason::Running => return Reply::Continue,
+                reason => stopped(system, reason),
+            },
+            ("c", []) | ("continue", []) => {
+                self.step_target = None;
+                return Reply::Continue;
+            },
+            ("c", [count]) | ("continue", [count]) => match count.parse::<u64>() {
+                Ok(count) => {
+                    let reason = self.cont(system, count);
+                    stopped(system, reason)
+                },
+                Err(_) => format!("bad count {}", count),
+            },
+            ("r", []) | ("regs", []) | ("info", ["registers"]) => registers(&system.cpu),
+            ("x", [addr]) | ("x", [addr, _]) => {
+                let len = match args.get(1).map(|len| len.parse::<usize>()) {
+                    Some(Ok(len)) => len,
+                    Some(Err(_)) => return Reply::Output(format!("bad length {}", args[1])),
+                    None => DEFAULT_DUMP_LEN,
+                };
+                match parse_addr(addr) {
+                    Some(addr) => hex_dump(&system.cpu, addr as usize, len),
+                    None => format!("bad address {}", addr),
+                }
+            },
+            ("l", []) | ("list", []) => self.list(&system.cpu, system.cpu.pc),
+            ("l", [addr]) | ("list", [addr]) => match parse_addr(addr) {
+                Some(addr) => self.list(&system.cpu, addr),
+                None => format!("bad address {}", addr),
+            },
+            ("key", [key, state]) => {
+                let pressed = match *state {
+                    "down" => Some(true),
+                    "up" => None,
+                    _ => return Reply::Output(format!("expected down or up, got {}", state)),
+                };
+                match u8::from_str_radix(key, 16) {
+                    Ok(key) if key < 16 => {
+                        system.cpu.curr_keys[key as usize] = pressed;
+                        format!("key {:X} {}", key, state)
+                    },
+                    _ => format!("bad key {}", key),
+                }
+            },
+            ("screen", []) => system.display.to_ascii(),
+            ("h", []) | ("help", []) => HELP.to_string(),
+            ("q", []) | ("quit", []) => return Reply::Quit,
+            _ => format!("unknown command {}, try help", line),
+        };
+        Reply::Output(output)
+    }
+
+    fn add_conditional(&mut self, addr: Option<u16>, words: &[&str]) -> String {
+        let text = words.join(" ");
+        match Condition::parse_all(&text) {
+            Ok(conditions) => {
+                self.conditional.push(ConditionalBreakpoint { addr, conditions, text });
+                format!("breakpoint #{}", self.conditional.len())
+            },
+            Err(err) => err,
+        }
+    }
+
+    fn info_breakpoints(&self, cpu: &CPU) -> String {
+        let mut lines: Vec<String> = self.breakpoints.iter().map(|addr| format!("{:04X}", addr)).collect();
+        for (n, breakpoint) in self.conditional.iter().enumerate() {
+            match breakpoint.addr {
+                Some(addr) => lines.push(format!("#{} {:04X} if {}", n + 1, addr, breakpoint.text)),
+                None => lines.push(format!("#{} if {}", n + 1, breakpoint.text)),
+            }
+        }
+        for (n, watch) in cpu.watchpoints.iter().enumerate() {
+            let access = match (watch.on_read, watch.on_write) {
+                (true, true) => "read/write",
+                (true, false) => "read",
+                _ => "write",
+            };
+            lines.push(format!("watchpoint {} {:04X}..{:04X} {}", n + 1, watch.start, watch.end, access));
+        }
+        if lines.is_empty() { "no breakpoints".to_string() } else { lines.join("\n") }
+    }
+
+    // Disassembles LIST_CONTEXT instructions either side of addr, marking PC and breakpoints.
+    // Going backwards assumes 2 byte instructions.
+    fn list(&self, cpu: &CPU, addr: u16) -> String {
+        let mut lines = vec![];
+        let mut addr = addr.saturating_sub(2 * LIST_CONTEXT);
+        for _ in 0..(2 * LIST_CONTEXT + 1) {
+            if cpu.check_mem_range(addr as usize, 2).is_err() {
+                break;
+            }
+            let (text, size) = disassemble(cpu, addr);
+            let marker = if addr == cpu.pc { "=>" } else { "  " };
+            let breakpoint = if self.breakpoints.contains(&addr) { "*" } else { " " };
+            let word = cpu.fetch_instr_from_addr(addr as usize).unwrap_or(0);
+            lines.push(format!("{}{} {:04X}  {:04X}  {}", marker, breakpoint, addr, word, text));
+            addr = addr.wrapping_add(size);
+        }
+        lines.join("\n")
+    }
+}
+
+// Reports why execution stopped and the instruction at PC
+pub fn stopped(system: &System, reason: StopReason) -> String {
+    let (text, _) = disassemble(&system.cpu, system.cpu.pc);
+    match reason {
+        StopReason::Stepped => format!("{:04X}  {}", system.cpu.pc, text),
+        _ => format!("{}\n{:04X}  {}", reason, system.cpu.pc, text),
+    }
+}
+
+// Adds a watchpoint on len bytes from addr, 1 if no length is given
+fn add_watchpoint(cpu: &mut CPU, addr: &str, len: Option<&&str>, on_read: bool, on_write: bool) -> String {
+    let start = match parse_addr(addr) {
+        Some(addr) => addr as usize,
+        None => return format!("bad address {}", addr),
+    };
+    let len = match len.map(|len| len.parse::<usize>()) {
+        Some(Ok(len)) if len > 0 => len,
+        None => 1,
+        Some(_) => return format!("bad length {}", len.unwrap()),
+    };
+    // A range running past the end of memory is cut off there
+    let last = cpu.memory.borrow().len() - 1;
+    if start > last {
+        return format!("bad address {}", addr);
+    }
+    let end = start.checked_add(len - 1).map_or(last, |end| end.min(last));
+    cpu.watchpoints.push(Watchpoint { start, end, on_read, on_write });
+    format!("watchpoint {} on {:04X}..{:04X}", cpu.watchpoints.len(), start, end)
+}
+
+// Runs one instruction, returning why to stop if the run should not go on
+fn step_once(system: &mut System) -> Option<StopReason> {
+    system.cpu.take_watch_hit();
+    match system.run_tick() {
+        Ok(StepOutcome::Executed(_)) | Ok(StepOutcome::Ignored(_)) => {
+            if let Some(hit) = system.cpu.take_watch_hit() {
+                Some(StopReason::Watchpoint(hit))
+            } else if system.cpu.is_halted() {
+                Some(StopReason::Halted)
+            } else {
+                None
+            }
+        },
+        Ok(StepOutcome::Waiting(_)) => Some(StopReason::Waiting),
+        Ok(StepOutcome::Halted) => Some(StopReason::Halted),
+        Err(err) => Some(StopReason::Error(err)),
+    }
+}
+
+// The instruction at addr as text, and its size in bytes. Words that are not instructions are
+// shown as data.
+pub fn disassemble(cpu: &CPU, addr: u16) -> (String, u16) {
+    match cpu.decode_at(addr as usize) {
+        Ok(instr) => (instr.print().trim_end().to_string(), instr.size()),
+        Err(_) => match cpu.fetch_instr_from_addr(addr as usize) {
+            Ok(word) => (format!(".word {:04X}", word), 2),
+            Err(_) => ("??".to_string(), 2),
+        },
+    }
+}
+
+// V0-VF, I, PC, DT, ST and the stack, innermost return address last
+pub fn registers(cpu: &CPU) -> String {
+    let mut out = format!("PC {:04X}  I {:04X}  DT {:02X}  ST {:02X}\n", cpu.pc, cpu.i, cpu.dt, cpu.st);
+    for row in 0..2 {
+        let regs: Vec<String> = (row * 8..row * 8 + 8).map(|vx| format!("V{:X} {:02X}", vx, cpu.vregs[vx])).collect();
+        out.push_str(&regs.join("  "));
+        out.push('\n');
+    }
+    let stack: Vec<String> = cpu.stack.iter().map(|addr| format!("{:04X}", addr)).collect();
+    out.push_str(&format!("stack [{}]", stack.join(" ")));
+    out
+}
+
+// len bytes of memory from addr, 16 to a line, stopping at the end of memory
+pub fn hex_dump(cpu: &CPU, addr: usize, len: usize) -> String {
+    let memory = cpu.memory.borrow();
+    let end = addr.saturating_add(len).min(memory.len());
+    if addr >= end {
+        return format!("address {:04X} out of range", addr);
+    }
+    let mut lines = vec![];
+    for start in (addr..end).step_by(16) {
+        let bytes = &memory[start..(start + 16).min(end)];
+        let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        let text: String = bytes.iter().map(|byte| if byte.is_ascii_graphic() { *byte as char } else { '.' }).collect();
+        lines.push(format!("{:04X}: {:<47}  {}", start, hex.join(" "), text));
+    }
+    lines.join("\n")
+}
+
+// Parses a decimal number, or a hex one starting with 0x
+fn parse_number(text: &str) -> Option<u16> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(digits) => u16::from_str_radix(digits, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+// Parses a hex address, with or without 0x
+pub fn parse_addr(text: &str) -> Option<u16> {
+    let digits = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).unwrap_or(text);
+    u16::from_str_radix(digits, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::WatchHit;
+    use crate::debugger::{Comparison, Condition, Debugger, Operand, Reply, StopReason};
+    use crate::rom::ROM;
+    use crate::system::System;
+
+    fn system_with(program: &[u16]) -> System {
+        let mut system = System::new();
+        system.set_seed(0);
+        system.load_rom(&ROM::new(program.iter().flat_map(|word| word.to_be_bytes().to_vec()).collect())).unwrap();
+        system
+    }
+
+    #[test]
+    fn step_over_in_chunks() {
+        // CALL 0x204; JP 0x202; then a subroutine that adds to V0 until it reaches 100
+        let mut system = system_with(&[0x2204, 0x1202, 0x7001, 0x3064, 0x1204, 0x00EE]);
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.step_over(&mut system, 50), StopReason::Running);
+        assert!(debugger.is_stepping());
+        let mut chunks = 0;
+        while debugger.finish_step(&mut system, 50) == StopReason::Running {
+            chunks += 1;
+        }
+        assert!(chunks > 0);
+        assert!(!debugger.is_stepping());
+        assert_eq!((system.cpu.pc, system.cpu.vregs[0]), (0x202, 100));
+
+        // Stepping out of the subroutine
+        let mut system = system_with(&[0x2204, 0x1202, 0x7001, 0x3064, 0x1204, 0x00EE]);
+        assert_eq!(debugger.step(&mut system), StopReason::Stepped);
+        assert_eq!(debugger.step_out(&mut system, 1000), StopReason::Stepped);
+        assert_eq!((system.cpu.pc, system.cpu.vregs[0]), (0x202, 100));
+
+        // A breakpoint inside the subroutine ends the step
+        let mut system = system_with(&[0x2204, 0x1202, 0x7001, 0x3064, 0x1204, 0x00EE]);
+        debugger.breakpoints.insert(0x20A);
+        assert_eq!(debugger.step_over(&mut system, 1000), StopReason::Breakpoint(0x20A));
+        assert!(!debugger.is_stepping());
+    }
+
+    #[test]
+    fn interrupt_next() {
+        // CALL 0x204; JP 0x200; then a subroutine that never returns
+        let mut system = system_with(&[0x2204, 0x1200, 0x7001, 0x1204]);
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.command(&mut system, "next"), Reply::Continue);
+        let mut chunks = 0;
+        let reason = debugger.cont_interruptible(&mut system, || {
+            chunks += 1;
+            chunks == 100
+        });
+        assert_eq!(reason, StopReason::Interrupted);
+        assert!(!debugger.is_stepping());
+        assert_eq!(system.cpu.stack.len(), 1);
+    }
+
+    // Watchpoint stops from running program to its end
+    fn watch_hits(program: &[u16], command: &str) -> Vec<WatchHit> {
+        let mut system = system_with(program);
+        let mut debugger = Debugger::new();
+        debugger.command(&mut system, command);
+        let mut hits = vec![];
+        loop {
+            match debugger.cont(&mut system, 1000) {
+                StopReason::Watchpoint(hit) => hits.push(hit),
+                reason => {
+                    assert_eq!(reason, StopReason::Halted);
+                    return hits;
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn watchpoints() {
+        // LD V0, 123; LD V1, 9; then with I on 0x300 each time: LD B, V0; LD [I], V1; LD V1, [I];
+        // DRW V0, V0, 2; JP 0x214
+        let program = [0x607B, 0x6109, 0xA300, 0xF033, 0xA300, 0xF155, 0xA300, 0xF165, 0xA300, 0xD002, 0x1214];
+        let hit = |pc, addr, value, write| WatchHit { pc, addr, value, write };
+        assert_eq!(watch_hits(&program, "watch 301"), vec![hit(0x206, 0x301, 2, true), hit(0x20A, 0x301, 9, true)]);
+        assert_eq!(watch_hits(&program, "rwatch 301"), vec![hit(0x20E, 0x301, 9, false), hit(0x212, 0x301, 9, false)]);
+        let hits = watch_hits(&program, "awatch 302 2");
+        assert_eq!(hits.iter().map(|hit| (hit.pc, hit.write)).collect::<Vec<_>>(), vec![(0x206, true)]);
+        assert_eq!(hits[0].addr, 0x302);
+        let pcs: Vec<u16> = watch_hits(&program, "awatch 300").iter().map(|hit| hit.pc).collect();
+        assert_eq!(pcs, vec![0x206, 0x20A, 0x20E, 0x212]);
+
+        // Ranges stop at the end of memory
+        let mut system = system_with(&program);
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.command(&mut system, "watch ffe 16"), Reply::Output("watchpoint 1 on 0FFE..0FFF".to_string()));
+        assert_eq!(debugger.command(&mut system, &format!("watch fff {}", usize::MAX)),
+                   Reply::Output("watchpoint 2 on 0FFF..0FFF".to_string()));
+        assert_eq!(debugger.command(&mut system, "watch 1000"), Reply::Output("bad address 1000".to_string()));
+        assert_eq!(debugger.command(&mut system, "watch 300 0"), Reply::Output("bad length 0".to_string()));
+    }
+
+    #[test]
+    fn parse_conditions() {
+        assert_eq!(Condition::parse("V3 == 0x10"), Ok(Condition::Compare(Operand::V(3), Comparison::Equal, 0x10)));
+        assert_eq!(Condition::parse("sp>=2"), Ok(Condition::Compare(Operand::Sp, Comparison::GreaterOrEqual, 2)));
+        assert_eq!(Condition::parse("DT < 7"), Ok(Condition::Compare(Operand::Dt, Comparison::Less, 7)));
+        assert_eq!(Condition::parse("I outside 0x200..0xFFF"),
+                   Ok(Condition::Range { operand: Operand::I, start: 0x200, end: 0xFFF, inside: false }));
+        assert_eq!(Condition::parse("any drw"), Ok(Condition::Instruction("DRW".to_string())));
+        assert_eq!(Condition::parse_all("SP > 2 && PC != 0x200").unwrap().len(), 2);
+        assert!(Condition::parse("V3 = 1").is_err());
+        assert!(Condition::parse("VG == 1").is_err());
+        assert!(Condition::parse("V3 == x").is_err());
+        assert!(Condition::parse("I inside 1-2").is_err());
+    }
+
+    #[test]
+    fn evaluate_conditions() {
+        let cases = [
+            (Comparison::Equal, [false, true, false]),
+            (Comparison::NotEqual, [true, false, true]),
+            (Comparison::LessOrEqual, [true, true, false]),
+            (Comparison::GreaterOrEqual, [false, true, true]),
+            (Comparison::Less, [true, false, false]),
+            (Comparison::Greater, [false, false, true]),
+        ];
+        for (comparison, expected) in cases.iter() {
+            for (current, holds) in [4, 5, 6].iter().zip(expected.iter()) {
+                assert_eq!(comparison.holds(*current, 5), *holds, "{} {:?} 5", current, comparison);
+            }
+        }
+
+        // LD V3, 0x10; DRW V0, V0, 1
+        let mut system = system_with(&[0x6310, 0xD001]);
+        let holds = |system: &System, text: &str| Condition::parse_all(text).unwrap().iter().all(|condition| condition.holds(&system.cpu));
+        assert!(!holds(&system, "V3 == 0x10"));
+        assert!(holds(&system, "PC inside 0x200..0x200"));
+        system.step().unwrap();
+        assert!(holds(&system, "V3 == 0x10 && any DRW"));
+        assert!(holds(&system, "I outside 0x200..0xFFF"));
+        assert!(!holds(&system, "V3 == 0x10 && SP > 0"));
+        assert!(!holds(&system, "any CLR"));
+    }
+}
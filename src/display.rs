@@ -8,73 +8,228 @@ pub const REAL_SCREEN_HEIGHT_PIXELS : u16 = 256;
 pub const SCREEN_WIDTH_PIXELS : u16 = 64;
 pub const SCREEN_HEIGHT_PIXELS: u16 = 32;
 
+// SUPER-CHIP high resolution screen dimensions in pixels
+pub const HIRES_SCREEN_WIDTH_PIXELS : u16 = 128;
+pub const HIRES_SCREEN_HEIGHT_PIXELS : u16 = 64;
+
 pub const PIXEL_WIDTH : u16 = REAL_SCREEN_WIDTH_PIXELS / SCREEN_WIDTH_PIXELS;
 pub const PIXEL_HEIGHT : u16 = REAL_SCREEN_WIDTH_PIXELS / SCREEN_WIDTH_PIXELS;
 
 // Each sprite is 8 pixels wide and up to 15 pixels height.
 pub const SPRITE_PIXELS_WIDTH : u16 = 8;
 
+// SUPER-CHIP DXY0 sprites are 16x16 pixels, stored as two bytes per row.
+pub const LARGE_SPRITE_PIXELS_WIDTH : u16 = 16;
+
+// XO-CHIP has two bitplanes, giving 4 colours
+pub const PLANE_COUNT : u8 = 2;
+pub const ALL_PLANES : u8 = (1 << PLANE_COUNT) - 1;
+
 #[derive(Debug)]
 pub struct Display {
-    // The screen is stored as as array of bytes. Even though the display
-    // is monochrome and we are only using 1 bit value.
-    pub mem : [[u8; SCREEN_WIDTH_PIXELS as usize] ; SCREEN_HEIGHT_PIXELS as usize]
+    // The screen is stored as as array of bytes. Each bit of a byte is one bitplane, bit 0 is
+    // plane 1 and bit 1 is plane 2, so a pixel holds a colour from 0 to 3. Programs that never
+    // select plane 2 only ever see the values 0 and 1.
+    // The buffer is always sized for high resolution, in low resolution only the
+    // top left SCREEN_WIDTH_PIXELS x SCREEN_HEIGHT_PIXELS corner is used.
+    pub mem : [[u8; HIRES_SCREEN_WIDTH_PIXELS as usize] ; HIRES_SCREEN_HEIGHT_PIXELS as usize],
+
+    // SUPER-CHIP 128x64 mode, switched with 00FF/00FE
+    pub hires: bool,
+
+    // XO-CHIP planes that drawing, clearing and scrolling apply to, selected with FN01
+    pub planes: u8,
 }
 
 
 
 impl Display {
     pub fn new() -> Display {
-        Display { mem: [[0u8; SCREEN_WIDTH_PIXELS as usize]; SCREEN_HEIGHT_PIXELS as usize] }
+        Display {
+            mem: [[0u8; HIRES_SCREEN_WIDTH_PIXELS as usize]; HIRES_SCREEN_HEIGHT_PIXELS as usize],
+            hires: false,
+            planes: 1,
+        }
+    }
+
+    // Width of the screen in the current resolution
+    pub fn width(&self) -> usize {
+        if self.hires { HIRES_SCREEN_WIDTH_PIXELS as usize } else { SCREEN_WIDTH_PIXELS as usize }
+    }
+
+    // Height of the screen in the current resolution
+    pub fn height(&self) -> usize {
+        if self.hires { HIRES_SCREEN_HEIGHT_PIXELS as usize } else { SCREEN_HEIGHT_PIXELS as usize }
+    }
+
+    // FN01: select which planes the following instructions apply to
+    pub fn select_planes(&mut self, planes: u8) {
+        self.planes = planes & ALL_PLANES;
+    }
+
+    // Number of selected planes. DXYN reads one sprite per selected plane.
+    pub fn selected_plane_count(&self) -> usize {
+        self.planes.count_ones() as usize
+    }
+
+    // Clears the selected planes
+    pub fn clear(&mut self) {
+        let keep = !self.planes;
+        for row in self.mem.iter_mut() {
+            for pixel in row.iter_mut() {
+                *pixel &= keep;
+            }
+        }
+    }
+
+    // Switches between the 64x32 and 128x64 modes. Like Octo, switching clears every plane.
+    pub fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.mem = [[0u8; HIRES_SCREEN_WIDTH_PIXELS as usize]; HIRES_SCREEN_HEIGHT_PIXELS as usize];
     }
 
     // Draws sprite of at given  x,y position. If the draw operation changes any existing values, then returns true, otherwise returns false
-    pub fn draw_sprite(&mut self, x: u8, y: u8, sprite: Vec<u8>) -> bool {
+    // The start position always wraps onto the screen. When clip is set, the parts of the sprite that
+    // run past the right or bottom edge are dropped instead of wrapping around to the other side.
+    // With several planes selected the sprite holds one image per plane, plane 1 first.
+    pub fn draw_sprite(&mut self, x: u8, y: u8, sprite: Vec<u8>, clip: bool) -> bool {
+        let rows: Vec<u16> = sprite.iter().map(|row| (*row as u16) << 8).collect();
+        self.draw_planes(x, y, &rows, SPRITE_PIXELS_WIDTH, clip)
+    }
+
+    // Draws a 16x16 SUPER-CHIP sprite, stored as 32 bytes with the left half of each row first.
+    pub fn draw_large_sprite(&mut self, x: u8, y: u8, sprite: Vec<u8>, clip: bool) -> bool {
+        let rows: Vec<u16> = sprite.chunks(2)
+            .map(|row| (row[0] as u16) << 8 | row.get(1).map_or(0, |b| *b as u16))
+            .collect();
+        self.draw_planes(x, y, &rows, LARGE_SPRITE_PIXELS_WIDTH, clip)
+    }
+
+    // Splits the rows evenly between the selected planes and draws each part into its plane.
+    fn draw_planes(&mut self, x: u8, y: u8, rows: &[u16], sprite_width: u16, clip: bool) -> bool {
+        let plane_count = self.selected_plane_count();
+        if plane_count == 0 {
+            return false;
+        }
+        let rows_per_plane = rows.len() / plane_count;
+        let mut screen_set = false;
+        let mut plane_rows = rows.chunks(rows_per_plane.max(1));
+        for plane in 0..PLANE_COUNT {
+            let plane_bit = 1 << plane;
+            if self.planes & plane_bit == 0 {
+                continue;
+            }
+            if let Some(rows) = plane_rows.next() {
+                screen_set |= self.draw_rows(x, y, rows, sprite_width, clip, plane_bit);
+            }
+        }
+        screen_set
+    }
+
+    // XORs the sprite rows onto one plane. Each row holds its pixels left aligned, most significant bit first.
+    fn draw_rows(&mut self, x: u8, y: u8, rows: &[u16], sprite_width: u16, clip: bool, plane_bit: u8) -> bool {
         let mut screen_set = false;
-        // Read current screen values. Place into a byte representing pixel values.
-
-        // For each sprite value index i
-        // Start at x position at row y + i, read SPRITE_PIXEL_WIDTH values from screen memory. 
-        // Pack this into a byte.
-        // xor the byte above with the current the sprite value byte
-        // Store the value back into screen. 
-        
-
-        println!("Display::draw_sprite x: {}, y: {}, sprite: {:?}", x, y, sprite);
-
-        for i in 0..sprite.len() {
-            let mut old_screen_row = 0u8;
-            // Extract the current 8 bit screen value.
-            for j in 0..SPRITE_PIXELS_WIDTH {
-                let row_index = (y+ (i as u8)) as usize;
-                let col_index = (x+(j as u8)) as usize;
-                //println!("row_index: {}, col_index: {}", row_index, col_index);
-                let pixel_value = self.mem[row_index % (SCREEN_HEIGHT_PIXELS as usize)][col_index % (SCREEN_WIDTH_PIXELS as usize)];
-                old_screen_row |= pixel_value << (SPRITE_PIXELS_WIDTH - j - 1);
+        let width = self.width();
+        let height = self.height();
+        let x = x as usize % width;
+        let y = y as usize % height;
+
+        for (i, sprite_row) in rows.iter().enumerate() {
+            let row_index = y + i;
+            if clip && row_index >= height {
+                break;
             }
-            // New screen value is current value xor sprite value. 
-            let new_screen_row = old_screen_row ^ sprite[i];
-            //println!("old_screen_row: {:X} sprite: {:X} new_screen_row: {:X}", old_screen_row, sprite[i], new_screen_row);
-            // If there is difference in values after xoring sprite
-            if new_screen_row != old_screen_row {
-                // Update the display with new values.
-                for j in 0..SPRITE_PIXELS_WIDTH {
-                    let row_index = (y+ (i as u8)) as usize;
-                    let col_index = (x+(j as u8)) as usize;
-                    self.mem[row_index % (SCREEN_HEIGHT_PIXELS as usize)][col_index % (SCREEN_WIDTH_PIXELS as usize)] = 
-                       (new_screen_row >> (SPRITE_PIXELS_WIDTH - j -1)) & 0x1;
-                    let new_pixel = (new_screen_row >> (SPRITE_PIXELS_WIDTH - j -1)) & 0x1;
-                    let old_pixel = (old_screen_row >> (SPRITE_PIXELS_WIDTH - j -1)) & 0x1;
-                    // Mark the operation as having changed the display
-                    if old_pixel == 1 && new_pixel == 0{
-                        screen_set = true
-                    }
+            let row_index = row_index % height;
+            for j in 0..sprite_width as usize {
+                if (sprite_row >> (15 - j)) & 0x1 == 0 {
+                    continue;
+                }
+                let col_index = x + j;
+                if clip && col_index >= width {
+                    break;
+                }
+                let pixel = &mut self.mem[row_index][col_index % width];
+                // Mark the operation as having turned off a pixel
+                if *pixel & plane_bit != 0 {
+                    screen_set = true
                 }
-                
+                *pixel ^= plane_bit;
             }
         }
         screen_set
-    } 
+    }
+
+    // Moves the selected planes of the pixel at (from_row, from_col) to (row, col). Out of range
+    // source coordinates scroll in blank pixels.
+    fn move_pixel(&mut self, row: usize, col: usize, from_row: Option<usize>, from_col: Option<usize>) {
+        let source = match (from_row, from_col) {
+            (Some(from_row), Some(from_col)) => self.mem[from_row][from_col] & self.planes,
+            _ => 0,
+        };
+        let pixel = &mut self.mem[row][col];
+        *pixel = (*pixel & !self.planes) | source;
+    }
+
+    // 00CN: scroll the selected planes down by n pixels
+    pub fn scroll_down(&mut self, n: u8) {
+        let n = n as usize;
+        for row in (0..self.height()).rev() {
+            for col in 0..self.width() {
+                self.move_pixel(row, col, row.checked_sub(n), Some(col));
+            }
+        }
+    }
+
+    // XO-CHIP 00DN: scroll the selected planes up by n pixels
+    pub fn scroll_up(&mut self, n: u8) {
+        let n = n as usize;
+        let height = self.height();
+        for row in 0..height {
+            let from_row = if row + n < height { Some(row + n) } else { None };
+            for col in 0..self.width() {
+                self.move_pixel(row, col, from_row, Some(col));
+            }
+        }
+    }
+
+    // 00FB: scroll the selected planes right by 4 pixels
+    pub fn scroll_right(&mut self) {
+        for row in 0..self.height() {
+            for col in (0..self.width()).rev() {
+                self.move_pixel(row, col, Some(row), col.checked_sub(4));
+            }
+        }
+    }
+
+    // 00FC: scroll the selected planes left by 4 pixels
+    pub fn scroll_left(&mut self) {
+        let width = self.width();
+        for row in 0..self.height() {
+            for col in 0..width {
+                let from_col = if col + 4 < width { Some(col + 4) } else { None };
+                self.move_pixel(row, col, Some(row), from_col);
+            }
+        }
+    }
+
+    // Renders the screen as text, one line per row. A clear pixel is '.', a pixel set only in plane 1
+    // is '#', only in plane 2 is '+' and in both planes is '@'.
+    pub fn to_ascii(&self) -> String {
+        let mut out = String::with_capacity((self.width() + 1) * self.height());
+        for row in self.mem.iter().take(self.height()) {
+            for pixel in row.iter().take(self.width()) {
+                out.push(['.', '#', '+', '@'][(*pixel & ALL_PLANES) as usize]);
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl fmt::Display for Display {
@@ -83,3 +238,111 @@ impl fmt::Display for Display {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use crate::display::{Display, HIRES_SCREEN_HEIGHT_PIXELS, HIRES_SCREEN_WIDTH_PIXELS, SCREEN_HEIGHT_PIXELS, SCREEN_WIDTH_PIXELS};
+
+    // Coordinates of the set pixels, row first
+    fn set_pixels(display: &Display) -> Vec<(usize, usize)> {
+        let mut pixels = vec![];
+        for (row, pixels_in_row) in display.mem.iter().enumerate() {
+            for (col, pixel) in pixels_in_row.iter().enumerate() {
+                if *pixel != 0 {
+                    pixels.push((row, col));
+                }
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn scroll_lores() {
+        let (width, height) = (SCREEN_WIDTH_PIXELS as usize, SCREEN_HEIGHT_PIXELS as usize);
+        let mut display = Display::new();
+        display.mem[0][0] = 1;
+        display.mem[1][width - 2] = 1;
+        display.mem[height - 1][5] = 1;
+        // Pixels that scroll off the lo-res screen are gone, they do not end up in the unused
+        // part of the buffer
+        display.scroll_down(2);
+        assert_eq!(set_pixels(&display), vec![(2, 0), (3, width - 2)]);
+        display.scroll_right();
+        assert_eq!(set_pixels(&display), vec![(2, 4)]);
+        display.scroll_left();
+        display.scroll_left();
+        assert_eq!(set_pixels(&display), vec![]);
+    }
+
+    #[test]
+    fn scroll_hires() {
+        let (width, height) = (HIRES_SCREEN_WIDTH_PIXELS as usize, HIRES_SCREEN_HEIGHT_PIXELS as usize);
+        let mut display = Display::new();
+        display.set_hires(true);
+        display.mem[0][100] = 1;
+        display.mem[0][width - 1] = 1;
+        display.scroll_down(15);
+        assert_eq!(set_pixels(&display), vec![(15, 100), (15, width - 1)]);
+        display.scroll_right();
+        assert_eq!(set_pixels(&display), vec![(15, 104)]);
+        display.scroll_left();
+        assert_eq!(set_pixels(&display), vec![(15, 100)]);
+        display.scroll_down((height - 16) as u8);
+        assert_eq!(set_pixels(&display), vec![(height - 1, 100)]);
+        display.scroll_down(1);
+        assert_eq!(set_pixels(&display), vec![]);
+    }
+
+    #[test]
+    fn scroll_selected_planes() {
+        let mut display = Display::new();
+        display.mem[5][7] = 3;
+        display.mem[6][7] = 1;
+        // 00DN on plane 2 leaves plane 1 where it was
+        display.select_planes(2);
+        display.scroll_up(5);
+        assert_eq!(set_pixels(&display), vec![(0, 7), (5, 7), (6, 7)]);
+        assert_eq!((display.mem[0][7], display.mem[5][7], display.mem[6][7]), (2, 1, 1));
+        display.select_planes(3);
+        display.scroll_up(1);
+        assert_eq!(set_pixels(&display), vec![(4, 7), (5, 7)]);
+    }
+
+    #[test]
+    fn large_sprite_collision() {
+        let mut display = Display::new();
+        display.set_hires(true);
+        let sprite = vec![0xFF; 32];
+        assert!(!display.draw_large_sprite(8, 4, sprite.clone(), true));
+        assert_eq!(set_pixels(&display).len(), 16 * 16);
+        assert_eq!(set_pixels(&display)[0], (4, 8));
+        assert_eq!(set_pixels(&display)[255], (19, 23));
+        // Overlapping a single pixel is a collision and clears it
+        let mut corner = vec![0; 32];
+        corner[0] = 0x80;
+        assert!(display.draw_large_sprite(23, 19, corner, true));
+        assert_eq!(display.mem[19][23], 0);
+        assert!(display.draw_large_sprite(8, 4, sprite, true));
+        assert_eq!(set_pixels(&display), vec![(19, 23)]);
+    }
+
+    #[test]
+    fn large_sprite_clipping() {
+        let (width, height) = (HIRES_SCREEN_WIDTH_PIXELS as usize, HIRES_SCREEN_HEIGHT_PIXELS as usize);
+        let sprite = vec![0xFF; 32];
+
+        let mut display = Display::new();
+        display.set_hires(true);
+        display.draw_large_sprite((width - 4) as u8, (height - 2) as u8, sprite.clone(), true);
+        assert_eq!(set_pixels(&display).len(), 4 * 2);
+        assert!(set_pixels(&display).iter().all(|&(row, col)| row >= height - 2 && col >= width - 4));
+
+        let mut display = Display::new();
+        display.set_hires(true);
+        display.draw_large_sprite((width - 4) as u8, (height - 2) as u8, sprite, false);
+        assert_eq!(set_pixels(&display).len(), 16 * 16);
+        assert_eq!(display.mem[0][0], 1);
+        assert_eq!(display.mem[13][11], 1);
+        assert_eq!(display.mem[14][12], 0);
+    }
+}
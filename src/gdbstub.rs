@@ -0,0 +1,610 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::cpu::{CPU, ExecutionError, STACK_SIZE, Watchpoint};
+use crate::debugger::{Debugger, StopReason};
+use crate::system::System;
+
+// Instructions run between checks for an interrupt from gdb while continuing
+const CONTINUE_CHUNK: u64 = 10_000;
+// Largest packet gdb may send or expect, reported in qSupported. Memory reads are cut down to
+// fit in a reply.
+const PACKET_SIZE: usize = 0x1000;
+
+// Register numbers in g/G/p/P packets and the target description: V0-VF, then I, PC, the stack
+// depth, DT, ST and the STACK_SIZE stack slots. 16 bit registers are sent little endian.
+const REG_I: usize = 16;
+const REG_PC: usize = 17;
+const REG_SP: usize = 18;
+const REG_DT: usize = 19;
+const REG_ST: usize = 20;
+const REG_STACK: usize = 21;
+const REG_COUNT: usize = REG_STACK + STACK_SIZE;
+
+// Signals reported in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+// Serves one gdb connection on addr, which should be a local address such as 127.0.0.1:1234.
+// Returns when gdb detaches, kills the program or disconnects.
+pub fn serve<A: ToSocketAddrs>(system: &mut System, addr: A) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    GdbStub::new(stream).run(system)
+}
+
+// GDB remote serial protocol server for one connection. Breakpoints, watchpoints and stepping
+// go through Debugger, so they behave as in the headless runner's --debug prompt.
+pub struct GdbStub {
+    stream: TcpStream,
+    debugger: Debugger,
+    no_ack: bool,           // QStartNoAckMode: packets are no longer acknowledged
+    last_reply: Vec<u8>,    // Sent again when gdb asks with '-'
+    pending: VecDeque<u8>,  // Bytes read while checking for an interrupt, read again first
+}
+
+impl GdbStub {
+    pub fn new(stream: TcpStream) -> GdbStub {
+        GdbStub { stream, debugger: Debugger::new(), no_ack: false, last_reply: vec![], pending: VecDeque::new() }
+    }
+
+    // Answers packets until the session ends
+    pub fn run(&mut self, system: &mut System) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            let reply = match packet.as_str() {
+                "k" => return Ok(()),
+                "D" => {
+                    self.send("OK")?;
+                    return Ok(());
+                },
+                _ => self.handle(system, &packet)?,
+            };
+            self.send(&reply)?;
+            if packet == "QStartNoAckMode" {
+                self.no_ack = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, system: &mut System, packet: &str) -> io::Result<String> {
+        if packet.is_empty() || !packet.is_char_boundary(1) {
+            return Ok(String::new());
+        }
+        let (command, args) = packet.split_at(1);
+        let reply = match command {
+            "?" => stop_reply(&system.cpu, StopReason::Stepped),
+            "g" => (0..REG_COUNT).map(|reg| read_register(&system.cpu, reg)).collect(),
+            "G" => {
+                let mut rest = args;
+                for reg in 0..REG_COUNT {
+                    let len = register_size(reg) * 2;
+                    if rest.len() < len {
+                        break;
+                    }
+                    let (value, tail) = rest.split_at(len);
+                    write_register(&mut system.cpu, reg, value);
+                    rest = tail;
+                }
+                "OK".to_string()
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(reg) if reg < REG_COUNT => read_register(&system.cpu, reg),
+                _ => "E01".to_string(),
+            },
+            "P" => match args.split_once('=').map(|(reg, value)| (usize::from_str_radix(reg, 16), value)) {
+                Some((Ok(reg), value)) if reg < REG_COUNT && value.len() == register_size(reg) * 2 => {
+                    write_register(&mut system.cpu, reg, value);
+                    "OK".to_string()
+                },
+                _ => "E01".to_string(),
+            },
+            "m" => match parse_range(args) {
+                Some((addr, len)) => read_memory(&system.cpu, addr, len),
+                None => "E01".to_string(),
+            },
+            "M" => match args.split_once(':').and_then(|(range, data)| Some((parse_range(range)?, data))) {
+                Some(((addr, len), data)) => write_memory(&system.cpu, addr, len, data),
+                None => "E01".to_string(),
+            },
+            "Z" | "z" => self.breakpoint(&mut system.cpu, command == "Z", args),
+            "s" => {
+                let reason = self.debugger.step(system);
+                stop_reply(&system.cpu, reason)
+            },
+            "c" => self.cont(system)?,
+            "H" => "OK".to_string(),
+            "q" | "Q" | "v" => self.query(system, packet)?,
+            _ => String::new(),
+        };
+        Ok(reply)
+    }
+
+    // General queries and settings, and vCont
+    fn query(&mut self, system: &mut System, packet: &str) -> io::Result<String> {
+        let reply = if packet.starts_with("qSupported") {
+            format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+", PACKET_SIZE)
+        } else if let Some(request) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            match parse_range(request) {
+                Some((offset, len)) => {
+                    let xml = target_xml();
+                    let start = offset.min(xml.len());
+                    let end = start.saturating_add(len).min(xml.len());
+                    let more = if end < xml.len() { "m" } else { "l" };
+                    format!("{}{}", more, &xml[start..end])
+                },
+                None => "E01".to_string(),
+            }
+        } else {
+            match packet {
+                "qAttached" => "1".to_string(),
+                "qC" => "QC1".to_string(),
+                "qfThreadInfo" => "m1".to_string(),
+                "qsThreadInfo" => "l".to_string(),
+                "qSymbol::" | "QStartNoAckMode" => "OK".to_string(),
+                "vCont?" => "vCont;c;C;s;S".to_string(),
+                _ => match packet.strip_prefix("vCont;") {
+                    Some(actions) => self.resume(system, actions)?,
+                    None => String::new(),
+                },
+            }
+        };
+        Ok(reply)
+    }
+
+    // vCont: runs the first action for thread 1, all threads (-1) or no thread in particular.
+    // Signals given with C and S are ignored, the program has no signal handlers.
+    fn resume(&mut self, system: &mut System, actions: &str) -> io::Result<String> {
+        for action in actions.split(';') {
+            let (action, thread) = match action.split_once(':') {
+                Some((action, thread)) => (action, Some(thread)),
+                None => (action, None),
+            };
+            if !matches!(thread, None | Some("1") | Some("-1")) {
+                continue;
+            }
+            match action.get(..1) {
+                Some("s") | Some("S") => {
+                    let reason = self.debugger.step(system);
+                    return Ok(stop_reply(&system.cpu, reason));
+                },
+                Some("c") | Some("C") => return self.cont(system),
+                _ => return Ok("E01".to_string()),
+            }
+        }
+        Ok("E01".to_string())
+    }
+
+    // Z/z: sets or clears a breakpoint (types 0 and 1) or a watchpoint on writes (2), reads (3)
+    // or both (4)
+    fn breakpoint(&mut self, cpu: &mut CPU, insert: bool, args: &str) -> String {
+        let mut fields = args.split(',');
+        let kind = fields.next();
+        let addr = fields.next().and_then(|addr| usize::from_str_radix(addr, 16).ok());
+        let len = fields.next().and_then(|len| usize::from_str_radix(len, 16).ok()).unwrap_or(1).max(1);
+        // Memory is at most 64 KiB, larger addresses would wrap around when stored as PC
+        let addr = match addr {
+            Some(addr) if addr <= u16::MAX as usize => addr,
+            _ => return "E01".to_string(),
+        };
+        let (on_read, on_write) = match kind {
+            Some("0") | Some("1") => {
+                if insert {
+                    self.debugger.breakpoints.insert(addr as u16);
+                } else {
+                    self.debugger.breakpoints.remove(&(addr as u16));
+                }
+                return "OK".to_string();
+            },
+            Some("2") => (false, true),
+            Some("3") => (true, false),
+            Some("4") => (true, true),
+            _ => return String::new(),
+        };
+        let end = match addr.checked_add(len - 1) {
+            Some(end) => end,
+            None => return "E01".to_string(),
+        };
+        let watch = Watchpoint { start: addr, end, on_read, on_write };
+        if insert {
+            cpu.watchpoints.push(watch);
+        } else if let Some(n) = cpu.watchpoints.iter().position(|other| *other == watch) {
+            cpu.watchpoints.remove(n);
+        }
+        "OK".to_string()
+    }
+
+    // Runs until something stops the CPU or gdb sends an interrupt
+    fn cont(&mut self, system: &mut System) -> io::Result<String> {
+        let mut first = true;
+        loop {
+            if !first {
+                if let Some(reason) = self.debugger.check_breakpoints(&system.cpu) {
+                    return Ok(stop_reply(&system.cpu, reason));
+                }
+            }
+            first = false;
+            match self.debugger.cont(system, CONTINUE_CHUNK) {
+                // Nothing to stop for yet, a waiting FX0A keeps the timers running
+                StopReason::Stepped | StopReason::Waiting => {},
+                reason => return Ok(stop_reply(&system.cpu, reason)),
+            }
+            if self.interrupted()? {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+        }
+    }
+
+    // Whether gdb sent a break (Ctrl-C) while the program was running
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0u8; 1];
+        let result = self.stream.read(&mut byte);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(1) if byte[0] == 0x03 => Ok(true),
+            Ok(1) => {
+                self.pending.push_back(byte[0]);
+                Ok(false)
+            },
+            Ok(_) => Err(io::ErrorKind::UnexpectedEof.into()),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if let Some(byte) = self.pending.pop_front() {
+            return Ok(Some(byte));
+        }
+        let mut byte = [0u8; 1];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    // Reads the next $packet#checksum, acknowledging it. Returns None when gdb disconnects.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {},
+                Some(b'-') => {
+                    let reply = self.last_reply.clone();
+                    self.stream.write_all(&reply)?;
+                    continue;
+                },
+                // Acks and interrupts outside of a run
+                Some(_) => continue,
+            }
+            let mut data = vec![];
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let mut checksum = [0u8; 2];
+            for digit in checksum.iter_mut() {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(byte) => *digit = byte,
+                }
+            }
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            if !self.no_ack {
+                if expected != Some(checksum_of(&data)) {
+                    self.stream.write_all(b"-")?;
+                    continue;
+                }
+                self.stream.write_all(b"+")?;
+            }
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.last_reply = packet.into_bytes();
+        self.stream.write_all(&self.last_reply)?;
+        self.stream.flush()
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+// Stop reply for why a step or continue ended
+fn stop_reply(cpu: &CPU, reason: StopReason) -> String {
+    match reason {
+        StopReason::Watchpoint(hit) => {
+            let kind = match cpu.watchpoints.iter().find(|watch| hit.addr >= watch.start && hit.addr <= watch.end) {
+                Some(watch) if watch.on_read && watch.on_write => "awatch",
+                _ if hit.write => "watch",
+                _ => "rwatch",
+            };
+            format!("T{:02x}{}:{:x};", SIGTRAP, kind, hit.addr)
+        },
+        StopReason::Error(ExecutionError::InvalidOpcode { .. }) | StopReason::Error(ExecutionError::InvalidKey { .. }) =>
+            format!("S{:02x}", SIGILL),
+        StopReason::Error(_) => format!("S{:02x}", SIGSEGV),
+        _ => format!("S{:02x}", SIGTRAP),
+    }
+}
+
+fn register_size(reg: usize) -> usize {
+    match reg {
+        REG_I | REG_PC => 2,
+        _ if reg >= REG_STACK => 2,
+        _ => 1,
+    }
+}
+
+fn read_register(cpu: &CPU, reg: usize) -> String {
+    let value = match reg {
+        0..=15 => cpu.vregs[reg] as u16,
+        REG_I => cpu.i,
+        REG_PC => cpu.pc,
+        REG_SP => cpu.stack.len() as u16,
+        REG_DT => cpu.dt as u16,
+        REG_ST => cpu.st as u16,
+        _ => cpu.stack.get(reg - REG_STACK).copied().unwrap_or(0),
+    };
+    value.to_le_bytes()[..register_size(reg)].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Sets a register from its hex encoding. Writing SP grows or shrinks the stack, stack slots past
+// SP are ignored.
+fn write_register(cpu: &mut CPU, reg: usize, hex: &str) {
+    let bytes = decode_hex(hex).unwrap_or_default();
+    let value = match bytes.as_slice() {
+        [low] => *low as u16,
+        [low, high] => u16::from_le_bytes([*low, *high]),
+        _ => return,
+    };
+    match reg {
+        0..=15 => cpu.vregs[reg] = value as u8,
+        REG_I => cpu.i = value,
+        REG_PC => cpu.pc = value,
+        REG_SP => cpu.stack.resize((value as usize).min(STACK_SIZE), 0),
+        REG_DT => cpu.dt = value as u8,
+        REG_ST => cpu.st = value as u8,
+        _ => if let Some(slot) = cpu.stack.get_mut(reg - REG_STACK) {
+            *slot = value;
+        },
+    }
+}
+
+// Reads up to len bytes, fewer if memory or the reply packet ends first
+fn read_memory(cpu: &CPU, addr: usize, len: usize) -> String {
+    let memory = cpu.memory.borrow();
+    if addr >= memory.len() {
+        return "E01".to_string();
+    }
+    let len = len.min(memory.len() - addr).min(PACKET_SIZE / 2);
+    memory[addr..addr + len].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Writes through store_byte_mem, so decoded and translated code over the bytes is dropped
+fn write_memory(cpu: &CPU, addr: usize, len: usize, hex: &str) -> String {
+    match decode_hex(hex) {
+        Some(bytes) if bytes.len() == len && cpu.check_mem_range(addr, len).is_ok() => {
+            for (i, byte) in bytes.iter().enumerate() {
+                let _ = cpu.store_byte_mem(addr + i, *byte);
+            }
+            // gdb's own writes are not the program's, forget any watchpoint they hit
+            cpu.take_watch_hit();
+            "OK".to_string()
+        },
+        _ => "E01".to_string(),
+    }
+}
+
+// Parses ADDR,LENGTH in hex
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let (addr, len) = text.split_once(',')?;
+    Some((usize::from_str_radix(addr, 16).ok()?, usize::from_str_radix(len, 16).ok()?))
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+// Target description of the CHIP-8 register file, in the register order of g packets
+pub fn target_xml() -> String {
+    let mut regs = String::new();
+    for vx in 0..16 {
+        regs.push_str(&format!("    <reg name=\"v{:x}\" bitsize=\"8\" type=\"uint8\" group=\"general\"/>\n", vx));
+    }
+    regs.push_str("    <reg name=\"i\" bitsize=\"16\" type=\"data_ptr\" group=\"general\"/>\n");
+    regs.push_str("    <reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\" group=\"general\"/>\n");
+    regs.push_str("    <reg name=\"sp\" bitsize=\"8\" type=\"uint8\" group=\"general\"/>\n");
+    regs.push_str("    <reg name=\"dt\" bitsize=\"8\" type=\"uint8\" group=\"timers\"/>\n");
+    regs.push_str("    <reg name=\"st\" bitsize=\"8\" type=\"uint8\" group=\"timers\"/>\n");
+    for slot in 0..STACK_SIZE {
+        regs.push_str(&format!("    <reg name=\"stack{}\" bitsize=\"16\" type=\"code_ptr\" group=\"stack\"/>\n", slot));
+    }
+    format!("<?xml version=\"1.0\"?>\n\
+             <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+             <target version=\"1.0\">\n  <feature name=\"org.chip8.core\">\n{}  </feature>\n</target>\n", regs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread::{self, JoinHandle};
+
+    use crate::gdbstub::{checksum_of, target_xml, GdbStub};
+    use crate::rom::ROM;
+    use crate::system::System;
+
+    // Starts a stub on a local socket for a system running program and returns gdb's end of it
+    fn session(program: &[u16]) -> (TcpStream, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        client.set_nodelay(true).unwrap();
+        stream.set_nodelay(true).unwrap();
+        let rom = ROM::new(program.iter().flat_map(|word| word.to_be_bytes().to_vec()).collect());
+        let stub = thread::spawn(move || {
+            let mut system = System::new();
+            system.load_rom(&rom).unwrap();
+            GdbStub::new(stream).run(&mut system).unwrap();
+        });
+        (client, stub)
+    }
+
+    fn read_char(client: &mut TcpStream) -> u8 {
+        let mut byte = [0u8; 1];
+        client.read_exact(&mut byte).unwrap();
+        byte[0]
+    }
+
+    // Reads the next $reply#checksum, checking the checksum, and acknowledges it
+    fn reply(client: &mut TcpStream) -> String {
+        assert_eq!(read_char(client), b'$');
+        let mut data = vec![];
+        loop {
+            match read_char(client) {
+                b'#' => break,
+                byte => data.push(byte),
+            }
+        }
+        let checksum = [read_char(client), read_char(client)];
+        assert_eq!(std::str::from_utf8(&checksum).unwrap(), format!("{:02x}", checksum_of(&data)));
+        client.write_all(b"+").unwrap();
+        String::from_utf8(data).unwrap()
+    }
+
+    // Sends a packet, expects it to be acknowledged and returns the reply
+    fn exchange(client: &mut TcpStream, packet: &str) -> String {
+        write!(client, "${}#{:02x}", packet, checksum_of(packet.as_bytes())).unwrap();
+        assert_eq!(read_char(client), b'+');
+        reply(client)
+    }
+
+    fn end(mut client: TcpStream, stub: JoinHandle<()>) {
+        client.write_all(b"$k#6b").unwrap();
+        assert_eq!(read_char(&mut client), b'+');
+        stub.join().unwrap();
+    }
+
+    #[test]
+    fn packet_framing() {
+        let (mut client, stub) = session(&[0x1200]);
+        // A bad checksum is refused, the packet sent again is answered
+        client.write_all(b"$?#00").unwrap();
+        assert_eq!(read_char(&mut client), b'-');
+        assert_eq!(exchange(&mut client, "?"), "S05");
+        // '-' asks for the last reply again
+        client.write_all(b"-").unwrap();
+        assert_eq!(reply(&mut client), "S05");
+        // Unknown packets get an empty reply
+        assert_eq!(exchange(&mut client, "X"), "");
+        end(client, stub);
+    }
+
+    #[test]
+    fn registers() {
+        let (mut client, stub) = session(&[0x6A05, 0xA345, 0x1204]);
+        assert_eq!(exchange(&mut client, "s"), "S05");
+        assert_eq!(exchange(&mut client, "s"), "S05");
+        // V0-VF, then I, PC, SP, DT, ST and the 16 stack slots
+        let regs = exchange(&mut client, "g");
+        assert_eq!(regs.len(), 16 * 2 + 2 * 4 + 3 * 2 + 16 * 4);
+        assert_eq!(&regs[20..22], "05");
+        assert_eq!(&regs[32..40], "45030402");
+        assert_eq!(exchange(&mut client, "p11"), "0402");
+
+        let mut written = regs.clone();
+        written.replace_range(0..2, "7f");
+        written.replace_range(32..36, "0003");
+        assert_eq!(exchange(&mut client, &format!("G{}", written)), "OK");
+        assert_eq!(exchange(&mut client, "g"), written);
+        assert_eq!(exchange(&mut client, "P10=3412"), "OK");
+        assert_eq!(exchange(&mut client, "p10"), "3412");
+        assert_eq!(exchange(&mut client, "p99"), "E01");
+        end(client, stub);
+    }
+
+    #[test]
+    fn memory() {
+        let (mut client, stub) = session(&[0x1200]);
+        assert_eq!(exchange(&mut client, "m200,2"), "1200");
+        assert_eq!(exchange(&mut client, "M300,3:abcdef"), "OK");
+        assert_eq!(exchange(&mut client, "m300,3"), "abcdef");
+        // Reads stop at the end of memory, writes past it fail
+        assert_eq!(exchange(&mut client, "mffe,4"), "0000");
+        assert_eq!(exchange(&mut client, "m1000,1"), "E01");
+        assert_eq!(exchange(&mut client, "Mffe,4:01020304"), "E01");
+        assert_eq!(exchange(&mut client, "M300,2:abc"), "E01");
+        end(client, stub);
+    }
+
+    #[test]
+    fn breakpoints_and_watchpoints() {
+        // LD V0, 5; LD I, 0x300; LD [I], V0; LD I, 0x300; LD V0, [I]; LD I, 0x300; DRW V0, V0, 1;
+        // JP 0x20E
+        let (mut client, stub) = session(&[0x6005, 0xA300, 0xF055, 0xA300, 0xF065, 0xA300, 0xD001, 0x120E]);
+        for kind in ["0", "1"].iter() {
+            assert_eq!(exchange(&mut client, &format!("Z{},202,2", kind)), "OK");
+            assert_eq!(exchange(&mut client, "c"), "S05");
+            assert_eq!(exchange(&mut client, "p11"), "0202");
+            assert_eq!(exchange(&mut client, &format!("z{},202,2", kind)), "OK");
+            assert_eq!(exchange(&mut client, "P11=0002"), "OK");
+        }
+        // The write by FX55, the read by FX65, then the sprite read by DRW
+        assert_eq!(exchange(&mut client, "Z2,300,1"), "OK");
+        assert_eq!(exchange(&mut client, "c"), "T05watch:300;");
+        assert_eq!(exchange(&mut client, "p11"), "0602");
+        assert_eq!(exchange(&mut client, "z2,300,1"), "OK");
+        assert_eq!(exchange(&mut client, "Z4,300,1"), "OK");
+        assert_eq!(exchange(&mut client, "c"), "T05awatch:300;");
+        assert_eq!(exchange(&mut client, "p11"), "0a02");
+        assert_eq!(exchange(&mut client, "z4,300,1"), "OK");
+        assert_eq!(exchange(&mut client, "Z3,300,1"), "OK");
+        assert_eq!(exchange(&mut client, "c"), "T05rwatch:300;");
+        assert_eq!(exchange(&mut client, "p11"), "0e02");
+        assert_eq!(exchange(&mut client, "Z2,ffff,2"), "OK");
+        assert_eq!(exchange(&mut client, "Z0,10000,2"), "E01");
+        end(client, stub);
+    }
+
+    #[test]
+    fn target_description() {
+        let (mut client, stub) = session(&[0x1200]);
+        assert!(exchange(&mut client, "qSupported:multiprocess+").contains("qXfer:features:read+"));
+        let xml = target_xml();
+        assert_eq!(exchange(&mut client, "qXfer:features:read:target.xml:0,ffff"), format!("l{}", xml));
+        assert_eq!(exchange(&mut client, "qXfer:features:read:target.xml:0,10"), format!("m{}", &xml[..0x10]));
+        assert_eq!(exchange(&mut client, "qXfer:features:read:target.xml:10,ffff"), format!("l{}", &xml[0x10..]));
+        assert!(xml.contains("name=\"stack15\""));
+        end(client, stub);
+    }
+
+    #[test]
+    fn packet_during_continue() {
+        // JP 0x202; JP 0x200 runs until interrupted
+        let (mut client, stub) = session(&[0x1202, 0x1200]);
+        client.write_all(b"$c#63").unwrap();
+        assert_eq!(read_char(&mut client), b'+');
+        // A packet sent while running is kept and answered after the stop
+        client.write_all(b"$?#3f").unwrap();
+        client.write_all(&[0x03]).unwrap();
+        assert_eq!(reply(&mut client), "S02");
+        assert_eq!(read_char(&mut client), b'+');
+        assert_eq!(reply(&mut client), "S05");
+        end(client, stub);
+    }
+}
@@ -0,0 +1,351 @@
+use std::fmt;
+
+// Just enough JSON for the debug adapter protocol
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),    // Members in the order they were written
+}
+
+static NULL: Json = Json::Null;
+
+// Arrays and objects nested deeper than this are rejected, the parser recurses once per level
+const MAX_DEPTH: usize = 64;
+
+impl Json {
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = Parser { text: text.as_bytes(), pos: 0, depth: 0 };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos < parser.text.len() {
+            return Err(format!("unexpected data at offset {}", parser.pos));
+        }
+        Ok(value)
+    }
+
+    // Builds an object from its members
+    pub fn object(members: Vec<(&str, Json)>) -> Json {
+        Json::Object(members.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+    }
+
+    // The member named key, or null if there is none
+    pub fn get(&self, key: &str) -> &Json {
+        match *self {
+            Json::Object(ref members) => members.iter().find(|(name, _)| name == key).map_or(&NULL, |(_, value)| value),
+            _ => &NULL,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::String(ref text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Json::Number(n) if n.fract() == 0.0 && n.abs() < 9.0e15 => Some(n as i64),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Json::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    // The elements of an array, none for anything else
+    pub fn as_array(&self) -> &[Json] {
+        match *self {
+            Json::Array(ref elements) => elements,
+            _ => &[],
+        }
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Json {
+        Json::Bool(b)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(n: i64) -> Json {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Json {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(text: &str) -> Json {
+        Json::String(text.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(text: String) -> Json {
+        Json::String(text)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(elements: Vec<Json>) -> Json {
+        Json::Array(elements)
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) if n.fract() == 0.0 && n.abs() < 9.0e15 => write!(f, "{}", n as i64),
+            Json::Number(n) if n.is_finite() => write!(f, "{}", n),
+            Json::Number(_) => write!(f, "null"),
+            Json::String(ref text) => write_string(f, text),
+            Json::Array(ref elements) => {
+                write!(f, "[")?;
+                for (i, element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", element)?;
+                }
+                write!(f, "]")
+            },
+            Json::Object(ref members) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, text: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in text.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+    depth: usize,       // Arrays and objects open around pos
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.text.get(self.pos) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.text.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        if self.peek() != Some(byte) {
+            return Err(format!("expected '{}' at offset {}", byte as char, self.pos));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        if !self.text[self.pos..].starts_with(word.as_bytes()) {
+            return Err(format!("unexpected data at offset {}", self.pos));
+        }
+        self.pos += word.len();
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        if let Some(b'{') | Some(b'[') = self.peek() {
+            if self.depth == MAX_DEPTH {
+                return Err(format!("nested too deeply at offset {}", self.pos));
+            }
+            self.depth += 1;
+            let value = self.container();
+            self.depth -= 1;
+            return value;
+        }
+        self.scalar()
+    }
+
+    // Reads an array or object, the opening bracket is at pos
+    fn container(&mut self) -> Result<Json, String> {
+        match self.peek() {
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members = vec![];
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(members));
+                }
+                loop {
+                    if self.peek() != Some(b'"') {
+                        return Err(format!("expected a member name at offset {}", self.pos));
+                    }
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    members.push((key, self.value()?));
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        _ => break,
+                    }
+                }
+                self.expect(b'}')?;
+                Ok(Json::Object(members))
+            },
+            Some(b'[') => {
+                self.pos += 1;
+                let mut elements = vec![];
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(elements));
+                }
+                loop {
+                    elements.push(self.value()?);
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        _ => break,
+                    }
+                }
+                self.expect(b']')?;
+                Ok(Json::Array(elements))
+            },
+            _ => self.scalar(),
+        }
+    }
+
+    fn scalar(&mut self) -> Result<Json, String> {
+        match self.peek() {
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(b'-') | Some(b'0'..=b'9') => {
+                let start = self.pos;
+                while let Some(b'-') | Some(b'+') | Some(b'.') | Some(b'e') | Some(b'E') | Some(b'0'..=b'9') = self.text.get(self.pos) {
+                    self.pos += 1;
+                }
+                let number = std::str::from_utf8(&self.text[start..self.pos]).unwrap_or("");
+                number.parse().map(Json::Number).map_err(|_| format!("bad number {}", number))
+            },
+            Some(_) => Err(format!("unexpected data at offset {}", self.pos)),
+            None => Err("unexpected end of data".to_string()),
+        }
+    }
+
+    // Reads a string, the opening quote is at pos
+    fn string(&mut self) -> Result<String, String> {
+        self.pos += 1;
+        let mut bytes = vec![];
+        loop {
+            let byte = *self.text.get(self.pos).ok_or("unterminated string")?;
+            self.pos += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escape = *self.text.get(self.pos).ok_or("unterminated string")?;
+                    self.pos += 1;
+                    let c = match escape {
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'u' => {
+                            let mut code = self.hex4()?;
+                            // A surrogate pair is two escapes
+                            if (0xD800..0xDC00).contains(&code) && self.text[self.pos..].starts_with(b"\\u") {
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                code = 0x10000 + ((code - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF);
+                            }
+                            char::from_u32(code).unwrap_or('\u{FFFD}')
+                        },
+                        other => other as char,
+                    };
+                    let mut buffer = [0u8; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+                },
+                _ => bytes.push(byte),
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| "string is not UTF-8".to_string())
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self.text.get(self.pos..self.pos + 4).ok_or("unterminated string")?;
+        self.pos += 4;
+        std::str::from_utf8(digits).ok().and_then(|hex| u32::from_str_radix(hex, 16).ok()).ok_or_else(|| "bad \\u escape".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::json::{Json, MAX_DEPTH};
+
+    #[test]
+    fn escapes() {
+        let text = r#""a\"b\\c\/d\n\r\t\b\fAé😀\u0001""#;
+        let value = Json::parse(text).unwrap();
+        assert_eq!(value.as_str(), Some("a\"b\\c/d\n\r\t\u{8}\u{c}A\u{e9}\u{1F600}\u{1}"));
+        // Writing escapes what JSON requires and reads back the same string
+        assert_eq!(value.to_string(), r#""a\"b\\c/d\n\r\t\u0008\u000cAé😀\u0001""#);
+        assert_eq!(Json::parse(&value.to_string()).unwrap(), value);
+        assert!(Json::parse(r#""\u12""#).is_err());
+        assert!(Json::parse(r#""abc"#).is_err());
+    }
+
+    #[test]
+    fn numbers() {
+        let value = Json::parse("[0, -7, 4096, 1.5, -2.5e3, 1E2, 9007199254740993]").unwrap();
+        let numbers: Vec<Option<i64>> = value.as_array().iter().map(Json::as_i64).collect();
+        assert_eq!(numbers, vec![Some(0), Some(-7), Some(4096), None, Some(-2500), Some(100), None]);
+        assert_eq!(value.to_string(), "[0,-7,4096,1.5,-2500,100,9007199254740992]");
+        assert_eq!(Json::from(511usize).to_string(), "511");
+        assert!(Json::parse("-").is_err());
+        assert!(Json::parse("1.2.3").is_err());
+    }
+
+    #[test]
+    fn nesting_depth() {
+        let nested = |depth: usize| "[".repeat(depth) + &"]".repeat(depth);
+        assert!(Json::parse(&nested(MAX_DEPTH)).is_ok());
+        assert!(Json::parse(&nested(MAX_DEPTH + 1)).is_err());
+        assert!(Json::parse(&"{\"a\":".repeat(100_000)).is_err());
+    }
+}
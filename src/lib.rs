@@ -0,0 +1,40 @@
+/* CHIP8 emulator core.
+
+   The machine (CPU, memory, display and ROM loading) lives here so it can be
+   embedded by other crates and tools without pulling in a window. The SDL
+   frontend is the `chip8-emu` binary and is only built with the `sdl` feature.
+*/
+#![allow(clippy::upper_case_acronyms)]
+
+pub mod audio;
+pub mod cpu;
+pub mod dap;
+pub mod debugger;
+pub mod display;
+pub mod gdbstub;
+mod json;
+pub mod machine;
+pub mod movie;
+pub mod quirks;
+pub mod recompiler;
+pub mod rewind;
+pub mod rng;
+pub mod rom;
+pub mod savestate;
+pub mod system;
+pub mod timing;
+
+pub use audio::{AudioPattern, Beeper, Waveform};
+pub use cpu::{CPU, DecodeError, ExecutionError, Opcode, WatchHit, Watchpoint};
+pub use debugger::Debugger;
+pub use display::Display;
+pub use machine::Machine;
+pub use movie::{Movie, MovieError};
+pub use quirks::Quirks;
+pub use recompiler::Backend;
+pub use rewind::Rewind;
+pub use rng::Rng;
+pub use rom::{ROM, ROMError, read_rom};
+pub use savestate::SaveStateError;
+pub use system::{ErrorPolicy, StepOutcome, System};
+pub use timing::Timing;
@@ -0,0 +1,46 @@
+use crate::cpu::{CPU, ExecutionError, VF};
+use crate::display::Display;
+use crate::rng::Rng;
+
+// Everything an instruction can act on: the CPU with its registers, timers, keys and memory,
+// the display and the random number generator. System lends its parts out as a Machine for each
+// instruction.
+pub struct Machine<'a> {
+    pub cpu: &'a mut CPU,
+    pub display: &'a mut Display,
+    pub rng: &'a mut Rng,
+    // Set when the display changed and the screen should be redrawn
+    pub draw_screen: &'a mut bool,
+}
+
+impl<'a> Machine<'a> {
+    // DXYN: draws the n byte sprite at I at (VX, VY) and sets VF on collision. DXY0 is a
+    // SUPER-CHIP 16x16 sprite, 2 bytes per row.
+    pub fn draw(&mut self, vx: u8, vy: u8, n: u8) -> Result<(), ExecutionError> {
+        let mut sprite: Vec<u8> = vec!();
+        let i_reg = self.cpu.i as usize;
+        let sprite_len = if n == 0 { 32 } else { n as usize };
+        // XO-CHIP draws one sprite per selected plane, stored one after the other
+        let sprite_len = sprite_len * self.display.selected_plane_count();
+        for i in 0..sprite_len {
+            sprite.push(self.cpu.get_byte_mem(i_reg + i)?);
+        }
+        let x = self.cpu.vregs[vx as usize];
+        let y = self.cpu.vregs[vy as usize];
+        let clip = self.cpu.quirks.clip_sprites;
+        let collision = if n == 0 {
+            self.display.draw_large_sprite(x, y, sprite, clip)
+        } else {
+            self.display.draw_sprite(x, y, sprite, clip)
+        };
+        self.cpu.vregs[VF] = collision as u8;
+        *self.draw_screen = true;
+        Ok(())
+    }
+
+    // Runs a change to the display and marks the screen for redrawing
+    pub fn update_display<F: FnOnce(&mut Display)>(&mut self, update: F) {
+        update(self.display);
+        *self.draw_screen = true;
+    }
+}
@@ -1,26 +1,25 @@
 /* Emulator for CHIP8 CPU */
 
 use clap::{App, Arg};
+use sdl2::audio::{AudioCallback, AudioSpecDesired};
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::mouse::MouseButton;
 use sdl2::pixels::{PixelFormatEnum, Color};
 use sdl2::rect;
 use std::io::BufWriter;
-use std::path::Path;
-use std::time::{SystemTime, UNIX_EPOCH, Duration};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-mod cpu;
-mod display;
-mod rom;
-mod system;
-use system::System;
+mod debug_panel;
 
-const DESIRED_FPS : u32 = 60;
+use debug_panel::{DebugPanel, PanelButton, PANEL_WIDTH};
+use chip8_emu::{audio, display, quirks, rom, system, timing, AudioPattern, Beeper, Movie, Quirks, Rewind, System, Timing, Waveform};
 
 /* 
     This was our initial prototype of decoding instructions.
     No longer used, cpu::CPU::decode_instr does similar work but decodes into
-    Instruction trait object instead of just returning a string 
+    an Opcode instead of just returning a string 
 
 
 fn decode_instr(instr: u16) -> String {
@@ -225,6 +224,15 @@ fn decode_instr(instr: u16) -> String {
 }
 */
 
+// Colour of a pixel for each combination of the two XO-CHIP planes. Plain CHIP8 only uses
+// the first two.
+const PALETTE: [Color; 4] = [
+    Color::BLACK,
+    Color::RED,
+    Color::RGB(0xFF, 0xAA, 0x00),
+    Color::WHITE,
+];
+
 fn draw_screen(
     canvas: &mut sdl2::render::Canvas<sdl2::video::Window>,
     display: &display::Display,
@@ -237,16 +245,20 @@ fn draw_screen(
     canvas.with_texture_canvas(&mut texture, |texture_canvas| {
         texture_canvas.set_draw_color(Color::BLACK);
         texture_canvas.clear();
-        texture_canvas.set_draw_color(Color::RED);
-        for x in 0..display::SCREEN_WIDTH_PIXELS {
-            for y in 0..display::SCREEN_HEIGHT_PIXELS {
-                if display.mem[y as usize][x as usize] != 0 {
+        // Scale each CHIP8 pixel to fill the window in both the 64x32 and the 128x64 mode
+        let pixel_width = display::REAL_SCREEN_WIDTH_PIXELS as usize / display.width();
+        let pixel_height = display::REAL_SCREEN_HEIGHT_PIXELS as usize / display.height();
+        for x in 0..display.width() {
+            for y in 0..display.height() {
+                let colour = display.mem[y][x] & display::ALL_PLANES;
+                if colour != 0 {
                     //println!("Drawing pixel at ({}, {})", x, y);
+                    texture_canvas.set_draw_color(PALETTE[colour as usize]);
                     texture_canvas.fill_rect(rect::Rect::new(
-                        (x * display::PIXEL_WIDTH) as i32,
-                        (y * display::PIXEL_WIDTH) as i32,
-                        display::PIXEL_WIDTH as u32,
-                        display::PIXEL_HEIGHT as u32,
+                        (x * pixel_width) as i32,
+                        (y * pixel_height) as i32,
+                        pixel_width as u32,
+                        pixel_height as u32,
                     )).expect("could not draw pixel");
                 }
             }
@@ -259,9 +271,99 @@ fn draw_screen(
         dst)?;
     Ok(())
 }
+// Speed controls changed by hotkeys while running
+const SPEED_UP_KEY: Keycode = Keycode::Equals;
+const SPEED_DOWN_KEY: Keycode = Keycode::Minus;
+const FAST_FORWARD_KEY: Keycode = Keycode::Tab;
+const SLOW_MOTION_KEY: Keycode = Keycode::F2;
+const REWIND_KEY: Keycode = Keycode::Backspace;
+
+// Debugger panel: F1 shows or hides it, F3 and F4 do what its pause and step buttons do
+const PANEL_KEY: Keycode = Keycode::F1;
+const PAUSE_KEY: Keycode = Keycode::F3;
+const STEP_KEY: Keycode = Keycode::F4;
+
+// Quick-save slots: the key loads the slot, with shift it saves to it
+const STATE_SLOT_KEYS: [Keycode; 4] = [Keycode::F5, Keycode::F6, Keycode::F7, Keycode::F8];
+
+// Slow motion runs this many times slower than normal
+const SLOW_MOTION_FACTOR: u32 = 4;
+const MAX_CYCLES_PER_FRAME: u32 = 1 << 16;
+
+struct Speed {
+    fast_forward: bool, // Held down: run frames as fast as possible
+    rewinding: bool,    // Held down: step back one snapshot per frame instead of running
+    slow_motion: bool,  // Toggled: every frame takes SLOW_MOTION_FACTOR times longer
+    paused: bool,       // Toggled: no frames run, the debugger panel steps single instructions
+}
+
+impl Speed {
+    fn frame_duration(&self) -> std::time::Duration {
+        let frame = std::time::Duration::new(0, 1_000_000_000u32 / system::FRAMES_PER_SECOND);
+        if self.slow_motion { frame * SLOW_MOTION_FACTOR } else { frame }
+    }
+
+    fn window_title(&self, system: &System) -> String {
+        let mut title = match system.timing {
+            Timing::Fixed => format!("CHIP8 Emulator - {} cycles/frame ({} Hz)",
+                system.cycles_per_frame, system.cycles_per_frame * system::FRAMES_PER_SECOND),
+            Timing::Vip => "CHIP8 Emulator - COSMAC VIP timing".to_string(),
+        };
+        if self.rewinding {
+            title.push_str(" [rewind]");
+        } else if self.paused {
+            title.push_str(" [paused]");
+        } else if self.fast_forward {
+            title.push_str(" [fast forward]");
+        } else if self.slow_motion {
+            title.push_str(&format!(" [slow motion 1/{}]", SLOW_MOTION_FACTOR));
+        }
+        title
+    }
+}
+
+// File that quick-save slot slot of the ROM at rom_filepath is kept in
+fn state_path(rom_filepath: &str, slot: usize) -> PathBuf {
+    PathBuf::from(format!("{}.state{}", rom_filepath, slot + 1))
+}
+
+// Pause toggles running, Step pauses and runs one instruction. Movies advance a frame at a time,
+// so stepping is disabled while one is recorded or played.
+fn press_button(button: PanelButton, speed: &mut Speed, panel: &mut DebugPanel, system: &mut System, movie_active: bool) {
+    match button {
+        PanelButton::Pause => {
+            speed.paused = !speed.paused;
+            panel.set_status(if speed.paused { "paused" } else { "running" });
+        },
+        PanelButton::Step if movie_active => {
+            println!("stepping is disabled while a movie is recorded or played");
+            panel.set_status("no stepping during a movie");
+        },
+        PanelButton::Step => {
+            speed.paused = true;
+            panel.step(system);
+        },
+    }
+}
+
+// SDL audio callback, plays the beeper while the sound timer is running
+struct BeeperCallback {
+    beeper: Beeper,
+    sound_on: bool,
+}
+
+impl AudioCallback for BeeperCallback {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        self.beeper.fill(out, self.sound_on);
+    }
+}
+
 fn main() -> Result<(), String> {
     let sdl2_context = sdl2::init()?;
     let video_subsystem = sdl2_context.video()?;
+    let audio_subsystem = sdl2_context.audio()?;
 
     let window = video_subsystem
         .window(
@@ -291,6 +393,106 @@ fn main() -> Result<(), String> {
                 .long("file")
                 .takes_value(true)
                 .help("filepath to ROM"),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .help("print every executed instruction"),
+        )
+        .arg(
+            Arg::with_name("quirks")
+                .long("quirks")
+                .takes_value(true)
+                .possible_values(&quirks::PRESET_NAMES)
+                .default_value("vip")
+                .help("interpreter whose quirks to emulate"),
+        )
+        .arg(
+            Arg::with_name("key-press-completes")
+                .long("key-press-completes")
+                .help("let FX0A complete when the key is pressed instead of when it is released"),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .takes_value(true)
+                .help("seed for RND, so a run can be reproduced"),
+        )
+        .arg(
+            Arg::with_name("cycles-per-frame")
+                .long("cycles-per-frame")
+                .takes_value(true)
+                .help("instructions run per 60 Hz frame with fixed timing, 10 by default. Change it while running with - and ="),
+        )
+        .arg(
+            Arg::with_name("timing")
+                .long("timing")
+                .takes_value(true)
+                .possible_values(&timing::TIMING_NAMES)
+                .default_value("fixed")
+                .help("fixed runs 10 instructions per frame, vip charges each instruction what it cost on the COSMAC VIP"),
+        )
+        .arg(
+            Arg::with_name("beep-frequency")
+                .long("beep-frequency")
+                .takes_value(true)
+                .default_value("440")
+                .help("pitch of the sound timer tone in Hz"),
+        )
+        .arg(
+            Arg::with_name("waveform")
+                .long("waveform")
+                .takes_value(true)
+                .possible_values(&audio::WAVEFORM_NAMES)
+                .default_value("square")
+                .help("shape of the sound timer tone"),
+        )
+        .arg(
+            Arg::with_name("volume")
+                .long("volume")
+                .takes_value(true)
+                .default_value("25")
+                .help("volume of the sound timer tone, 0 to 100"),
+        )
+        .arg(
+            Arg::with_name("mute")
+                .long("mute")
+                .help("do not play the sound timer tone"),
+        )
+        .arg(
+            Arg::with_name("rewind-interval")
+                .long("rewind-interval")
+                .takes_value(true)
+                .default_value("1")
+                .help("frames between the snapshots that Backspace rewinds through"),
+        )
+        .arg(
+            Arg::with_name("rewind-memory")
+                .long("rewind-memory")
+                .takes_value(true)
+                .default_value("8")
+                .help("megabytes kept for rewind snapshots, the oldest are dropped beyond that"),
+        )
+        .arg(
+            Arg::with_name("record-movie")
+                .long("record-movie")
+                .takes_value(true)
+                .conflicts_with("play-movie")
+                .help("record the keys pressed each frame, with the seed and settings, to this movie file"),
+        )
+        .arg(
+            Arg::with_name("play-movie")
+                .long("play-movie")
+                .takes_value(true)
+                .help("play back the keys recorded in this movie file with its seed and settings"),
+        )
+        .arg(
+            Arg::with_name("on-error")
+                .long("on-error")
+                .takes_value(true)
+                .possible_values(&system::ERROR_POLICY_NAMES)
+                .default_value("halt")
+                .help("what to do when an instruction fails, ignore skips it and carries on"),
         );
 
     app.write_long_help(&mut buf).unwrap();
@@ -299,8 +501,58 @@ fn main() -> Result<(), String> {
     let helpmessage = String::from_utf8(bytes).unwrap();
 
     let rom_filepath = argmatches.value_of("file");
-    let mut system = System::new();
-    if let None = rom_filepath {
+    let state_prefix = rom_filepath.unwrap_or_default().to_string();
+    let preset = argmatches.value_of("quirks").unwrap();
+    let mut quirks = Quirks::from_preset_name(preset).unwrap();
+    quirks.key_wait_completes_on_press = argmatches.is_present("key-press-completes");
+    let memsize = if preset == "xochip" { system::XO_CHIP_MEMSIZE } else { system::MEMSIZE };
+    let mut system = System::with_memory_size(quirks, memsize);
+    system.trace = argmatches.is_present("trace");
+    system.error_policy = system::ErrorPolicy::from_name(argmatches.value_of("on-error").unwrap()).unwrap();
+    if let Some(seed) = argmatches.value_of("seed") {
+        match seed.parse() {
+            Ok(seed) => system.set_seed(seed),
+            Err(_) => {
+                println!("--seed expects a number, got {}", seed);
+                std::process::exit(1);
+            }
+        }
+    }
+    system.timing = Timing::from_name(argmatches.value_of("timing").unwrap()).unwrap();
+    if let Some(cycles) = argmatches.value_of("cycles-per-frame") {
+        match cycles.parse::<u32>() {
+            Ok(cycles) => system.cycles_per_frame = cycles.clamp(1, MAX_CYCLES_PER_FRAME),
+            Err(_) => {
+                println!("--cycles-per-frame expects a number, got {}", cycles);
+                std::process::exit(1);
+            }
+        }
+    }
+    let rewind_interval = argmatches.value_of("rewind-interval").unwrap().parse::<u32>();
+    let rewind_memory = argmatches.value_of("rewind-memory").unwrap().parse::<usize>();
+    let mut rewind = match (rewind_interval, rewind_memory) {
+        (Ok(interval), Ok(megabytes)) => Rewind::new(interval, megabytes * 1024 * 1024),
+        _ => {
+            println!("--rewind-interval and --rewind-memory expect numbers");
+            std::process::exit(1);
+        }
+    };
+    // Playing a movie replaces the settings above with the recorded ones
+    let mut playback = argmatches.value_of("play-movie").map(|path| {
+        Movie::load(path).unwrap_or_else(|err| {
+            println!("could not load movie {}: {}", path, err);
+            std::process::exit(1);
+        })
+    });
+    if let Some(ref movie) = playback {
+        let trace = system.trace;
+        system = movie.new_system();
+        system.trace = trace;
+        println!("playing movie of {} frames", movie.frames.len());
+    }
+    println!("random seed: {}", system.seed());
+    let mut recording: Option<Movie> = None;
+    if rom_filepath.is_none() {
         println!("{}", helpmessage);
         std::process::exit(1);
     } else if let Some(filepath) = rom_filepath {
@@ -311,11 +563,49 @@ fn main() -> Result<(), String> {
             println!("read rom successfully");
             println!("rom size is {}", rom.size());
             system.dump_rom(&rom);
-            system.load_rom(&rom);
+            if let Err(err) = system.load_rom(&rom) {
+                println!("err: {}", err);
+                std::process::exit(1);
+            }
+            if let Some(ref movie) = playback {
+                if !movie.matches_rom(&rom) {
+                    println!("warning: the movie was recorded with a different ROM");
+                }
+            }
+            if argmatches.is_present("record-movie") {
+                recording = Some(Movie::new(&system, &rom));
+            }
         }
     }
+    let frequency = argmatches.value_of("beep-frequency").unwrap().parse::<f32>();
+    let volume = argmatches.value_of("volume").unwrap().parse::<f32>();
+    let (frequency, volume) = match (frequency, volume) {
+        (Ok(frequency), Ok(volume)) if frequency > 0.0 => (frequency, volume.clamp(0.0, 100.0) / 100.0),
+        _ => {
+            println!("--beep-frequency and --volume expect numbers");
+            std::process::exit(1);
+        }
+    };
+    let waveform = Waveform::from_name(argmatches.value_of("waveform").unwrap()).unwrap();
+    let muted = argmatches.is_present("mute");
+    let audio_spec = AudioSpecDesired { freq: Some(audio::SAMPLE_RATE as i32), channels: Some(1), samples: None };
+    let mut audio_device = audio_subsystem.open_playback(None, &audio_spec, |spec| {
+        let mut beeper = Beeper::new(spec.freq as u32);
+        beeper.frequency = frequency;
+        beeper.waveform = waveform;
+        beeper.volume = volume;
+        beeper.muted = muted;
+        BeeperCallback { beeper, sound_on: false }
+    })?;
+    audio_device.resume();
+
     canvas.set_draw_color(Color::BLACK);
     canvas.clear();
+    let mut movie_frame = 0;
+    let mut speed = Speed { fast_forward: false, rewinding: false, slow_motion: false, paused: false };
+    let mut panel = DebugPanel::default();
+    let mut title = speed.window_title(&system);
+    canvas.window_mut().set_title(&title).map_err(|e| e.to_string())?;
     let mut previous_time : std::time::Duration = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
     'running: loop {
         for event in event_pump.poll_iter() {
@@ -327,6 +617,70 @@ fn main() -> Result<(), String> {
                 } => {
                     break 'running;
                 },
+                Event::KeyDown { keycode: Some(SPEED_UP_KEY), .. } => {
+                    system.cycles_per_frame = (system.cycles_per_frame * 2).min(MAX_CYCLES_PER_FRAME);
+                },
+                Event::KeyDown { keycode: Some(SPEED_DOWN_KEY), .. } => {
+                    system.cycles_per_frame = (system.cycles_per_frame / 2).max(1);
+                },
+                Event::KeyDown { keycode: Some(FAST_FORWARD_KEY), .. } => {
+                    speed.fast_forward = true;
+                },
+                Event::KeyUp { keycode: Some(FAST_FORWARD_KEY), .. } => {
+                    speed.fast_forward = false;
+                },
+                Event::KeyDown { keycode: Some(REWIND_KEY), repeat, .. } => {
+                    if recording.is_some() || playback.is_some() {
+                        if !repeat {
+                            println!("rewind is disabled while a movie is recorded or played");
+                        }
+                    } else {
+                        speed.rewinding = true;
+                    }
+                },
+                Event::KeyUp { keycode: Some(REWIND_KEY), .. } => {
+                    speed.rewinding = false;
+                },
+                Event::KeyDown { keycode: Some(SLOW_MOTION_KEY), repeat: false, .. } => {
+                    speed.slow_motion = !speed.slow_motion;
+                },
+                Event::KeyDown { keycode: Some(PANEL_KEY), repeat: false, .. } => {
+                    panel.visible = !panel.visible;
+                    let width = display::REAL_SCREEN_WIDTH_PIXELS as u32 + if panel.visible { PANEL_WIDTH } else { 0 };
+                    canvas.window_mut().set_size(width, display::REAL_SCREEN_HEIGHT_PIXELS as u32).map_err(|e| e.to_string())?;
+                },
+                Event::KeyDown { keycode: Some(PAUSE_KEY), repeat: false, .. } => {
+                    let movie_active = recording.is_some() || playback.is_some();
+                    press_button(PanelButton::Pause, &mut speed, &mut panel, &mut system, movie_active);
+                },
+                Event::KeyDown { keycode: Some(STEP_KEY), .. } => {
+                    let movie_active = recording.is_some() || playback.is_some();
+                    press_button(PanelButton::Step, &mut speed, &mut panel, &mut system, movie_active);
+                },
+                Event::MouseButtonDown { mouse_btn: MouseButton::Left, x, y, .. } if panel.visible => {
+                    if let Some(button) = panel.button_at(x, y) {
+                        let movie_active = recording.is_some() || playback.is_some();
+                        press_button(button, &mut speed, &mut panel, &mut system, movie_active);
+                    }
+                },
+                Event::KeyDown { keycode: Some(keypress), keymod, repeat: false, .. }
+                    if STATE_SLOT_KEYS.contains(&keypress) => {
+                    let slot = STATE_SLOT_KEYS.iter().position(|key| *key == keypress).unwrap();
+                    let path = state_path(&state_prefix, slot);
+                    if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                        match system.save_state_to_file(&path) {
+                            Ok(()) => println!("saved state to {}", path.display()),
+                            Err(err) => println!("could not save state to {}: {}", path.display(), err),
+                        }
+                    } else if recording.is_some() || playback.is_some() {
+                        println!("loading states is disabled while a movie is recorded or played");
+                    } else {
+                        match system.load_state_from_file(&path) {
+                            Ok(()) => println!("loaded state from {}", path.display()),
+                            Err(err) => println!("could not load state from {}: {}", path.display(), err),
+                        }
+                    }
+                },
                 Event::KeyDown { 
                     keycode: Some(keypress),
                     ..
@@ -343,6 +697,7 @@ fn main() -> Result<(), String> {
                         Keycode::R => curr_keypress = Some(0xD),
                         Keycode::A => curr_keypress = Some(7),
                         Keycode::S => curr_keypress = Some(8),
+                        Keycode::D => curr_keypress = Some(9),
                         Keycode::F => curr_keypress = Some(0xE),
                         Keycode::Z => curr_keypress = Some(0xA),
                         Keycode::X => curr_keypress = Some(0),
@@ -391,22 +746,51 @@ fn main() -> Result<(), String> {
         let since_the_epoch = start
         .duration_since(UNIX_EPOCH).unwrap();
         let delta = since_the_epoch - previous_time;
-        println!("frame: {:?}", delta);
+        if system.trace {
+            println!("frame: {:?}", delta);
+        }
         previous_time = since_the_epoch;
 
-         // Since we are running at 60 fps and the CHIP-8 runs at 500 Hz 500. 
-         // We're gonna run 10 instructions for each frame. Compute
-         // the time spent per instruction, primarily to support DT/ST instruct-
-         // ions.
-        const INSTR_PER_FRAME : f32 = 10.0;
-        let time_per_frame_in_micro =  1.0/(DESIRED_FPS as f32) * 1_000_000.0;
-        println!("time_per_frame in microsec: {}", time_per_frame_in_micro);
-        let time_per_instr = std::time::Duration::from_micros((time_per_frame_in_micro/INSTR_PER_FRAME) as u64);
+        let new_title = speed.window_title(&system);
+        if new_title != title {
+            title = new_title;
+            canvas.window_mut().set_title(&title).map_err(|e| e.to_string())?;
+        }
 
+        // Fast forward runs as many frames as fit in the time of one displayed frame
+        let frame_duration = speed.frame_duration();
+        loop {
+            if speed.rewinding {
+                if let Err(err) = rewind.step_back(&mut system) {
+                    println!("could not rewind: {}", err);
+                }
+            } else if !speed.paused {
+                if let Some(ref movie) = playback {
+                    if !movie.play_frame(movie_frame, &mut system) {
+                        println!("movie finished, keyboard input is back");
+                        system.cpu.curr_keys = [None; 16];
+                        playback = None;
+                    }
+                }
+                if let Some(ref mut movie) = recording {
+                    movie.record_frame(&system);
+                }
+                movie_frame += 1;
+                if let Err(err) = system.run_frame() {
+                    println!("Error executing instruction: {}", err);
+                    break;
+                }
+                rewind.record(&system);
+            }
+            if !speed.fast_forward || start.elapsed().unwrap_or_default() >= frame_duration {
+                break;
+            }
+        }
 
-       
-        for _i in 0..INSTR_PER_FRAME as u32 {
-            system.run_tick(time_per_instr);
+        {
+            let mut callback = audio_device.lock();
+            callback.sound_on = system.cpu.st > 0 && !speed.paused;
+            callback.beeper.pattern = AudioPattern::from_system(&system);
         }
 
         // Draw screen
@@ -414,11 +798,21 @@ fn main() -> Result<(), String> {
             draw_screen(&mut canvas, &system.display).expect("couldn't draw screen");
             system.draw_screen = false;
         //}
+        if panel.visible {
+            panel.draw(&mut canvas, &system, speed.paused)?;
+        }
         canvas.present();
 
         // Display time
-       
-        std::thread::sleep(std::time::Duration::new(0, 1_000_000_000u32 / DESIRED_FPS));
+        if !speed.fast_forward {
+            std::thread::sleep(frame_duration);
+        }
+    }
+    if let (Some(movie), Some(path)) = (recording, argmatches.value_of("record-movie")) {
+        match movie.save(path) {
+            Ok(()) => println!("recorded {} frames to {}", movie.frames.len(), path),
+            Err(err) => println!("could not save movie to {}: {}", path, err),
+        }
     }
     Ok(())
 }
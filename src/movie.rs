@@ -0,0 +1,283 @@
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::quirks::Quirks;
+use crate::rom::ROM;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+use crate::system::{ErrorPolicy, System};
+use crate::timing::Timing;
+
+// Movies start with MOVIE_MAGIC and the format version, then the settings and one record per
+// frame, all little endian
+pub const MOVIE_MAGIC: &[u8; 8] = b"CHIP8MOV";
+pub const MOVIE_VERSION: u16 = 1;
+
+#[derive(Debug)]
+pub enum MovieError {
+    IOError(io::Error),
+    // Not a movie
+    BadMagic,
+    // Written by a different version of the format
+    UnsupportedVersion(u16),
+    // Ends before all the frames were read
+    Truncated,
+    // A field holds a value that cannot occur
+    Invalid(&'static str),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MovieError::IOError(ref err) =>
+                write!(f, "could not read or write movie: {}", err),
+            MovieError::BadMagic =>
+                write!(f, "not a movie"),
+            MovieError::UnsupportedVersion(version) =>
+                write!(f, "unsupported movie version {}, expected {}", version, MOVIE_VERSION),
+            MovieError::Truncated =>
+                write!(f, "movie is truncated"),
+            MovieError::Invalid(field) =>
+                write!(f, "movie has an invalid {}", field),
+        }
+    }
+}
+
+impl error::Error for MovieError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            MovieError::IOError(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MovieError {
+    fn from(err: io::Error) -> MovieError {
+        MovieError::IOError(err)
+    }
+}
+
+// The movie is read with the save state reader, whose errors mean the same for a movie
+impl From<SaveStateError> for MovieError {
+    fn from(err: SaveStateError) -> MovieError {
+        match err {
+            SaveStateError::IOError(err) => MovieError::IOError(err),
+            SaveStateError::BadMagic => MovieError::BadMagic,
+            SaveStateError::UnsupportedVersion(version) => MovieError::UnsupportedVersion(version),
+            SaveStateError::Truncated => MovieError::Truncated,
+            SaveStateError::MemorySizeMismatch { .. } => MovieError::Invalid("memory size"),
+            SaveStateError::Invalid(field) => MovieError::Invalid(field),
+        }
+    }
+}
+
+// Input for one emulated frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MovieFrame {
+    pub keys: [Option<bool>; 16],   // CPU::curr_keys while the frame ran
+    pub cycles_per_frame: u32,      // Can change mid-run through the speed hotkeys
+}
+
+// Keypad input recorded frame by frame from power on, with everything else that decides how
+// the ROM runs. Playing it back into a system built by new_system repeats the run exactly.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Movie {
+    pub seed: u64,
+    pub memsize: usize,
+    pub quirks: Quirks,
+    pub timing: Timing,
+    pub error_policy: ErrorPolicy,
+    pub rom_checksum: u32,          // Of the ROM the movie was recorded with, see rom_checksum()
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    // Starts recording a system that has just had rom loaded
+    pub fn new(system: &System, rom: &ROM) -> Movie {
+        Movie {
+            seed: system.seed(),
+            memsize: system.cpu.memory.borrow().len(),
+            quirks: system.cpu.quirks,
+            timing: system.timing,
+            error_policy: system.error_policy,
+            rom_checksum: rom_checksum(rom),
+            frames: vec![],
+        }
+    }
+
+    // Records the input for the frame system is about to run
+    pub fn record_frame(&mut self, system: &System) {
+        self.frames.push(MovieFrame { keys: system.cpu.curr_keys, cycles_per_frame: system.cycles_per_frame });
+    }
+
+    // Feeds the input of the given frame to system before it runs the frame. Returns false past
+    // the end of the movie, leaving the keys as they were.
+    pub fn play_frame(&self, frame: usize, system: &mut System) -> bool {
+        match self.frames.get(frame) {
+            Some(input) => {
+                system.cpu.curr_keys = input.keys;
+                system.cycles_per_frame = input.cycles_per_frame;
+                true
+            },
+            None => false,
+        }
+    }
+
+    // A freshly powered on system with the recorded settings, ready for the ROM to be loaded
+    pub fn new_system(&self) -> System {
+        let mut system = System::with_memory_size(self.quirks, self.memsize);
+        system.set_seed(self.seed);
+        system.timing = self.timing;
+        system.error_policy = self.error_policy;
+        if let Some(first) = self.frames.first() {
+            system.cycles_per_frame = first.cycles_per_frame;
+        }
+        system
+    }
+
+    // Whether the movie was recorded with rom
+    pub fn matches_rom(&self, rom: &ROM) -> bool {
+        self.rom_checksum == rom_checksum(rom)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = StateWriter::with_header(MOVIE_MAGIC, MOVIE_VERSION);
+        out.u64(self.seed);
+        out.u32(self.memsize as u32);
+        out.bool(self.quirks.shift_uses_vy);
+        out.bool(self.quirks.load_store_increments_i);
+        out.bool(self.quirks.logic_resets_vf);
+        out.bool(self.quirks.jump_uses_vx);
+        out.bool(self.quirks.clip_sprites);
+        out.bool(self.quirks.key_wait_completes_on_press);
+        out.u8(match self.timing {
+            Timing::Fixed => 0,
+            Timing::Vip => 1,
+        });
+        out.u8(match self.error_policy {
+            ErrorPolicy::Halt => 0,
+            ErrorPolicy::Ignore => 1,
+            ErrorPolicy::Trap => 2,
+        });
+        out.u32(self.rom_checksum);
+        out.u32(self.frames.len() as u32);
+        for frame in self.frames.iter() {
+            for key in frame.keys.iter() {
+                out.key(*key);
+            }
+            out.u32(frame.cycles_per_frame);
+        }
+        out.data
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Movie, MovieError> {
+        let mut input = StateReader::with_header(data, MOVIE_MAGIC, MOVIE_VERSION)?;
+        let seed = input.u64()?;
+        let memsize = input.u32()? as usize;
+        let quirks = Quirks {
+            shift_uses_vy: input.bool()?,
+            load_store_increments_i: input.bool()?,
+            logic_resets_vf: input.bool()?,
+            jump_uses_vx: input.bool()?,
+            clip_sprites: input.bool()?,
+            key_wait_completes_on_press: input.bool()?,
+        };
+        let timing = match input.u8()? {
+            0 => Timing::Fixed,
+            1 => Timing::Vip,
+            _ => return Err(MovieError::Invalid("timing")),
+        };
+        let error_policy = match input.u8()? {
+            0 => ErrorPolicy::Halt,
+            1 => ErrorPolicy::Ignore,
+            2 => ErrorPolicy::Trap,
+            _ => return Err(MovieError::Invalid("error policy")),
+        };
+        let rom_checksum = input.u32()?;
+        let frame_count = input.u32()? as usize;
+        let mut frames = Vec::with_capacity(frame_count.min(data.len()));
+        for _ in 0..frame_count {
+            let mut keys = [None; 16];
+            for key in keys.iter_mut() {
+                *key = input.key()?;
+            }
+            let cycles_per_frame = input.u32()?;
+            frames.push(MovieFrame { keys, cycles_per_frame });
+        }
+        input.finish()?;
+        Ok(Movie { seed, memsize, quirks, timing, error_policy, rom_checksum, frames })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), MovieError> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Movie, MovieError> {
+        let data = fs::read(path)?;
+        Movie::from_bytes(&data)
+    }
+}
+
+// FNV-1a hash of the ROM, to warn when a movie is played back with a different ROM
+pub fn rom_checksum(rom: &ROM) -> u32 {
+    let mut hash: u32 = 0x811C_9DC5;
+    for byte in rom.data().iter() {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::movie::{Movie, MovieError};
+    use crate::quirks::Quirks;
+    use crate::rom::ROM;
+    use crate::system::{ErrorPolicy, System, XO_CHIP_MEMSIZE};
+    use crate::timing::Timing;
+
+    #[test]
+    fn write_read_and_replay() {
+        // LD V0, K; ADD V1, V0; RND V2, 0xFF; JP 0x200
+        let rom = ROM::new(vec![0xF0, 0x0A, 0x81, 0x04, 0xC2, 0xFF, 0x12, 0x00]);
+        let mut system = System::with_memory_size(Quirks::xo_chip(), XO_CHIP_MEMSIZE);
+        system.set_seed(0x1234_5678_9ABC_DEF0);
+        system.timing = Timing::Vip;
+        system.error_policy = ErrorPolicy::Ignore;
+        system.load_rom(&rom).unwrap();
+
+        let mut movie = Movie::new(&system, &rom);
+        for frame in 0..30 {
+            system.cpu.curr_keys = [None; 16];
+            if frame % 4 < 2 {
+                system.cpu.curr_keys[frame % 16] = Some(true);
+            }
+            system.cycles_per_frame = 5 + frame as u32;
+            movie.record_frame(&system);
+            system.run_frame().unwrap();
+        }
+
+        let read = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        assert_eq!(read, movie);
+        assert!(read.matches_rom(&rom));
+
+        let mut replay = read.new_system();
+        replay.load_rom(&rom).unwrap();
+        let mut frame = 0;
+        while read.play_frame(frame, &mut replay) {
+            replay.run_frame().unwrap();
+            frame += 1;
+        }
+        assert_eq!(replay.save_state(), system.save_state());
+
+        let bytes = movie.to_bytes();
+        match Movie::from_bytes(&bytes[..bytes.len() - 1]) {
+            Err(MovieError::Truncated) => (),
+            other => panic!("truncated movie read: {:?}", other),
+        }
+    }
+}
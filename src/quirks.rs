@@ -0,0 +1,156 @@
+// Behaviours that differ between CHIP8 interpreters. ROMs are usually written against one
+// platform, so the presets below match what each of them did.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quirks {
+    // 8XY6/8XYE shift VY and store the result in VX, instead of shifting VX in place
+    pub shift_uses_vy: bool,
+    // FX55/FX65 leave I pointing one past the last register stored or loaded
+    pub load_store_increments_i: bool,
+    // 8XY1/8XY2/8XY3 reset VF to 0
+    pub logic_resets_vf: bool,
+    // BNNN is treated as BXNN and jumps to XNN + VX instead of NNN + V0
+    pub jump_uses_vx: bool,
+    // Sprites are clipped at the screen edges instead of wrapping around
+    pub clip_sprites: bool,
+    // FX0A completes as soon as a key is pressed instead of waiting for it to be released
+    pub key_wait_completes_on_press: bool,
+}
+
+pub const PRESET_NAMES: [&str; 4] = ["vip", "chip48", "schip", "xochip"];
+
+impl Quirks {
+    // The original COSMAC VIP interpreter
+    pub fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            logic_resets_vf: true,
+            jump_uses_vx: false,
+            clip_sprites: true,
+            key_wait_completes_on_press: false,
+        }
+    }
+
+    // CHIP-48 on the HP-48 calculators
+    pub fn chip48() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            logic_resets_vf: false,
+            jump_uses_vx: true,
+            clip_sprites: true,
+            key_wait_completes_on_press: false,
+        }
+    }
+
+    // SUPER-CHIP 1.1
+    pub fn schip() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            logic_resets_vf: false,
+            jump_uses_vx: true,
+            clip_sprites: true,
+            key_wait_completes_on_press: false,
+        }
+    }
+
+    // XO-CHIP as implemented by Octo
+    pub fn xo_chip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            logic_resets_vf: false,
+            jump_uses_vx: false,
+            clip_sprites: false,
+            key_wait_completes_on_press: false,
+        }
+    }
+
+    // Looks up a preset by one of the names in PRESET_NAMES
+    pub fn from_preset_name(name: &str) -> Option<Quirks> {
+        match name {
+            "vip" => Some(Quirks::cosmac_vip()),
+            "chip48" => Some(Quirks::chip48()),
+            "schip" => Some(Quirks::schip()),
+            "xochip" => Some(Quirks::xo_chip()),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks::cosmac_vip()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::quirks::{Quirks, PRESET_NAMES};
+    use crate::rom::ROM;
+    use crate::system::System;
+
+    fn run(quirks: Quirks, program: &[u16]) -> System {
+        let mut system = System::with_quirks(quirks);
+        system.set_seed(0);
+        system.load_rom(&ROM::new(program.iter().flat_map(|word| word.to_be_bytes().to_vec()).collect())).unwrap();
+        for _ in 0..program.len() {
+            system.step().unwrap();
+        }
+        system
+    }
+
+    // Runs program under every preset, in the order of PRESET_NAMES, and checks what it left behind
+    fn check<T: PartialEq + std::fmt::Debug>(program: &[u16], result: fn(&System) -> T, expected: [T; 4]) {
+        for (name, expected) in PRESET_NAMES.iter().zip(expected.iter()) {
+            let system = run(Quirks::from_preset_name(name).unwrap(), program);
+            assert_eq!(&result(&system), expected, "{} quirks", name);
+        }
+    }
+
+    #[test]
+    fn default_is_vip() {
+        assert_eq!(Quirks::default(), Quirks::cosmac_vip());
+    }
+
+    #[test]
+    fn shifts() {
+        // LD V1, 0x81; LD V2, 0x02; SHR V1, V2
+        check(&[0x6181, 0x6202, 0x8126], |system| (system.cpu.vregs[1], system.cpu.vregs[0xF]),
+              [(0x01, 0), (0x40, 1), (0x40, 1), (0x01, 0)]);
+        // LD V1, 0x81; LD V2, 0x40; SHL V1, V2
+        check(&[0x6181, 0x6240, 0x812E], |system| (system.cpu.vregs[1], system.cpu.vregs[0xF]),
+              [(0x80, 0), (0x02, 1), (0x02, 1), (0x80, 0)]);
+    }
+
+    #[test]
+    fn load_store_i() {
+        // LD I, 0x300; LD [I], V2
+        check(&[0xA300, 0xF255], |system| system.cpu.i, [0x303, 0x300, 0x300, 0x303]);
+        // LD I, 0x300; LD V2, [I]
+        check(&[0xA300, 0xF265], |system| system.cpu.i, [0x303, 0x300, 0x300, 0x303]);
+    }
+
+    #[test]
+    fn logic_vf() {
+        // LD VF, 1; OR V1, V2 / AND V1, V2 / XOR V1, V2
+        for op in [0x8121, 0x8122, 0x8123].iter() {
+            check(&[0x6F01, *op], |system| system.cpu.vregs[0xF], [0, 1, 1, 1]);
+        }
+    }
+
+    #[test]
+    fn jump_with_offset() {
+        // LD V0, 2; LD V1, 4; JP V0, 0x104 (BXNN: JP V1, 0x104)
+        check(&[0x6002, 0x6104, 0xB104], |system| system.cpu.pc, [0x106, 0x108, 0x108, 0x106]);
+    }
+
+    #[test]
+    fn sprite_clipping() {
+        // LD I, 0 (the font's 0); LD V0, 62; LD V1, 30; DRW V0, V1, 5. The sprite runs off the
+        // right and bottom edges.
+        check(&[0xA000, 0x603E, 0x611E, 0xD015], |system| (system.display.mem[30][0], system.display.mem[0][62]),
+              [(0, 0), (0, 0), (0, 0), (1, 1)]);
+    }
+}
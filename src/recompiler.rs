@@ -0,0 +1,308 @@
+use std::fmt;
+
+use crate::cpu::{CPU, ExecutionError, Opcode, VF};
+use crate::machine::Machine;
+
+// Longest run of instructions translated into a single block
+pub const MAX_BLOCK_LEN: usize = 64;
+
+// Which execution engine System::run_ticks uses
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    // Decode and execute one instruction at a time
+    Interpreter,
+    // Run cached, translated basic blocks and fall back to the interpreter for the
+    // instruction that ends each block
+    Recompiler,
+}
+
+pub const BACKEND_NAMES: [&str; 2] = ["interpreter", "recompiler"];
+
+impl Backend {
+    pub fn from_name(name: &str) -> Option<Backend> {
+        match name {
+            "interpreter" => Some(Backend::Interpreter),
+            "recompiler" => Some(Backend::Recompiler),
+            _ => None,
+        }
+    }
+}
+
+type CompiledInstr = Box<dyn Fn(&mut Machine) -> Result<(), ExecutionError>>;
+
+// A straight-line run of instructions starting at `start`. Each one only touches registers
+// and memory and always falls through to the next one, so running the first n of them and
+// setting pc to next_pc[n - 1] is the same as stepping through them in the interpreter.
+//
+// A block ends before the first jump, call, return, skip, DRW or display instruction, before
+// anything that reads or writes DT/ST or waits for a key, and before anything that cannot be
+// decoded. Those are left to System::step. A block also ends right after an instruction that
+// writes memory, so that code it overwrites is invalidated before it runs.
+pub struct Block {
+    pub start: u16,
+    // One past the last byte translated into this block
+    pub end: u16,
+    instrs: Vec<CompiledInstr>,
+    next_pc: Vec<u16>,
+}
+
+impl Block {
+    pub fn len(&self) -> usize {
+        self.instrs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instrs.is_empty()
+    }
+
+    // Address of the i-th instruction of the block
+    fn pc_of(&self, i: usize) -> u16 {
+        if i == 0 { self.start } else { self.next_pc[i - 1] }
+    }
+
+    // Runs the first n instructions of the block and leaves pc after the last one. Stops at an
+    // instruction that fails, leaving pc on it, and returns how many instructions completed.
+    // Failing instructions leave the machine untouched, so stepping the interpreter from there
+    // raises the same error.
+    pub fn run(&self, machine: &mut Machine, n: usize) -> usize {
+        for (i, instr) in self.instrs.iter().take(n).enumerate() {
+            if instr(machine).is_err() {
+                machine.cpu.pc = self.pc_of(i);
+                return i;
+            }
+        }
+        if n > 0 {
+            machine.cpu.pc = self.next_pc[n - 1];
+        }
+        n
+    }
+}
+
+// How an opcode is handled when building a block
+enum Translation {
+    // Falls through to the next instruction
+    Straight(CompiledInstr),
+    // Falls through, but the block has to end after it because it writes memory
+    Last(CompiledInstr),
+    // Has to go through the interpreter, the block ends before it
+    Interpret,
+}
+
+fn translate(instr: Opcode) -> Translation {
+    let compiled: CompiledInstr = match instr {
+        Opcode::Sys { .. } => Box::new(|_machine: &mut Machine| Ok(())),
+        Opcode::LdVal { vx, value } => Box::new(move |machine: &mut Machine| {
+            let cpu = &mut *machine.cpu;
+            cpu.vregs[vx as usize] = value;
+            Ok(())
+        }),
+        Opcode::AddImmediate { vx, value } => Box::new(move |machine: &mut Machine| {
+            let cpu = &mut *machine.cpu;
+            cpu.vregs[vx as usize] = cpu.vregs[vx as usize].wrapping_add(value);
+            Ok(())
+        }),
+        Opcode::LdReg { vx, vy } => Box::new(move |machine: &mut Machine| {
+            let cpu = &mut *machine.cpu;
+            cpu.vregs[vx as usize] = cpu.vregs[vy as usize];
+            Ok(())
+        }),
+        Opcode::Add { vx, vy } => Box::new(move |machine: &mut Machine| {
+            let cpu = &mut *machine.cpu;
+            let (add_result, carry) = cpu.vregs[vx as usize].overflowing_add(cpu.vregs[vy as usize]);
+            cpu.vregs[vx as usize] = add_result;
+            cpu.vregs[VF] = carry as u8;
+            Ok(())
+        }),
+        Opcode::LdI { addr } | Opcode::LdILong { addr } => Box::new(move |machine: &mut Machine| {
+            let cpu = &mut *machine.cpu;
+            cpu.i = addr;
+            Ok(())
+        }),
+        // The rest share the interpreter's implementation, including the quirks it consults
+        Opcode::Or { .. } | Opcode::And { .. } | Opcode::Xor { .. } | Opcode::Sub { .. }
+        | Opcode::Shr { .. } | Opcode::Subn { .. } | Opcode::Shl { .. } | Opcode::Rnd { .. }
+        | Opcode::AddI { .. } | Opcode::StoreSprite { .. } | Opcode::LdVxsFromI { .. }
+        | Opcode::LdHf { .. } | Opcode::StoreRpl { .. } | Opcode::LdRpl { .. }
+        | Opcode::LdRange { .. } | Opcode::Audio | Opcode::Pitch { .. } => {
+            Box::new(move |machine: &mut Machine| instr.execute(machine))
+        },
+        Opcode::StoreBcd { .. } | Opcode::StoreVxsIntoI { .. } | Opcode::StoreRange { .. } => {
+            return Translation::Last(Box::new(move |machine: &mut Machine| instr.execute(machine)));
+        },
+        _ => return Translation::Interpret,
+    };
+    Translation::Straight(compiled)
+}
+
+// Translates basic blocks on first use and keeps them until the memory they were built from
+// is written to.
+#[derive(Default)]
+pub struct Recompiler {
+    // Indexed by start address, grown on demand up to the memory size
+    blocks: Vec<Option<Block>>,
+}
+
+impl Recompiler {
+    pub fn new() -> Recompiler {
+        Recompiler { blocks: vec![] }
+    }
+
+    // Returns the block starting at addr, translating it if needed. Blocks overlapping memory
+    // written since the last call are dropped first.
+    pub fn block_at(&mut self, cpu: &CPU, addr: u16) -> &Block {
+        if let Some((start, end)) = cpu.take_code_writes() {
+            self.invalidate(start, end);
+        }
+        let index = addr as usize;
+        if index >= self.blocks.len() {
+            self.blocks.resize_with(index + 1, || None);
+        }
+        self.blocks[index].get_or_insert_with(|| Recompiler::compile(cpu, addr))
+    }
+
+    // Drops every block that covers a byte in start..=end
+    pub fn invalidate(&mut self, start: usize, end: usize) {
+        // A block holds at most MAX_BLOCK_LEN instructions of up to 4 bytes, so only blocks
+        // starting that far before the write can reach it
+        if self.blocks.is_empty() {
+            return;
+        }
+        let first = start.saturating_sub(MAX_BLOCK_LEN * 4);
+        let last = end.min(self.blocks.len() - 1);
+        if first > last {
+            return;
+        }
+        for entry in self.blocks[first..=last].iter_mut() {
+            if let Some(block) = entry {
+                if (block.end as usize) > start && (block.start as usize) <= end {
+                    *entry = None;
+                }
+            }
+        }
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.blocks.iter().filter(|block| block.is_some()).count()
+    }
+
+    fn compile(cpu: &CPU, start: u16) -> Block {
+        let memsize = cpu.memory.borrow().len();
+        let mut block = Block { start, end: start, instrs: vec![], next_pc: vec![] };
+        let mut addr = start as usize;
+        while block.len() < MAX_BLOCK_LEN && addr + 1 < memsize {
+            let instr = match cpu.decode_at(addr) {
+                Ok(instr) => instr,
+                Err(_) => break,
+            };
+            // The interpreter runs an instruction that ends at the top of memory, where pc wraps
+            // and the block's end would not fit in a u16
+            if addr + instr.size() as usize > u16::MAX as usize {
+                break;
+            }
+            let (compiled, last) = match translate(instr) {
+                Translation::Straight(compiled) => (compiled, false),
+                Translation::Last(compiled) => (compiled, true),
+                Translation::Interpret => break,
+            };
+            addr += instr.size() as usize;
+            block.instrs.push(compiled);
+            block.next_pc.push(addr as u16);
+            block.end = addr as u16;
+            if last {
+                break;
+            }
+        }
+        block
+    }
+}
+
+impl fmt::Debug for Recompiler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Recompiler {{ blocks: {} }}", self.block_count())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::recompiler::{Backend, Recompiler, MAX_BLOCK_LEN};
+    use crate::rom::ROM;
+    use crate::system::System;
+
+    // Runs program with both backends and checks they end in the same state
+    fn run_both(program: &[u16], cycles: u64) -> System {
+        let data = program.iter().flat_map(|word| word.to_be_bytes().to_vec()).collect();
+        let rom = ROM::new(data);
+        let mut systems = vec![];
+        for &backend in [Backend::Interpreter, Backend::Recompiler].iter() {
+            let mut system = System::new();
+            system.set_seed(0);
+            system.backend = backend;
+            system.load_rom(&rom).unwrap();
+            system.run_ticks(cycles).unwrap();
+            systems.push(system);
+        }
+        let recompiled = systems.pop().unwrap();
+        assert_eq!(systems[0].save_state(), recompiled.save_state());
+        recompiled
+    }
+
+    #[test]
+    fn patch_ahead_of_pc() {
+        // Each pass rewrites the ADD right after the store with a bigger immediate, after the
+        // block holding it was translated on the pass before
+        let system = run_both(&[
+            0x7301,     // 200: ADD V3, 1
+            0xA20A,     // 202: LD I, 0x20A
+            0x6072,     // 204: LD V0, 0x72
+            0x8134,     // 206: ADD V1, V3
+            0xF155,     // 208: LD [I], V1
+            0x6200,     // 20A: patched to ADD V2, V1
+            0x3303,     // 20C: SE V3, 3
+            0x1200,     // 20E: JP 0x200
+            0x1210,     // 210: JP 0x210
+        ], 100);
+        assert_eq!(system.cpu.vregs[2], 1 + 3 + 6);
+    }
+
+    #[test]
+    fn patch_other_block() {
+        // The first pass translates the block at 0x200, then rewrites its first instruction
+        let system = run_both(&[
+            0x6200,     // 200: patched to ADD V2, 1
+            0x7301,     // 202: ADD V3, 1
+            0xA200,     // 204: LD I, 0x200
+            0x6072,     // 206: LD V0, 0x72
+            0x6101,     // 208: LD V1, 0x01
+            0xF155,     // 20A: LD [I], V1
+            0x3303,     // 20C: SE V3, 3
+            0x1200,     // 20E: JP 0x200
+            0x1210,     // 210: JP 0x210
+        ], 100);
+        assert_eq!(system.cpu.vregs[2], 2);
+    }
+
+    #[test]
+    fn invalidate_overlapping_blocks() {
+        // 64 ADD V0, 1 fill the first block, then ADD V1, 1; JP 0x282 make the second
+        let mut program = vec![0x7001u16; MAX_BLOCK_LEN];
+        program.extend_from_slice(&[0x7101, 0x1282]);
+        let mut system = System::new();
+        system.load_rom(&ROM::new(program.iter().flat_map(|word| word.to_be_bytes().to_vec()).collect())).unwrap();
+        let mut recompiler = Recompiler::new();
+        let end = recompiler.block_at(&system.cpu, 0x200).end;
+        assert_eq!(end as usize, 0x200 + MAX_BLOCK_LEN * 2);
+        recompiler.block_at(&system.cpu, end);
+        assert_eq!(recompiler.block_count(), 2);
+
+        // Writes before the first block, after both of them or past the end of the table
+        recompiler.invalidate(0, 0x1FF);
+        recompiler.invalidate(0x300, 0x400);
+        recompiler.invalidate(0x10000, 0x10000);
+        assert_eq!(recompiler.block_count(), 2);
+        // The last byte of the first block only drops that one
+        recompiler.invalidate(end as usize - 1, end as usize - 1);
+        assert_eq!(recompiler.block_count(), 1);
+        recompiler.invalidate(end as usize, end as usize + 1);
+        assert_eq!(recompiler.block_count(), 0);
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::VecDeque;
+
+use crate::savestate::SaveStateError;
+use crate::system::System;
+
+pub const DEFAULT_REWIND_INTERVAL: u32 = 1;
+pub const DEFAULT_REWIND_MEMORY: usize = 8 * 1024 * 1024;
+
+// Ring buffer of machine snapshots for stepping back in time. Only the newest snapshot is kept
+// whole, every older one is stored as the difference to the snapshot after it, run length
+// encoded. Successive frames differ in a handful of bytes, so a snapshot usually costs about a
+// hundred bytes instead of a whole save state.
+#[derive(Debug)]
+pub struct Rewind {
+    pub interval: u32,          // Frames between snapshots
+    pub max_bytes: usize,       // Oldest snapshots are dropped once they take up more than this
+    latest: Option<Vec<u8>>,    // Newest snapshot, as written by System::save_state
+    deltas: VecDeque<Vec<u8>>,  // Older snapshots, oldest first, each relative to the next one
+    bytes: usize,               // Memory used by latest and deltas
+    frames_since: u32,          // Frames run since latest was taken
+}
+
+impl Default for Rewind {
+    fn default() -> Self {
+        Rewind::new(DEFAULT_REWIND_INTERVAL, DEFAULT_REWIND_MEMORY)
+    }
+}
+
+impl Rewind {
+    pub fn new(interval: u32, max_bytes: usize) -> Rewind {
+        Rewind {
+            interval: interval.max(1),
+            max_bytes,
+            latest: None,
+            deltas: VecDeque::new(),
+            bytes: 0,
+            frames_since: 0,
+        }
+    }
+
+    // Number of snapshots that can be stepped back to
+    pub fn len(&self) -> usize {
+        if self.latest.is_some() { self.deltas.len() + 1 } else { 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    // Memory taken up by the snapshots
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+        self.bytes = 0;
+        self.frames_since = 0;
+    }
+
+    // Call once after every emulated frame, takes a snapshot every interval frames
+    pub fn record(&mut self, system: &System) {
+        self.frames_since += 1;
+        if self.latest.is_some() && self.frames_since < self.interval.max(1) {
+            return;
+        }
+        self.frames_since = 0;
+        let snapshot = system.save_state();
+        if let Some(latest) = self.latest.take() {
+            let delta = encode_delta(&latest, &snapshot);
+            self.bytes = self.bytes - latest.len() + delta.len();
+            self.deltas.push_back(delta);
+        }
+        self.bytes += snapshot.len();
+        self.latest = Some(snapshot);
+        while self.bytes > self.max_bytes {
+            match self.deltas.pop_front() {
+                Some(delta) => self.bytes -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    // Puts system back to the previous snapshot. Frames run since the newest snapshot are undone
+    // first. Returns false once there is nothing older to go back to.
+    pub fn step_back(&mut self, system: &mut System) -> Result<bool, SaveStateError> {
+        if self.frames_since == 0 {
+            let (latest, delta) = match (self.latest.take(), self.deltas.pop_back()) {
+                (Some(latest), Some(delta)) => (latest, delta),
+                (latest, _) => {
+                    self.latest = latest;
+                    return Ok(false);
+                }
+            };
+            let previous = apply_delta(&latest, &delta);
+            self.bytes = self.bytes - latest.len() - delta.len() + previous.len();
+            self.latest = Some(previous);
+        }
+        match self.latest {
+            Some(ref latest) => system.load_state(latest)?,
+            None => return Ok(false),
+        }
+        self.frames_since = 0;
+        Ok(true)
+    }
+}
+
+// Encodes older as the XOR with newer, prefixed with its length. Snapshots differ in length only
+// by the stack, missing bytes count as 0. Runs of zero bytes become a 0 and the run length.
+fn encode_delta(older: &[u8], newer: &[u8]) -> Vec<u8> {
+    let mut out = (older.len() as u32).to_le_bytes().to_vec();
+    let mut zeros = 0u8;
+    for (i, old) in older.iter().enumerate() {
+        let byte = old ^ newer.get(i).copied().unwrap_or(0);
+        if byte == 0 {
+            zeros += 1;
+            if zeros == u8::MAX {
+                out.push(0);
+                out.push(zeros);
+                zeros = 0;
+            }
+            continue;
+        }
+        if zeros > 0 {
+            out.push(0);
+            out.push(zeros);
+            zeros = 0;
+        }
+        out.push(byte);
+    }
+    if zeros > 0 {
+        out.push(0);
+        out.push(zeros);
+    }
+    out
+}
+
+// Rebuilds the older snapshot encode_delta was given from newer
+fn apply_delta(newer: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut len = [0u8; 4];
+    len.copy_from_slice(&delta[..4]);
+    let len = u32::from_le_bytes(len) as usize;
+    let mut older = Vec::with_capacity(len);
+    let mut bytes = delta[4..].iter();
+    while let Some(&byte) = bytes.next() {
+        if byte == 0 {
+            let zeros = bytes.next().copied().unwrap_or(0);
+            for _ in 0..zeros {
+                older.push(newer.get(older.len()).copied().unwrap_or(0));
+            }
+        } else {
+            older.push(byte ^ newer.get(older.len()).copied().unwrap_or(0));
+        }
+    }
+    older
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rewind::Rewind;
+    use crate::rom::ROM;
+    use crate::system::System;
+
+    #[test]
+    fn step_back_after_eviction() {
+        let mut system = System::new();
+        system.set_seed(0);
+        // CALL 0x206; ADD V0, 1; JP 0x200; ADD V1, 2; RET. Frames end with and without a
+        // return address on the stack, so snapshots differ in length.
+        system.load_rom(&ROM::new(vec![0x22, 0x06, 0x70, 0x01, 0x12, 0x00, 0x71, 0x02, 0x00, 0xEE])).unwrap();
+        system.cycles_per_frame = 7;
+
+        let mut rewind = Rewind::new(1, system.save_state().len() + 200);
+        let mut states = vec![];
+        for _ in 0..40 {
+            system.run_frame().unwrap();
+            rewind.record(&system);
+            states.push(system.save_state());
+        }
+        assert!(rewind.len() > 1 && rewind.len() < states.len());
+        assert!(rewind.bytes() <= rewind.max_bytes);
+
+        // Each step goes back one frame, down to the oldest snapshot that was kept
+        let kept = rewind.len();
+        for back in 1..kept {
+            assert!(rewind.step_back(&mut system).unwrap());
+            assert_eq!(system.save_state(), states[states.len() - 1 - back]);
+        }
+        assert!(!rewind.step_back(&mut system).unwrap());
+        assert_eq!(system.save_state(), states[states.len() - kept]);
+    }
+}
@@ -0,0 +1,36 @@
+// Random number generator behind RND. It is owned by System and its whole state is one u64, so a
+// run can be replayed from its seed and the generator can be saved and restored with the rest of
+// the machine. Uses SplitMix64, which is fast and accepts any seed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rng {
+    pub state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    // Seeds from the operating system, for runs that do not need to be reproduced
+    pub fn from_entropy() -> Rng {
+        Rng::new(rand::random())
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    pub fn next_byte(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Rng::from_entropy()
+    }
+}
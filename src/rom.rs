@@ -10,6 +10,8 @@ use std::fs::{self, File};
 pub enum ROMError {
    IOError(io::Error),
    BadError,
+   // Larger than the memory from ROM_OFFSET up, which holds at most max bytes
+   TooLarge { size: usize, max: usize },
 }
 
 impl fmt::Display for ROMError {
@@ -18,7 +20,9 @@ impl fmt::Display for ROMError {
             ROMError::IOError(_) =>
                 write!(f, "ROMError::IOError could not ROM"),
             ROMError::BadError =>
-                write!(f, "ROMError::BadError")
+                write!(f, "ROMError::BadError"),
+            ROMError::TooLarge { size, max } =>
+                write!(f, "ROM too large for memory: {} bytes, at most {} fit", size, max),
         }
     }
 }
@@ -28,7 +32,7 @@ impl error::Error for ROMError {
         match *self {
             ROMError::IOError(ref e) =>
                 Some(e),
-            ROMError::BadError => 
+            ROMError::BadError | ROMError::TooLarge { .. } =>
                 None,
         }
     }
@@ -45,8 +49,8 @@ pub struct ROM {
 }
 
 impl ROM {
-    fn new(data: Vec<u8>) -> ROM {
-        ROM { data: data }
+    pub fn new(data: Vec<u8>) -> ROM {
+        ROM { data }
     }
    
     pub fn size(&self) -> usize {
@@ -66,11 +70,11 @@ impl IntoIterator for &ROM {
         let mut i = 0;
         let mut romdata : Vec<Self::Item> = vec![];
 
-        println!("size of ROM: {}", self.size());
+        //println!("size of ROM: {}", self.size());
         while i < self.data.len() {
             let mut word : u16 = (self.data[i] as u16) << 8;
             if i + 1 < self.size() {
-                word = word | (self.data[i+1] as u16);
+                word |= self.data[i+1] as u16;
             }
             i += 2;
             romdata.push(word);
@@ -85,7 +89,7 @@ pub fn read_rom(path: &Path) -> Result<ROM, ROMError> {
     let fsize = fmeta.unwrap().len();
     let mut buf : Vec<u8> = vec![0u8; fsize as usize];
     let len = f.read(&mut buf[..]);
-    if let Err(_) = len {
+    if len.is_err() {
         Err(ROMError::BadError)
     } else {
         Ok(ROM::new(buf))
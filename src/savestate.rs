@@ -0,0 +1,235 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use crate::cpu::ExecutionError;
+
+// Save states start with SAVE_STATE_MAGIC followed by the format version as a little endian
+// u16. Every field after that is little endian too. Bump SAVE_STATE_VERSION whenever the
+// layout written by System::save_state changes.
+pub const SAVE_STATE_MAGIC: &[u8; 8] = b"CHIP8SAV";
+pub const SAVE_STATE_VERSION: u16 = 1;
+
+#[derive(Debug)]
+pub enum SaveStateError {
+    IOError(io::Error),
+    // Not a save state
+    BadMagic,
+    // Written by a different version of the format
+    UnsupportedVersion(u16),
+    // Ends before all the fields were read
+    Truncated,
+    // Saved from a machine with a different amount of memory
+    MemorySizeMismatch { expected: usize, found: usize },
+    // A field holds a value that cannot occur
+    Invalid(&'static str),
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SaveStateError::IOError(ref err) =>
+                write!(f, "could not read or write save state: {}", err),
+            SaveStateError::BadMagic =>
+                write!(f, "not a save state"),
+            SaveStateError::UnsupportedVersion(version) =>
+                write!(f, "unsupported save state version {}, expected {}", version, SAVE_STATE_VERSION),
+            SaveStateError::Truncated =>
+                write!(f, "save state is truncated"),
+            SaveStateError::MemorySizeMismatch { expected, found } =>
+                write!(f, "save state has {} bytes of memory, this machine has {}", found, expected),
+            SaveStateError::Invalid(field) =>
+                write!(f, "save state has an invalid {}", field),
+        }
+    }
+}
+
+impl error::Error for SaveStateError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            SaveStateError::IOError(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SaveStateError {
+    fn from(err: io::Error) -> SaveStateError {
+        SaveStateError::IOError(err)
+    }
+}
+
+// Appends fields to a save state
+pub struct StateWriter {
+    pub data: Vec<u8>,
+}
+
+impl StateWriter {
+    // Starts a save state with the magic and version
+    pub fn new() -> StateWriter {
+        StateWriter::with_header(SAVE_STATE_MAGIC, SAVE_STATE_VERSION)
+    }
+
+    // Starts another kind of file written field by field, such as a movie
+    pub fn with_header(magic: &[u8], version: u16) -> StateWriter {
+        let mut writer = StateWriter { data: vec![] };
+        writer.bytes(magic);
+        writer.u16(version);
+        writer
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, value: i32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    // A key is 0 when it has never been touched, 1 when pressed and 2 when released
+    pub fn key(&mut self, key: Option<bool>) {
+        self.u8(match key {
+            None => 0,
+            Some(true) => 1,
+            Some(false) => 2,
+        });
+    }
+
+    // A tag saying which error, if any, then the address of the instruction and one more field
+    pub fn error(&mut self, error: Option<ExecutionError>) {
+        let (tag, pc, extra) = match error {
+            None => (0, 0, 0),
+            Some(ExecutionError::StackOverflow { pc }) => (1, pc, 0),
+            Some(ExecutionError::StackUnderflow { pc }) => (2, pc, 0),
+            Some(ExecutionError::InvalidOpcode { pc, opcode }) => (3, pc, opcode as u64),
+            Some(ExecutionError::MemoryOutOfRange { pc, addr }) => (4, pc, addr as u64),
+            Some(ExecutionError::InvalidKey { pc, key }) => (5, pc, key as u64),
+        };
+        self.u8(tag);
+        self.u16(pc);
+        self.u64(extra);
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Reads fields back in the order they were written
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    // Checks the magic and version and positions the reader on the first field
+    pub fn new(data: &'a [u8]) -> Result<StateReader<'a>, SaveStateError> {
+        StateReader::with_header(data, SAVE_STATE_MAGIC, SAVE_STATE_VERSION)
+    }
+
+    // Reads a file started with StateWriter::with_header
+    pub fn with_header(data: &'a [u8], magic: &[u8], version: u16) -> Result<StateReader<'a>, SaveStateError> {
+        let mut reader = StateReader { data };
+        if reader.bytes(magic.len()).map_err(|_| SaveStateError::BadMagic)? != magic {
+            return Err(SaveStateError::BadMagic);
+        }
+        let found = reader.u16()?;
+        if found != version {
+            return Err(SaveStateError::UnsupportedVersion(found));
+        }
+        Ok(reader)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, SaveStateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SaveStateError::Invalid("flag")),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, SaveStateError> {
+        let mut bytes = [0u8; 2];
+        bytes.copy_from_slice(self.bytes(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, SaveStateError> {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn i32(&mut self) -> Result<i32, SaveStateError> {
+        Ok(self.u32()? as i32)
+    }
+
+    pub fn u64(&mut self) -> Result<u64, SaveStateError> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], SaveStateError> {
+        if self.data.len() < len {
+            return Err(SaveStateError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub fn key(&mut self) -> Result<Option<bool>, SaveStateError> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(true)),
+            2 => Ok(Some(false)),
+            _ => Err(SaveStateError::Invalid("key")),
+        }
+    }
+
+    pub fn error(&mut self) -> Result<Option<ExecutionError>, SaveStateError> {
+        let tag = self.u8()?;
+        let pc = self.u16()?;
+        let extra = self.u64()?;
+        Ok(match tag {
+            0 => None,
+            1 => Some(ExecutionError::StackOverflow { pc }),
+            2 => Some(ExecutionError::StackUnderflow { pc }),
+            3 => Some(ExecutionError::InvalidOpcode { pc, opcode: extra as u16 }),
+            4 => Some(ExecutionError::MemoryOutOfRange { pc, addr: extra as usize }),
+            5 => Some(ExecutionError::InvalidKey { pc, key: extra as u8 }),
+            _ => return Err(SaveStateError::Invalid("error")),
+        })
+    }
+
+    // Fails unless every byte has been read
+    pub fn finish(&self) -> Result<(), SaveStateError> {
+        if self.data.is_empty() { Ok(()) } else { Err(SaveStateError::Invalid("length")) }
+    }
+}
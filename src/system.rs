@@ -1,14 +1,28 @@
 use std::cell::RefCell;
+use std::fs;
+use std::path::Path;
 use std::rc::Rc;
-use std::time;
 use crate::cpu;
-use crate::cpu::CPU;
-use crate::rom::ROM;
-use crate::display::Display;
+use crate::cpu::{CPU, ExecutionError, Opcode};
+use crate::rom::{ROM, ROMError};
+use crate::display::{self, Display};
+use crate::machine::Machine;
+use crate::rng::Rng;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+use crate::timing::{self, Timing};
+use crate::quirks::Quirks;
+use crate::recompiler::{Backend, Recompiler};
 
 pub const MEMSIZE: usize = 4 * 1024;
+// XO-CHIP programs can address the full 16 bit range through F000 NNNN
+pub const XO_CHIP_MEMSIZE: usize = 64 * 1024;
 pub const ROM_OFFSET: usize = 0x200;
 
+// The CHIP-8 runs at roughly 500 Hz while the display and the DT/ST timers run at 60 Hz,
+// so by default each frame executes INSTR_PER_FRAME instructions.
+pub const FRAMES_PER_SECOND: u32 = 60;
+pub const INSTR_PER_FRAME: u32 = 10;
+
 // character fonts. loaded into memory starting at address 0x0
 pub const FONT_DATA  : [u8; 80 ]= [
     0xF0u8, 0x90u8, 0x90u8, 0x90u8, 0xF0u8, // 0 
@@ -28,7 +42,66 @@ pub const FONT_DATA  : [u8; 80 ]= [
     0xF0u8, 0x80u8, 0xF0u8, 0x80u8, 0xF0u8, // E
     0xF0u8, 0x80u8, 0xF0u8, 0x80u8, 0x80u8, // F
     ];
+
+// SUPER-CHIP 8x10 character fonts, used by FX30. loaded into memory right after FONT_DATA
+pub const BIG_FONT_OFFSET: usize = 0x50;
+pub const BIG_FONT_DATA : [u8; 160] = [
+    0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+    0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+    0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+    0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+    ];
     
+// What System::step does when an instruction fails
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorPolicy {
+    // Stop the CPU. The error is returned once, later steps return StepOutcome::Halted.
+    Halt,
+    // Skip the failing instruction and carry on, returning StepOutcome::Ignored
+    Ignore,
+    // Return the error and leave pc on the failing instruction, so a debugger can inspect the
+    // machine. Stepping again retries the instruction.
+    Trap,
+}
+
+pub const ERROR_POLICY_NAMES: [&str; 3] = ["halt", "ignore", "trap"];
+
+impl ErrorPolicy {
+    pub fn from_name(name: &str) -> Option<ErrorPolicy> {
+        match name {
+            "halt" => Some(ErrorPolicy::Halt),
+            "ignore" => Some(ErrorPolicy::Ignore),
+            "trap" => Some(ErrorPolicy::Trap),
+            _ => None,
+        }
+    }
+}
+
+// What a successful System::step did
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepOutcome {
+    // The instruction ran
+    Executed(Opcode),
+    // A waited instruction such as LD VX, K has not completed yet
+    Waiting(Opcode),
+    // The instruction failed and was skipped under ErrorPolicy::Ignore
+    Ignored(ExecutionError),
+    // The CPU was stopped by an error under ErrorPolicy::Halt, nothing ran
+    Halted,
+}
+
 #[derive(Debug)]
 pub struct System {
    pub cpu: CPU,
@@ -36,41 +109,94 @@ pub struct System {
 
    // This holds the current instruction processed by the cpu, normally it is None, only used for 
    // certain instructions that _halt_ the cpu until a condition is met. i.e. LD VX, K 
-   curr_instr : Option<Box<dyn cpu::Instruction>>,
+   curr_instr : Option<Opcode>,
    pub draw_screen : bool,
    pub display: Display,
 
-   // To keep track of time to decrement DT/ST timers
-   time_since_dt_update: f32
-   
+   // Instructions run since the DT/ST timers last ticked. The timers only follow emulated
+   // frames, never the host clock, so runs and replays are repeatable.
+   ticks_in_frame: u32,
+
+   // Print every executed instruction and timer update to stdout
+   pub trace: bool,
+
+   // Generator behind RND, and the seed it started from
+   pub rng: Rng,
+   seed: u64,
+
+   // How much code run_frame runs: cycles_per_frame instructions with Timing::Fixed, and the
+   // machine cycles left in the frame with Timing::Vip
+   pub timing: Timing,
+   pub cycles_per_frame: u32,
+   vip_cycles_left: i32,
+
+   // Engine used by run_ticks and run_frame
+   pub backend: Backend,
+   recompiler: Recompiler,
+
+   // How failing instructions are handled, and the error that halted the CPU under ErrorPolicy::Halt
+   pub error_policy: ErrorPolicy,
+   fault: Option<ExecutionError>,
+}
 
+impl Default for System {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl System {
     pub fn new() -> Self {
-        let mem = Rc::new(RefCell::new(vec![0; MEMSIZE]));
+        System::with_quirks(Quirks::default())
+    }
+
+    pub fn with_quirks(quirks: Quirks) -> Self {
+        System::with_memory_size(quirks, MEMSIZE)
+    }
+
+    // Creates a system with memsize bytes of memory, MEMSIZE for CHIP8 and SUPER-CHIP,
+    // XO_CHIP_MEMSIZE for XO-CHIP.
+    pub fn with_memory_size(quirks: Quirks, memsize: usize) -> Self {
+        let mem = Rc::new(RefCell::new(vec![0; memsize]));
+        let seed = rand::random();
         let system = System {
-            cpu: CPU::new(mem.clone()),
+            cpu: CPU::new(mem.clone(), quirks),
             mem: mem.clone(),
             curr_instr:  None,
             display: Display::new(),
             draw_screen: false,
-            time_since_dt_update: 0.0
+            ticks_in_frame: 0,
+            trace: false,
+            rng: Rng::new(seed),
+            seed,
+            timing: Timing::Fixed,
+            cycles_per_frame: INSTR_PER_FRAME,
+            vip_cycles_left: 0,
+            backend: Backend::Interpreter,
+            recompiler: Recompiler::new(),
+            error_policy: ErrorPolicy::Halt,
+            fault: None,
         };
 
         // load font
-        let mut i = 0;
-        for sprite in  FONT_DATA.iter() {
-            system.cpu.store_byte_mem(i, *sprite);
-            i += 1;
+        for (i, sprite) in FONT_DATA.iter().enumerate() {
+            mem.borrow_mut()[i] = *sprite;
+        }
+        for (i, sprite) in BIG_FONT_DATA.iter().enumerate() {
+            mem.borrow_mut()[BIG_FONT_OFFSET + i] = *sprite;
         }
         system
     }
 
-    pub fn load_rom(&mut self, rom: &ROM) {
-        for (data, i) in rom.data().iter().zip(0..rom.size()){
-            self.mem.borrow_mut()[ROM_OFFSET + i] = *data;
+    // Copies rom to ROM_OFFSET. A ROM that does not fit in memory leaves it untouched.
+    pub fn load_rom(&mut self, rom: &ROM) -> Result<(), ROMError> {
+        let max = self.mem.borrow().len() - ROM_OFFSET;
+        if rom.size() > max {
+            return Err(ROMError::TooLarge { size: rom.size(), max });
         }
+        self.mem.borrow_mut()[ROM_OFFSET..ROM_OFFSET + rom.size()].copy_from_slice(rom.data());
+        self.cpu.clear_decode_cache();
+        Ok(())
     }
 
     pub fn dump_rom(&mut self, rom: &ROM) {
@@ -84,92 +210,536 @@ impl System {
             }
     }
 
-    pub fn do_drw_instr(&mut self, draw_instr: &cpu::DrwInstr) {
-        let mut sprite: Vec<u8> = vec!();
-        let i_reg = self.cpu.i;
-        for i in 0..draw_instr.n {
-            sprite.push(self.cpu.get_byte_mem((i_reg + (i as u16)) as usize));
+    // Lends the parts of the system that instructions act on
+    pub fn machine(&mut self) -> Machine<'_> {
+        Machine { cpu: &mut self.cpu, display: &mut self.display, rng: &mut self.rng, draw_screen: &mut self.draw_screen }
+    }
+
+    // Restarts the random number generator from seed, so RND gives the same numbers every run
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = Rng::new(seed);
+    }
+
+    // The seed the random number generator started from. Picked at random unless set_seed was called.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // The error that halted the CPU under ErrorPolicy::Halt
+    pub fn fault(&self) -> Option<ExecutionError> {
+        self.fault
+    }
+
+    // Saves the whole machine: memory, registers, stack, timers, keys, display, the instruction
+    // being waited on and the progress through the current frame. Settings such as quirks,
+    // timing, cycles_per_frame, backend and error_policy are not part of the state.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut out = StateWriter::new();
+        let mem = self.mem.borrow();
+        out.u32(mem.len() as u32);
+        out.bytes(&mem);
+
+        out.u16(self.cpu.pc);
+        out.bytes(&self.cpu.vregs);
+        out.u16(self.cpu.i);
+        out.u8(self.cpu.stack.len() as u8);
+        for addr in self.cpu.stack.iter() {
+            out.u16(*addr);
         }
-        let x = self.cpu.vregs[draw_instr.vx as usize];
-        let y = self.cpu.vregs[draw_instr.vy as usize];
-        self.cpu.vregs[cpu::VF] = self.display.draw_sprite(x,y, sprite) as u8;
-        if self.cpu.vregs[cpu::VF] == 1 {
-            println!("YES WE GOT A HIT at {}, {}", x ,y);
-        } else { 
-            println!("NO  HIT at {}, {}", x ,y);
+        out.u8(self.cpu.dt);
+        out.u8(self.cpu.st);
+        for key in self.cpu.curr_keys.iter() {
+            out.key(*key);
+        }
+        out.u8(self.cpu.waiting_key.unwrap_or(0xFF));
+        out.bool(self.cpu.is_halted);
+        out.bytes(&self.cpu.rpl);
+        out.bytes(&self.cpu.audio_pattern);
+        out.u8(self.cpu.pitch);
+
+        out.bool(self.display.hires);
+        out.u8(self.display.planes);
+        for row in self.display.mem.iter() {
+            out.bytes(row);
         }
+        out.bool(self.draw_screen);
+
+        // A waited instruction never moves pc, so it is decoded again from pc on load
+        out.bool(self.curr_instr.is_some());
+        out.u32(self.ticks_in_frame);
+        out.i32(self.vip_cycles_left);
+        out.u64(self.rng.state);
+        out.u64(self.seed);
+        out.error(self.fault);
+        out.data
     }
 
-    pub fn step(&mut self) {
+    // Restores a state written by save_state. The system must have the same amount of memory.
+    // On error the system is left unchanged.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        let mut input = StateReader::new(data)?;
+        let memsize = input.u32()? as usize;
+        let expected = self.mem.borrow().len();
+        if memsize != expected {
+            return Err(SaveStateError::MemorySizeMismatch { expected, found: memsize });
+        }
+        let mem = input.bytes(memsize)?;
+
+        let pc = input.u16()?;
+        let mut vregs = [0u8; 16];
+        vregs.copy_from_slice(input.bytes(16)?);
+        let i = input.u16()?;
+        let stack_len = input.u8()? as usize;
+        if stack_len > cpu::STACK_SIZE {
+            return Err(SaveStateError::Invalid("stack"));
+        }
+        let mut stack = Vec::with_capacity(stack_len);
+        for _ in 0..stack_len {
+            stack.push(input.u16()?);
+        }
+        let dt = input.u8()?;
+        let st = input.u8()?;
+        let mut curr_keys = [None; 16];
+        for key in curr_keys.iter_mut() {
+            *key = input.key()?;
+        }
+        let waiting_key = match input.u8()? {
+            0xFF => None,
+            key if key < 16 => Some(key),
+            _ => return Err(SaveStateError::Invalid("waiting key")),
+        };
+        let is_halted = input.bool()?;
+        let mut rpl = [0u8; 16];
+        rpl.copy_from_slice(input.bytes(16)?);
+        let mut audio_pattern = [0u8; cpu::AUDIO_PATTERN_SIZE];
+        audio_pattern.copy_from_slice(input.bytes(cpu::AUDIO_PATTERN_SIZE)?);
+        let pitch = input.u8()?;
+
+        let hires = input.bool()?;
+        let planes = input.u8()?;
+        if planes > display::ALL_PLANES {
+            return Err(SaveStateError::Invalid("plane selection"));
+        }
+        let mut screen = self.display.mem;
+        for row in screen.iter_mut() {
+            let width = row.len();
+            row.copy_from_slice(input.bytes(width)?);
+            if row.iter().any(|pixel| *pixel > display::ALL_PLANES) {
+                return Err(SaveStateError::Invalid("pixel"));
+            }
+        }
+        let draw_screen = input.bool()?;
+
+        let waiting = input.bool()?;
+        let ticks_in_frame = input.u32()?;
+        let vip_cycles_left = input.i32()?;
+        let rng_state = input.u64()?;
+        let seed = input.u64()?;
+        let fault = input.error()?;
+        input.finish()?;
+
+        // Everything parsed, nothing can fail from here on except decoding the waited instruction
+        let curr_instr = if waiting {
+            let instr = CPU::decode_instr(u16::from(mem[pc as usize % memsize]) << 8
+                                          | u16::from(mem[(pc as usize + 1) % memsize]))
+                .map_err(|_| SaveStateError::Invalid("waited instruction"))?;
+            if !instr.is_waited_instr() {
+                return Err(SaveStateError::Invalid("waited instruction"));
+            }
+            Some(instr)
+        } else {
+            None
+        };
+
+        self.mem.borrow_mut().copy_from_slice(mem);
+        // Memory changed behind the CPU's back, forget decoded and translated code
+        self.cpu.clear_decode_cache();
+        self.cpu.pc = pc;
+        self.cpu.vregs = vregs;
+        self.cpu.i = i;
+        self.cpu.stack = stack;
+        self.cpu.dt = dt;
+        self.cpu.st = st;
+        self.cpu.curr_keys = curr_keys;
+        self.cpu.waiting_key = waiting_key;
+        self.cpu.is_halted = is_halted;
+        self.cpu.rpl = rpl;
+        self.cpu.audio_pattern = audio_pattern;
+        self.cpu.pitch = pitch;
+        self.display.hires = hires;
+        self.display.planes = planes;
+        self.display.mem = screen;
+        self.draw_screen = draw_screen;
+        self.curr_instr = curr_instr;
+        self.ticks_in_frame = ticks_in_frame;
+        self.vip_cycles_left = vip_cycles_left;
+        self.rng = Rng::new(rng_state);
+        self.seed = seed;
+        self.fault = fault;
+        Ok(())
+    }
+
+    // Writes save_state to a file
+    pub fn save_state_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), SaveStateError> {
+        fs::write(path, self.save_state())?;
+        Ok(())
+    }
+
+    // Restores a state written by save_state_to_file
+    pub fn load_state_from_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), SaveStateError> {
+        let data = fs::read(path)?;
+        self.load_state(&data)
+    }
+
+    // Executes a single instruction. When it fails, error_policy decides whether the error is
+    // returned and what happens to the machine.
+    pub fn step(&mut self) -> Result<StepOutcome, ExecutionError> {
+        if self.fault.is_some() {
+            return Ok(StepOutcome::Halted);
+        }
+        // Still processing an instruction
+        if let Some(instr) = self.curr_instr.take() {
+            if !instr.check_completed(&mut self.machine()) {
+                if self.trace {
+                    println!("Still waiting for instr {} to complete", instr)
+                }
+                self.curr_instr = Some(instr);
+                return Ok(StepOutcome::Waiting(instr));
+            }
+            if let Err(err) = instr.incr_pc(&mut self.cpu) {
+                return self.handle_error(err, instr.size());
+            }
+            return Ok(StepOutcome::Executed(instr));
+        }
+
         // We are currently not in the middle of an instruction
-        if let None = self.curr_instr {
-            let ins = self.cpu.fetch_instr_from_pc();
-            match ins {
-                Ok(instr) => {
-                    instr.execute(&mut self.cpu);
-                    println!("PC: {:X} OPCODE: {:X} INSTR: {}", self.cpu.pc,  self.cpu.fetch_instr_from_addr(self.cpu.pc as usize) , instr.print());
-                    if instr.is_waited_instr() {
-                        self.curr_instr = Some(instr)
-                    } else {
-                         // Test if any of these are special instructions we should handle
-                         if  let Some(drw_instr) = instr.as_any().downcast_ref::<cpu::DrwInstr>() {
-                           
-                            self.do_drw_instr(drw_instr);
-                            self.draw_screen = true
-                        }
-                        instr.incr_pc(&mut self.cpu);
-                        
-                    }
-                }, 
-                Err(err) => {
+        let instr = match self.cpu.fetch_instr_from_pc() {
+            Ok(instr) => instr,
+            Err(err) => {
+                if self.trace {
                     println!("Error fetching instruction {}", err);
                 }
+                return self.handle_error(err, 2);
             }
-            if self.cpu.is_halted() {
-                println!("CPU is halted. Current pc is {}", self.cpu.pc);
-            } 
-           
-        } else {
-        // Still processing an instruction
-            let curr_instr = self.curr_instr.take();
-            match curr_instr {
-                Some(instr) => {
-                    if instr.check_completed(self) {
-                        instr.incr_pc(&mut self.cpu);
-                        self.curr_instr = None;
-                    }  else {
-                        println!("Still waiting for instr {} to complete", instr)
-                    }
-                },
-                None => {
-                    println!("Error unwrapping instruction! No instruction present");
+        };
+        if let Err(err) = self.execute(instr) {
+            return self.handle_error(err, instr.size());
+        }
+        if self.cpu.is_halted() && self.trace {
+            println!("CPU is halted. Current pc is {}", self.cpu.pc);
+        } 
+        if self.curr_instr.is_some() {
+            return Ok(StepOutcome::Waiting(instr));
+        }
+        Ok(StepOutcome::Executed(instr))
+    }
+
+    // Runs a freshly fetched instruction and moves pc on, unless it has to wait
+    fn execute(&mut self, instr: Opcode) -> Result<(), ExecutionError> {
+        instr.execute(&mut self.machine())?;
+        if self.trace {
+            println!("PC: {:X} OPCODE: {:X} INSTR: {}", self.cpu.pc,  self.cpu.fetch_instr_from_addr(self.cpu.pc as usize).unwrap_or(0) , instr.print());
+            if let Opcode::Drw { vx, vy, .. } = instr {
+                let (x, y) = (self.cpu.vregs[vx as usize], self.cpu.vregs[vy as usize]);
+                if self.cpu.vregs[cpu::VF] == 1 {
+                    println!("YES WE GOT A HIT at {}, {}", x ,y);
+                } else { 
+                    println!("NO  HIT at {}, {}", x ,y);
                 }
             }
         }
+        if instr.is_waited_instr() {
+            self.curr_instr = Some(instr);
+            return Ok(());
+        }
+        instr.incr_pc(&mut self.cpu)
+    }
+
+    // Applies error_policy to an instruction of the given size that failed with err
+    fn handle_error(&mut self, err: ExecutionError, size: u16) -> Result<StepOutcome, ExecutionError> {
+        if self.trace {
+            println!("{}", err);
+        }
+        match self.error_policy {
+            ErrorPolicy::Halt => {
+                self.cpu.is_halted = true;
+                self.fault = Some(err);
+                Err(err)
+            },
+            ErrorPolicy::Ignore => {
+                self.cpu.pc = self.cpu.pc.wrapping_add(size);
+                Ok(StepOutcome::Ignored(err))
+            },
+            ErrorPolicy::Trap => Err(err),
+        }
+    }
+
+    // Runs one instruction as part of a frame of cycles_per_frame instructions
+    pub fn run_tick(&mut self) -> Result<StepOutcome, ExecutionError> {
+           let result = self.step();
+           self.end_tick();
+           result
     }
-    pub fn run_tick(&mut self, delta: std::time::Duration) {
-           self.step();
-           self.update_dt_st(delta);
+
+    // Runs `cycles` instructions with the selected backend, stopping early when an instruction
+    // fails and error_policy returns the error.
+    pub fn run_ticks(&mut self, cycles: u64) -> Result<(), ExecutionError> {
+        let mut remaining = cycles;
+        while remaining > 0 {
+            // Waited instructions and traced runs always go through step
+            if self.backend == Backend::Interpreter || self.curr_instr.is_some() || self.trace {
+                self.run_tick()?;
+                remaining -= 1;
+                continue;
+            }
+            let pc = self.cpu.pc;
+            let block = self.recompiler.block_at(&self.cpu, pc);
+            if block.is_empty() {
+                self.run_tick()?;
+                remaining -= 1;
+                continue;
+            }
+            let n = block.len().min(remaining as usize);
+            let mut machine = Machine { cpu: &mut self.cpu, display: &mut self.display, rng: &mut self.rng, draw_screen: &mut self.draw_screen };
+            let completed = block.run(&mut machine, n);
+            // No compiled instruction touches the timers, so ticking them afterwards is the same
+            // as ticking them after each instruction
+            for _ in 0..completed {
+                self.end_tick();
+            }
+            remaining -= completed as u64;
+            // Let step report the failing instruction
+            if completed < n {
+                self.run_tick()?;
+                remaining -= 1;
+            }
+        }
+        Ok(())
     }
 
-    pub fn update_dt_st(&mut self, delta: std::time::Duration) {
-        println!("{:?} time_since_update: {}", delta, self.time_since_dt_update);
-        if self.time_since_dt_update >=  (1000.0 / 60.0) {
-            if self.cpu.dt > 0 {
+    // Runs one 60 Hz frame worth of instructions, stopping early on an execution error.
+    pub fn run_frame(&mut self) -> Result<(), ExecutionError> {
+        match self.timing {
+            Timing::Fixed => self.run_ticks(self.cycles_per_frame.max(1) as u64),
+            Timing::Vip => self.run_vip_frame(),
+        }
+    }
+
+    // Runs instructions until the VIP interpreter would have used up the frame's machine cycles,
+    // then ticks the timers once. Instructions are stepped one at a time whatever the backend.
+    fn run_vip_frame(&mut self) -> Result<(), ExecutionError> {
+        self.vip_cycles_left += timing::VIP_CYCLES_PER_FRAME - timing::VIP_INTERRUPT_CYCLES;
+        let result = self.run_vip_cycles();
+        if result.is_err() {
+            self.vip_cycles_left = 0;
+        }
+        self.tick_timers();
+        result
+    }
+
+    fn run_vip_cycles(&mut self) -> Result<(), ExecutionError> {
+        while self.vip_cycles_left > 0 {
+            match self.step()? {
+                StepOutcome::Executed(instr) => {
+                    // An instruction that overruns the frame borrows from the next one
+                    self.vip_cycles_left -= timing::vip_cycles(&instr);
+                    // DXYN waits for the vertical interrupt
+                    if let Opcode::Drw { .. } = instr {
+                        self.vip_cycles_left = self.vip_cycles_left.min(0);
+                    }
+                },
+                // A skipped instruction is charged like a jump
+                StepOutcome::Ignored(_) => self.vip_cycles_left -= timing::vip_cycles(&Opcode::Jp { addr: 0 }),
+                // Nothing else happens until the keys or the machine change
+                StepOutcome::Waiting(_) | StepOutcome::Halted => self.vip_cycles_left = 0,
+            }
+        }
+        Ok(())
+    }
+
+    // Counts DT and ST down by one 60 Hz tick
+    fn tick_timers(&mut self) {
+        if self.cpu.dt > 0 {
+            if self.trace {
                 println!("decrement dt");
-                self.cpu.dt -= 1;
             }
-    
-            if self.cpu.st > 0 {
-                self.cpu.st -= 1;
+            self.cpu.dt -= 1;
+        }
+        if self.cpu.st > 0 {
+            self.cpu.st -= 1;
+        }
+    }
+
+    // Counts one instruction towards the current frame with Timing::Fixed, ticking the timers
+    // once every cycles_per_frame instructions
+    fn end_tick(&mut self) {
+        self.ticks_in_frame += 1;
+        if self.ticks_in_frame >= self.cycles_per_frame.max(1) {
+            self.ticks_in_frame = 0;
+            self.tick_timers();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rom::{ROM, ROMError};
+    use crate::savestate::{SaveStateError, SAVE_STATE_MAGIC};
+    use crate::cpu::ExecutionError;
+    use crate::system::{ErrorPolicy, StepOutcome, System, MEMSIZE, ROM_OFFSET};
+    use crate::timing::Timing;
+
+    fn system_with(program: &[u16]) -> System {
+        let mut system = System::new();
+        system.set_seed(0);
+        system.load_rom(&ROM::new(program.iter().flat_map(|word| word.to_be_bytes().to_vec()).collect())).unwrap();
+        system
+    }
+
+    // The error the first failing instruction raises with ErrorPolicy::Trap
+    fn first_error(program: &[u16]) -> ExecutionError {
+        let mut system = system_with(program);
+        system.error_policy = ErrorPolicy::Trap;
+        for _ in 0..100 {
+            if let Err(err) = system.step() {
+                return err;
             }
-            //reset counter
-            self.time_since_dt_update = self.time_since_dt_update - (1000.0 / 60.0);
-        } else {
-            let dt = (delta.as_micros() as f32) / 1000.0;
-            println!("dt update: {}", dt);
-            self.time_since_dt_update += dt;
         }
-        
+        panic!("no instruction failed");
+    }
+
+    #[test]
+    fn execution_errors() {
+        // CALL 0x200 until the stack is full
+        assert_eq!(first_error(&[0x2200]), ExecutionError::StackOverflow { pc: 0x200 });
+        // RET
+        assert_eq!(first_error(&[0x00EE]), ExecutionError::StackUnderflow { pc: 0x200 });
+        // LD I, 0xFFF; LD V1, [I]
+        assert_eq!(first_error(&[0xAFFF, 0xF165]), ExecutionError::MemoryOutOfRange { pc: 0x202, addr: MEMSIZE });
+        // LD V0, 0x10; SKP V0
+        assert_eq!(first_error(&[0x6010, 0xE09E]), ExecutionError::InvalidKey { pc: 0x202, key: 0x10 });
+        assert_eq!(first_error(&[0x6000, 0xE0FF]), ExecutionError::InvalidOpcode { pc: 0x202, opcode: 0xE0FF });
+    }
+
+    #[test]
+    fn error_policies() {
+        // LD V0, 5; RET; LD V1, 6
+        let program = [0x6005, 0x00EE, 0x6106];
+        let underflow = ExecutionError::StackUnderflow { pc: 0x202 };
+
+        let mut system = system_with(&program);
+        assert_eq!(system.error_policy, ErrorPolicy::Halt);
+        system.step().unwrap();
+        assert_eq!(system.step(), Err(underflow));
+        assert_eq!(system.fault(), Some(underflow));
+        assert!(system.cpu.is_halted());
+        assert_eq!(system.step(), Ok(StepOutcome::Halted));
+        assert_eq!(system.cpu.pc, 0x202);
+
+        let mut system = system_with(&program);
+        system.error_policy = ErrorPolicy::Ignore;
+        system.step().unwrap();
+        assert_eq!(system.step(), Ok(StepOutcome::Ignored(underflow)));
+        assert_eq!(system.cpu.pc, 0x204);
+        assert_eq!(system.fault(), None);
+        system.step().unwrap();
+        assert_eq!((system.cpu.vregs[0], system.cpu.vregs[1]), (5, 6));
+
+        let mut system = system_with(&program);
+        system.error_policy = ErrorPolicy::Trap;
+        system.step().unwrap();
+        let before = system.save_state();
+        assert_eq!(system.step(), Err(underflow));
+        assert_eq!(system.save_state(), before);
+        assert_eq!(system.fault(), None);
+        // The failing instruction is tried again
+        assert_eq!(system.step(), Err(underflow));
+    }
+
+    #[test]
+    fn vip_frame_uses_up_its_cycles() {
+        // ADD V0, 1 (10 cycles); JP 0x200 (23 cycles). The frame's 3668 - 1100 = 2568 cycles run
+        // 77 loops with 27 cycles left, then one more that overruns by 6.
+        let mut system = system_with(&[0x7001, 0x1200]);
+        system.timing = Timing::Vip;
+        system.cpu.dt = 5;
+        system.run_frame().unwrap();
+        assert_eq!((system.cpu.vregs[0], system.cpu.pc, system.cpu.dt), (78, 0x200, 4));
+        // The next frame starts 6 cycles short: 77 loops leave 21, the 78th overruns again
+        system.run_frame().unwrap();
+        assert_eq!((system.cpu.vregs[0], system.cpu.dt), (156, 3));
+    }
+
+    #[test]
+    fn vip_frame_ends_at_drw() {
+        // ADD V0, 1; DRW V0, V0, 5; JP 0x200
+        let mut system = system_with(&[0x7001, 0xD005, 0x1200]);
+        system.timing = Timing::Vip;
+        system.run_frame().unwrap();
+        assert_eq!((system.cpu.vregs[0], system.cpu.pc), (1, 0x204));
+        system.run_frame().unwrap();
+        assert_eq!((system.cpu.vregs[0], system.cpu.pc), (2, 0x204));
+    }
+
+    #[test]
+    fn rom_too_large() {
+        let mut system = System::new();
+        let max = MEMSIZE - ROM_OFFSET;
+        match system.load_rom(&ROM::new(vec![0xAA; max + 1])) {
+            Err(ROMError::TooLarge { size, max: fits }) => assert_eq!((size, fits), (max + 1, max)),
+            other => panic!("oversized ROM loaded: {:?}", other),
+        }
+        assert!(system.cpu.memory.borrow()[ROM_OFFSET..].iter().all(|byte| *byte == 0));
+        system.load_rom(&ROM::new(vec![0xAA; max])).unwrap();
+        assert_eq!(system.cpu.memory.borrow()[MEMSIZE - 1], 0xAA);
+    }
+
+    #[test]
+    fn save_and_load_while_waiting_for_key() {
+        // LD V1, 5; LD V0, K; ADD V0, 1; JP 0x206
+        let mut system = system_with(&[0x6105, 0xF00A, 0x7001, 0x1206]);
+        system.run_ticks(5).unwrap();
+        // Key 3 goes down, FX0A remembers it and waits for it to come up again
+        system.cpu.curr_keys[3] = Some(true);
+        system.run_ticks(1).unwrap();
+        let state = system.save_state();
+
+        let mut loaded = system_with(&[]);
+        loaded.load_state(&state).unwrap();
+        assert_eq!(loaded.save_state(), state);
+
+        for system in [&mut system, &mut loaded].iter_mut() {
+            system.cpu.curr_keys[3] = None;
+            system.run_ticks(5).unwrap();
+        }
+        assert_eq!(loaded.cpu.vregs[0], 4);
+        assert_eq!(loaded.save_state(), system.save_state());
+    }
+
+    #[test]
+    fn failed_load_leaves_system_unchanged() {
+        let mut saved = system_with(&[0x6105, 0x1202]);
+        saved.run_ticks(3).unwrap();
+        let state = saved.save_state();
+
+        // ADD V2, 1; JP 0x200
+        let mut system = system_with(&[0x7201, 0x1200]);
+        system.run_ticks(7).unwrap();
+        let before = system.save_state();
+
+        match system.load_state(&state[..state.len() - 1]) {
+            Err(SaveStateError::Truncated) => (),
+            other => panic!("truncated state loaded: {:?}", other),
+        }
+        assert_eq!(system.save_state(), before);
+
+        let mut other_version = state.clone();
+        other_version[SAVE_STATE_MAGIC.len()] += 1;
+        match system.load_state(&other_version) {
+            Err(SaveStateError::UnsupportedVersion(_)) => (),
+            other => panic!("state with another version loaded: {:?}", other),
+        }
+        assert_eq!(system.save_state(), before);
     }
 }
@@ -0,0 +1,54 @@
+use crate::cpu::Opcode;
+
+// How much code System::run_frame runs per 60 Hz frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timing {
+    // A fixed number of instructions per frame, whatever they are
+    Fixed,
+    // Charge each instruction the machine cycles the COSMAC VIP interpreter spent on it and run
+    // until the frame's cycles are used up. DXYN waits for the vertical interrupt.
+    Vip,
+}
+
+pub const TIMING_NAMES: [&str; 2] = ["fixed", "vip"];
+
+impl Timing {
+    pub fn from_name(name: &str) -> Option<Timing> {
+        match name {
+            "fixed" => Some(Timing::Fixed),
+            "vip" => Some(Timing::Vip),
+            _ => None,
+        }
+    }
+}
+
+// The VIP's 1802 runs at 1.76 MHz with 8 clocks per machine cycle, which is 3668 machine cycles
+// per frame. The display interrupt and its DMA take about 1100 of them, leaving the rest for
+// the interpreter.
+pub const VIP_CYCLES_PER_FRAME: i32 = 3668;
+pub const VIP_INTERRUPT_CYCLES: i32 = 1100;
+
+// Machine cycles the VIP interpreter takes to run instr. These are averages measured on the
+// original interpreter, a skip costs the same whether it is taken or not.
+pub fn vip_cycles(instr: &Opcode) -> i32 {
+    match *instr {
+        Opcode::Clr => 24,
+        Opcode::Ret | Opcode::Call { .. } | Opcode::Jp { .. } | Opcode::JpV0 { .. } => 23,
+        Opcode::Se { .. } | Opcode::Sne { .. } | Opcode::LdI { .. } => 12,
+        Opcode::SeRegs { .. } | Opcode::SneReg { .. } | Opcode::Skp { .. } | Opcode::Sknp { .. } => 16,
+        Opcode::LdVal { .. } => 6,
+        Opcode::AddImmediate { .. } | Opcode::LdDt { .. } | Opcode::StoreDt { .. } | Opcode::StoreSt { .. } => 10,
+        Opcode::LdReg { .. } | Opcode::Or { .. } | Opcode::And { .. } | Opcode::Xor { .. }
+        | Opcode::Add { .. } | Opcode::Sub { .. } | Opcode::Shr { .. } | Opcode::Subn { .. }
+        | Opcode::Shl { .. } => 44,
+        Opcode::Rnd { .. } => 36,
+        // Drawing itself costs about 26 cycles per row, then DXYN waits for the next frame
+        Opcode::Drw { n, .. } => 34 + 26 * n as i32,
+        Opcode::AddI { .. } => 19,
+        Opcode::StoreSprite { .. } => 20,
+        Opcode::StoreBcd { .. } => 204,
+        Opcode::StoreVxsIntoI { vx } | Opcode::LdVxsFromI { vx } => 14 + 14 * (vx as i32 + 1),
+        // Nothing else existed on the VIP, charge a jump
+        _ => 23,
+    }
+}